@@ -0,0 +1,571 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use alloy_primitives::{Address, B256, U256};
+use alloy_rpc_types::{Block, EIP1186AccountProofResponse, Filter, Log, Transaction};
+use anyhow::{anyhow, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use revm::primitives::AccountInfo;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::{
+    raiko::{BlobData, BlobDataProvider, BlockDataProvider, TouchedState},
+    request::ProofRequest,
+    MerkleProof,
+};
+
+/// Everything preflight fetched for a single proof request. Replaying it reproduces the
+/// guest input without access to any of the original nodes.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PreflightArchive {
+    /// The request the data was recorded for.
+    pub request: ProofRequest,
+    /// Responses of the L2 node.
+    pub l2: ProviderArchive,
+    /// Responses of the L1 node.
+    pub l1: ProviderArchive,
+    /// Blobs from the beacon node keyed by slot and versioned hash.
+    #[serde_as(as = "Vec<(_, _)>")]
+    pub blobs: HashMap<(u64, B256), BlobData>,
+}
+
+/// The responses of a single [`BlockDataProvider`], keyed by what was asked for so that the
+/// batching of the requests doesn't matter when replaying.
+#[serde_as]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProviderArchive {
+    #[serde_as(as = "Vec<(_, _)>")]
+    blocks: HashMap<(u64, bool), Block>,
+    #[serde_as(as = "Vec<(_, _)>")]
+    accounts: HashMap<Address, AccountInfo>,
+    #[serde_as(as = "Vec<(_, _)>")]
+    storage: HashMap<(Address, U256), U256>,
+    #[serde_as(as = "Vec<(_, _)>")]
+    proofs: HashMap<(u64, Address), EIP1186AccountProofResponse>,
+    /// Logs keyed by the JSON encoding of the filter.
+    logs: HashMap<String, Vec<Log>>,
+    #[serde_as(as = "Vec<(_, _)>")]
+    transactions: HashMap<B256, Transaction>,
+    #[serde_as(as = "Vec<(_, _)>")]
+    touched_state: HashMap<u64, Option<TouchedState>>,
+}
+
+/// Returns the path of the archive for the given block and network in `dir`.
+pub fn get_archive_path(dir: &Path, block_number: u64, network: &str) -> PathBuf {
+    dir.join(format!("archive-{network}-{block_number}.json.gz"))
+}
+
+impl PreflightArchive {
+    pub fn new(request: ProofRequest) -> Self {
+        Self {
+            request,
+            l2: ProviderArchive::default(),
+            l1: ProviderArchive::default(),
+            blobs: HashMap::new(),
+        }
+    }
+
+    /// Reads a gzip compressed JSON archive.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("could not open {path:?}"))?;
+        serde_json::from_reader(GzDecoder::new(BufReader::new(file)))
+            .with_context(|| format!("could not read the archive {path:?}"))
+    }
+
+    /// Writes the archive as gzip compressed JSON.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("could not create {path:?}"))?;
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+        serde_json::to_writer(&mut encoder, self)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn l2_mut(&mut self) -> &mut ProviderArchive {
+        &mut self.l2
+    }
+
+    fn l1_mut(&mut self) -> &mut ProviderArchive {
+        &mut self.l1
+    }
+
+    /// Serves the recorded L2 responses.
+    pub fn replay_l2(&self) -> ReplayProvider {
+        ReplayProvider {
+            archive: Arc::new(self.l2.clone()),
+        }
+    }
+
+    /// Serves the recorded L1 responses.
+    pub fn replay_l1(&self) -> ReplayProvider {
+        ReplayProvider {
+            archive: Arc::new(self.l1.clone()),
+        }
+    }
+
+    /// Serves the recorded blobs.
+    pub fn replay_blobs(&self) -> ReplayBlobProvider {
+        ReplayBlobProvider {
+            blobs: Arc::new(self.blobs.clone()),
+        }
+    }
+}
+
+/// Collects the responses of all the providers wrapped with it into one [`PreflightArchive`].
+#[derive(Clone)]
+pub struct Recorder {
+    archive: Arc<Mutex<PreflightArchive>>,
+}
+
+impl Recorder {
+    pub fn new(request: ProofRequest) -> Self {
+        Self {
+            archive: Arc::new(Mutex::new(PreflightArchive::new(request))),
+        }
+    }
+
+    /// Records the responses of the L2 provider.
+    pub fn record_l2<P: BlockDataProvider>(&self, inner: P) -> RecordingProvider<P> {
+        RecordingProvider {
+            inner,
+            recorder: self.clone(),
+            section: PreflightArchive::l2_mut,
+        }
+    }
+
+    /// Records the responses of the L1 provider.
+    pub fn record_l1<P: BlockDataProvider>(&self, inner: P) -> RecordingProvider<P> {
+        RecordingProvider {
+            inner,
+            recorder: self.clone(),
+            section: PreflightArchive::l1_mut,
+        }
+    }
+
+    /// Records the blobs returned by the blob provider.
+    pub fn record_blobs<P: BlobDataProvider>(&self, inner: P) -> RecordingBlobProvider<P> {
+        RecordingBlobProvider {
+            inner,
+            recorder: self.clone(),
+        }
+    }
+
+    /// Writes everything recorded so far to `path`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.archive.lock().unwrap().save(path)
+    }
+
+    fn record<F: FnOnce(&mut PreflightArchive)>(&self, f: F) {
+        f(&mut self.archive.lock().unwrap());
+    }
+}
+
+/// Passes all calls through to `inner` and records the responses.
+pub struct RecordingProvider<P> {
+    inner: P,
+    recorder: Recorder,
+    section: fn(&mut PreflightArchive) -> &mut ProviderArchive,
+}
+
+impl<P> RecordingProvider<P> {
+    fn record<F: FnOnce(&mut ProviderArchive)>(&self, f: F) {
+        let section = self.section;
+        self.recorder.record(|archive| f(section(archive)));
+    }
+}
+
+impl<P: BlockDataProvider> BlockDataProvider for RecordingProvider<P> {
+    fn rpc_calls(&self) -> u64 {
+        self.inner.rpc_calls()
+    }
+
+    async fn get_blocks(&self, blocks_to_fetch: &[(u64, bool)]) -> Result<Vec<Block>> {
+        let blocks = self.inner.get_blocks(blocks_to_fetch).await?;
+        self.record(|archive| {
+            for (key, block) in blocks_to_fetch.iter().zip(&blocks) {
+                archive.blocks.insert(*key, block.clone());
+            }
+        });
+        Ok(blocks)
+    }
+
+    async fn get_accounts(&self, accounts: &[Address]) -> Result<Vec<AccountInfo>> {
+        let infos = self.inner.get_accounts(accounts).await?;
+        self.record(|archive| {
+            for (address, info) in accounts.iter().zip(&infos) {
+                archive.accounts.insert(*address, info.clone());
+            }
+        });
+        Ok(infos)
+    }
+
+    async fn get_storage_values(&self, accounts: &[(Address, U256)]) -> Result<Vec<U256>> {
+        let values = self.inner.get_storage_values(accounts).await?;
+        self.record(|archive| {
+            for (key, value) in accounts.iter().zip(&values) {
+                archive.storage.insert(*key, *value);
+            }
+        });
+        Ok(values)
+    }
+
+    async fn get_merkle_proofs(
+        &self,
+        block_number: u64,
+        accounts: HashMap<Address, Vec<U256>>,
+        offset: usize,
+        num_storage_proofs: usize,
+    ) -> Result<MerkleProof> {
+        let proofs = self
+            .inner
+            .get_merkle_proofs(block_number, accounts, offset, num_storage_proofs)
+            .await?;
+        self.record(|archive| {
+            for (address, proof) in &proofs {
+                // The same account can be proven in several calls for different slots.
+                match archive.proofs.get_mut(&(block_number, *address)) {
+                    Some(recorded) => {
+                        let known = recorded
+                            .storage_proof
+                            .iter()
+                            .map(|p| p.key.0)
+                            .collect::<HashSet<_>>();
+                        recorded.storage_proof.extend(
+                            proof
+                                .storage_proof
+                                .iter()
+                                .filter(|p| !known.contains(&p.key.0))
+                                .cloned(),
+                        );
+                    }
+                    None => {
+                        archive
+                            .proofs
+                            .insert((block_number, *address), proof.clone());
+                    }
+                }
+            }
+        });
+        Ok(proofs)
+    }
+
+    async fn get_touched_state(&self, block_number: u64) -> Result<Option<TouchedState>> {
+        let state = self.inner.get_touched_state(block_number).await?;
+        self.record(|archive| {
+            archive.touched_state.insert(block_number, state.clone());
+        });
+        Ok(state)
+    }
+
+    async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+        let logs = self.inner.get_logs(filter).await?;
+        let key = serde_json::to_string(filter)?;
+        self.record(|archive| {
+            archive.logs.insert(key, logs.clone());
+        });
+        Ok(logs)
+    }
+
+    async fn get_transaction_by_hash(&self, hash: B256) -> Result<Transaction> {
+        let tx = self.inner.get_transaction_by_hash(hash).await?;
+        self.record(|archive| {
+            archive.transactions.insert(hash, tx.clone());
+        });
+        Ok(tx)
+    }
+}
+
+/// Passes all calls through to `inner` and records the blobs.
+pub struct RecordingBlobProvider<P> {
+    inner: P,
+    recorder: Recorder,
+}
+
+impl<P: BlobDataProvider> BlobDataProvider for RecordingBlobProvider<P> {
+    async fn get_blob(&self, slot_id: u64, blob_hash: B256) -> Result<BlobData> {
+        let blob = self.inner.get_blob(slot_id, blob_hash).await?;
+        self.recorder.record(|archive| {
+            archive.blobs.insert((slot_id, blob_hash), blob.clone());
+        });
+        Ok(blob)
+    }
+}
+
+/// Serves the responses recorded by a [`RecordingProvider`]. Anything that wasn't recorded is
+/// an error.
+#[derive(Clone)]
+pub struct ReplayProvider {
+    archive: Arc<ProviderArchive>,
+}
+
+impl BlockDataProvider for ReplayProvider {
+    async fn get_blocks(&self, blocks_to_fetch: &[(u64, bool)]) -> Result<Vec<Block>> {
+        blocks_to_fetch
+            .iter()
+            .map(|(block_number, full)| {
+                self.archive
+                    .blocks
+                    .get(&(*block_number, *full))
+                    .cloned()
+                    .ok_or_else(|| anyhow!("block {block_number} is not in the archive"))
+            })
+            .collect()
+    }
+
+    async fn get_accounts(&self, accounts: &[Address]) -> Result<Vec<AccountInfo>> {
+        accounts
+            .iter()
+            .map(|address| {
+                self.archive
+                    .accounts
+                    .get(address)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("account {address} is not in the archive"))
+            })
+            .collect()
+    }
+
+    async fn get_storage_values(&self, accounts: &[(Address, U256)]) -> Result<Vec<U256>> {
+        accounts
+            .iter()
+            .map(|(address, key)| {
+                self.archive
+                    .storage
+                    .get(&(*address, *key))
+                    .copied()
+                    .ok_or_else(|| anyhow!("slot {key} of {address} is not in the archive"))
+            })
+            .collect()
+    }
+
+    async fn get_merkle_proofs(
+        &self,
+        block_number: u64,
+        accounts: HashMap<Address, Vec<U256>>,
+        _offset: usize,
+        _num_storage_proofs: usize,
+    ) -> Result<MerkleProof> {
+        let mut proofs = MerkleProof::new();
+        for (address, keys) in accounts {
+            let recorded = self
+                .archive
+                .proofs
+                .get(&(block_number, address))
+                .ok_or_else(|| {
+                    anyhow!("proof of {address} at block {block_number} is not in the archive")
+                })?;
+            let mut proof = recorded.clone();
+            proof.storage_proof.clear();
+            for key in keys {
+                let storage_proof = recorded
+                    .storage_proof
+                    .iter()
+                    .find(|p| U256::from_be_bytes(p.key.0 .0) == key)
+                    .ok_or_else(|| {
+                        anyhow!("proof of slot {key} of {address} is not in the archive")
+                    })?;
+                proof.storage_proof.push(storage_proof.clone());
+            }
+            proofs.insert(address, proof);
+        }
+        Ok(proofs)
+    }
+
+    async fn get_touched_state(&self, block_number: u64) -> Result<Option<TouchedState>> {
+        // Recordings made without the prestate tracer fall back to execution as before.
+        Ok(self
+            .archive
+            .touched_state
+            .get(&block_number)
+            .cloned()
+            .flatten())
+    }
+
+    async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+        self.archive
+            .logs
+            .get(&serde_json::to_string(filter)?)
+            .cloned()
+            .ok_or_else(|| anyhow!("logs for the filter are not in the archive"))
+    }
+
+    async fn get_transaction_by_hash(&self, hash: B256) -> Result<Transaction> {
+        self.archive
+            .transactions
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| anyhow!("transaction {hash} is not in the archive"))
+    }
+}
+
+/// Serves the blobs recorded by a [`RecordingBlobProvider`].
+#[derive(Clone)]
+pub struct ReplayBlobProvider {
+    blobs: Arc<HashMap<(u64, B256), BlobData>>,
+}
+
+impl BlobDataProvider for ReplayBlobProvider {
+    async fn get_blob(&self, slot_id: u64, blob_hash: B256) -> Result<BlobData> {
+        self.blobs
+            .get(&(slot_id, blob_hash))
+            .cloned()
+            .ok_or_else(|| anyhow!("blob {blob_hash} of slot {slot_id} is not in the archive"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::{address, Bytes, FixedBytes};
+    use raiko_lib::consts::Network;
+    use revm::primitives::Bytecode;
+
+    use super::*;
+    use crate::request::ProofType;
+
+    /// Answers every call with made up data derived from the arguments.
+    struct FakeProvider;
+
+    impl BlockDataProvider for FakeProvider {
+        async fn get_blocks(&self, blocks_to_fetch: &[(u64, bool)]) -> Result<Vec<Block>> {
+            Ok(blocks_to_fetch
+                .iter()
+                .map(|(block_number, _)| {
+                    let mut block = Block::default();
+                    block.header.hash = Some(B256::with_last_byte(*block_number as u8));
+                    block
+                })
+                .collect())
+        }
+
+        async fn get_accounts(&self, accounts: &[Address]) -> Result<Vec<AccountInfo>> {
+            Ok(accounts
+                .iter()
+                .enumerate()
+                .map(|(i, _)| {
+                    let code = Bytecode::new_raw(Bytes::from(vec![0x60, i as u8]));
+                    AccountInfo::new(U256::from(i), i as u64, code.hash_slow(), code)
+                })
+                .collect())
+        }
+
+        async fn get_storage_values(&self, accounts: &[(Address, U256)]) -> Result<Vec<U256>> {
+            Ok(accounts
+                .iter()
+                .map(|(_, key)| key + U256::from(1))
+                .collect())
+        }
+
+        async fn get_merkle_proofs(
+            &self,
+            _block_number: u64,
+            _accounts: HashMap<Address, Vec<U256>>,
+            _offset: usize,
+            _num_storage_proofs: usize,
+        ) -> Result<MerkleProof> {
+            Ok(MerkleProof::new())
+        }
+
+        async fn get_logs(&self, _filter: &Filter) -> Result<Vec<Log>> {
+            Ok(vec![Log::default()])
+        }
+
+        async fn get_transaction_by_hash(&self, hash: B256) -> Result<Transaction> {
+            Ok(Transaction {
+                hash,
+                ..Default::default()
+            })
+        }
+    }
+
+    struct FakeBlobProvider;
+
+    impl BlobDataProvider for FakeBlobProvider {
+        async fn get_blob(&self, slot_id: u64, _blob_hash: B256) -> Result<BlobData> {
+            Ok(BlobData {
+                blob: vec![slot_id as u8; 4],
+                commitment: FixedBytes::repeat_byte(1),
+                proof: FixedBytes::repeat_byte(2),
+            })
+        }
+    }
+
+    fn test_request() -> ProofRequest {
+        ProofRequest {
+            block_number: 2,
+            block_hash: None,
+            proposal_tx_hash: None,
+            rpc: "http://localhost:8545".to_string(),
+            l1_rpc: "http://localhost:8546".to_string(),
+            beacon_rpc: "http://localhost:5052".to_string(),
+            blob_archive_url: None,
+            blob_dir: None,
+            proposal_search_range: None,
+            network: Network::TaikoA7,
+            l1_network: Network::Holesky.to_string(),
+            graffiti: B256::ZERO,
+            prover: Address::ZERO,
+            proof_type: ProofType::Native,
+            prover_args: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let recorder = Recorder::new(test_request());
+        let l2 = recorder.record_l2(FakeProvider);
+        let l1 = recorder.record_l1(FakeProvider);
+        let blobs = recorder.record_blobs(FakeBlobProvider);
+
+        let account = address!("0000777735367b36bc9b61c50022d9d0700db4ec");
+        let slot = (account, U256::from(7));
+        let tx_hash = B256::repeat_byte(0x11);
+        let blob_hash = B256::repeat_byte(0x22);
+        let filter = Filter::new().address(account);
+
+        let blocks = l2.get_blocks(&[(2, true), (1, false)]).await.unwrap();
+        let accounts = l2.get_accounts(&[account]).await.unwrap();
+        let values = l2.get_storage_values(&[slot]).await.unwrap();
+        let l1_blocks = l1.get_blocks(&[(10, false)]).await.unwrap();
+        let logs = l1.get_logs(&filter).await.unwrap();
+        let tx = l1.get_transaction_by_hash(tx_hash).await.unwrap();
+        let blob = blobs.get_blob(5, blob_hash).await.unwrap();
+
+        // Round trip through the file so the serialization is covered as well.
+        let dir = std::env::temp_dir().join("raiko-archive-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = get_archive_path(&dir, 2, "taiko_a7");
+        recorder.save(&path).unwrap();
+        let archive = PreflightArchive::load(&path).unwrap();
+        assert_eq!(archive.request.block_number, 2);
+
+        let l2 = archive.replay_l2();
+        let l1 = archive.replay_l1();
+        let replayed = l2.get_blocks(&[(2, true), (1, false)]).await.unwrap();
+        assert_eq!(replayed[0].header.hash, blocks[0].header.hash);
+        assert_eq!(replayed[1].header.hash, blocks[1].header.hash);
+        assert_eq!(l2.get_accounts(&[account]).await.unwrap(), accounts);
+        assert_eq!(l2.get_storage_values(&[slot]).await.unwrap(), values);
+        assert_eq!(
+            l1.get_blocks(&[(10, false)]).await.unwrap()[0].header.hash,
+            l1_blocks[0].header.hash
+        );
+        assert_eq!(l1.get_logs(&filter).await.unwrap(), logs);
+        assert_eq!(l1.get_transaction_by_hash(tx_hash).await.unwrap(), tx);
+        assert_eq!(
+            archive.replay_blobs().get_blob(5, blob_hash).await.unwrap(),
+            blob
+        );
+        assert!(l2.get_touched_state(2).await.unwrap().is_none());
+
+        // Data that was never recorded can't be replayed.
+        assert!(l2.get_blocks(&[(3, true)]).await.is_err());
+        assert!(l1.get_accounts(&[account]).await.is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use alloy_primitives::{hex, FixedBytes, B256};
+use anyhow::{anyhow, bail, Context, Result};
+use c_kzg::{Blob, Bytes48, KzgCommitment, KzgProof};
+use raiko_primitives::eip4844::{kzg_to_versioned_hash, MAINNET_KZG_TRUSTED_SETUP};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc, time::Instant};
+use tracing::{debug, warn};
+
+use crate::{
+    metrics::{inc_rpc_call, observe_rpc_latency},
+    raiko::{BlobData, BlobDataProvider},
+    rpc_provider::endpoint_label,
+};
+
+/// Fetches blobs from the `blob_sidecars` endpoint of a beacon node.
+pub struct BeaconBlobProvider {
+    beacon_rpc_url: String,
+}
+
+impl BeaconBlobProvider {
+    pub fn new(beacon_rpc_url: &str) -> Self {
+        Self {
+            beacon_rpc_url: beacon_rpc_url.to_string(),
+        }
+    }
+}
+
+impl BlobDataProvider for BeaconBlobProvider {
+    async fn get_blob(&self, slot_id: u64, blob_hash: B256) -> Result<BlobData> {
+        let blobs = get_blob_data(&self.beacon_rpc_url, slot_id).await?;
+        if blobs.data.is_empty() {
+            return Err(anyhow!(
+                "blob data for slot {slot_id} not available anymore"
+            ));
+        }
+        // Select the blob storing the tx list by the commitment in its sidecar, the blob is
+        // checked against the commitment with the sidecar's proof afterwards.
+        for sidecar in blobs.data {
+            let commitment = hex_to_bytes48(&sidecar.kzg_commitment)?;
+            let commitment_hash = KzgCommitment::from_bytes(commitment.as_slice())
+                .map(kzg_to_versioned_hash)
+                .map_err(|e| anyhow!("invalid kzg commitment: {e:?}"))?;
+            if commitment_hash == blob_hash {
+                return Ok(BlobData {
+                    blob: blob_to_bytes(&sidecar.blob)?,
+                    commitment,
+                    proof: hex_to_bytes48(&sidecar.kzg_proof)?,
+                });
+            }
+        }
+        bail!("blob {blob_hash} not found in slot {slot_id}")
+    }
+}
+
+/// Fetches blobs by versioned hash from a blob archive HTTP API, e.g. Blobscan, which keeps
+/// blobs after the beacon nodes pruned them. The API is expected to answer
+/// `GET {url}/blobs/{versioned_hash}` with a JSON object holding the blob as hex in `data`.
+pub struct BlobArchiveProvider {
+    url: String,
+}
+
+impl BlobArchiveProvider {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+        }
+    }
+}
+
+impl BlobDataProvider for BlobArchiveProvider {
+    async fn get_blob(&self, _slot_id: u64, blob_hash: B256) -> Result<BlobData> {
+        let url = format!("{}/blobs/{blob_hash}", self.url.trim_end_matches('/'));
+        let endpoint = reqwest::Url::parse(&url)
+            .map(|url| endpoint_label(&url))
+            .unwrap_or_default();
+        let start = Instant::now();
+        let response = reqwest::get(url.clone()).await;
+        let success = matches!(&response, Ok(response) if response.status().is_success());
+        inc_rpc_call("blob_archive", &endpoint, success);
+        observe_rpc_latency(
+            "blob_archive",
+            &endpoint,
+            start.elapsed().as_millis(),
+            success,
+        );
+        let response = response?;
+        if !response.status().is_success() {
+            bail!(
+                "Request {url} failed with status code: {}",
+                response.status()
+            );
+        }
+        let blob: ArchivedBlob = response.json().await?;
+        blob_data(blob_to_bytes(&blob.data)?)
+    }
+}
+
+/// Reads blobs from a local directory holding a file per blob, named by its versioned hash
+/// (`0x`-prefixed hex). A file contains either the raw blob or the blob as hex.
+pub struct LocalBlobProvider {
+    dir: PathBuf,
+}
+
+impl LocalBlobProvider {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl BlobDataProvider for LocalBlobProvider {
+    async fn get_blob(&self, _slot_id: u64, blob_hash: B256) -> Result<BlobData> {
+        let path = self.dir.join(blob_hash.to_string());
+        let content = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("failed to read blob file {path:?}"))?;
+        match std::str::from_utf8(&content) {
+            Ok(text) if text.trim().starts_with("0x") => blob_data(blob_to_bytes(text.trim())?),
+            _ => blob_data(content),
+        }
+    }
+}
+
+/// A place blobs can be fetched from.
+pub enum BlobSource {
+    Beacon(BeaconBlobProvider),
+    Archive(BlobArchiveProvider),
+    Local(LocalBlobProvider),
+}
+
+impl BlobSource {
+    fn name(&self) -> &'static str {
+        match self {
+            BlobSource::Beacon(_) => "beacon node",
+            BlobSource::Archive(_) => "blob archive",
+            BlobSource::Local(_) => "local blob directory",
+        }
+    }
+}
+
+impl BlobDataProvider for BlobSource {
+    async fn get_blob(&self, slot_id: u64, blob_hash: B256) -> Result<BlobData> {
+        match self {
+            BlobSource::Beacon(provider) => provider.get_blob(slot_id, blob_hash).await,
+            BlobSource::Archive(provider) => provider.get_blob(slot_id, blob_hash).await,
+            BlobSource::Local(provider) => provider.get_blob(slot_id, blob_hash).await,
+        }
+    }
+}
+
+/// Tries a list of blob sources in order until one returns the blob. Whatever a source
+/// returns is checked against the versioned hash, so a source can't hand out a wrong blob.
+pub struct BlobSources {
+    sources: Vec<BlobSource>,
+}
+
+impl BlobSources {
+    pub fn new(sources: Vec<BlobSource>) -> Self {
+        Self { sources }
+    }
+}
+
+impl BlobDataProvider for BlobSources {
+    async fn get_blob(&self, slot_id: u64, blob_hash: B256) -> Result<BlobData> {
+        let mut errors = Vec::new();
+        for source in &self.sources {
+            let blob = source
+                .get_blob(slot_id, blob_hash)
+                .await
+                .and_then(|blob| verify_blob(&blob, blob_hash).map(|_| blob));
+            match blob {
+                Ok(blob) => return Ok(blob),
+                Err(e) => {
+                    warn!(
+                        "Failed to get blob {blob_hash} from the {}: {e:#}",
+                        source.name()
+                    );
+                    errors.push(format!("{}: {e:#}", source.name()));
+                }
+            }
+        }
+        bail!(
+            "blob {blob_hash} of slot {slot_id} not available from any source ({})",
+            errors.join("; ")
+        )
+    }
+}
+
+/// Checks that the commitment matches the versioned hash and that the proof shows the blob
+/// matches the commitment.
+pub fn verify_blob(data: &BlobData, blob_hash: B256) -> Result<()> {
+    let commitment = KzgCommitment::from_bytes(data.commitment.as_slice())
+        .map_err(|e| anyhow!("invalid kzg commitment: {e:?}"))?;
+    let versioned_hash = kzg_to_versioned_hash(commitment);
+    if versioned_hash != blob_hash {
+        bail!("blob has versioned hash {versioned_hash}, expected {blob_hash}");
+    }
+    let blob = Blob::from_bytes(&data.blob).map_err(|e| anyhow!("invalid blob: {e:?}"))?;
+    let kzg_settings = Arc::clone(&*MAINNET_KZG_TRUSTED_SETUP);
+    let valid = KzgProof::verify_blob_kzg_proof(
+        &blob,
+        &to_bytes48(&data.commitment)?,
+        &to_bytes48(&data.proof)?,
+        &kzg_settings,
+    )
+    .map_err(|e| anyhow!("failed to verify the blob kzg proof: {e:?}"))?;
+    if !valid {
+        bail!("blob does not match its kzg commitment");
+    }
+    Ok(())
+}
+
+/// Computes the commitment and proof for a blob from a source that only has the blob itself.
+pub fn blob_data(blob: Vec<u8>) -> Result<BlobData> {
+    let kzg_blob = Blob::from_bytes(&blob).map_err(|e| anyhow!("invalid blob: {e:?}"))?;
+    let kzg_settings = Arc::clone(&*MAINNET_KZG_TRUSTED_SETUP);
+    let commitment = KzgCommitment::blob_to_kzg_commitment(&kzg_blob, &kzg_settings)
+        .map_err(|e| anyhow!("failed to compute the blob commitment: {e:?}"))?;
+    let commitment = FixedBytes::<48>::from_slice(commitment.as_slice());
+    let proof =
+        KzgProof::compute_blob_kzg_proof(&kzg_blob, &to_bytes48(&commitment)?, &kzg_settings)
+            .map_err(|e| anyhow!("failed to compute the blob proof: {e:?}"))?;
+    Ok(BlobData {
+        blob,
+        commitment,
+        proof: FixedBytes::from_slice(proof.as_slice()),
+    })
+}
+
+fn to_bytes48(bytes: &FixedBytes<48>) -> Result<Bytes48> {
+    Bytes48::from_bytes(bytes.as_slice()).map_err(|e| anyhow!("invalid 48 byte value: {e:?}"))
+}
+
+fn hex_to_bytes48(hex_str: &str) -> Result<FixedBytes<48>> {
+    let bytes = hex::decode(hex_str.trim_start_matches("0x"))?;
+    if bytes.len() != 48 {
+        bail!("expected 48 bytes, got {}", bytes.len());
+    }
+    Ok(FixedBytes::from_slice(&bytes))
+}
+
+/// Decodes a blob given as hex, with or without the `0x` prefix.
+pub fn blob_to_bytes(blob_str: &str) -> Result<Vec<u8>> {
+    hex::decode(blob_str.to_lowercase().trim_start_matches("0x"))
+        .map_err(|e| anyhow!("invalid blob hex: {e}"))
+}
+
+async fn get_blob_data(beacon_rpc_url: &str, block_id: u64) -> Result<GetBlobsResponse> {
+    let url = format!(
+        "{}/eth/v1/beacon/blob_sidecars/{block_id}",
+        beacon_rpc_url.trim_end_matches('/'),
+    );
+    let endpoint = reqwest::Url::parse(&url)
+        .map(|url| endpoint_label(&url))
+        .unwrap_or_default();
+    let start = Instant::now();
+    let response = reqwest::get(url.clone()).await;
+    let success = matches!(&response, Ok(response) if response.status().is_success());
+    inc_rpc_call("blob_sidecars", &endpoint, success);
+    observe_rpc_latency(
+        "blob_sidecars",
+        &endpoint,
+        start.elapsed().as_millis(),
+        success,
+    );
+    let response = response?;
+    if response.status().is_success() {
+        let blob_response: GetBlobsResponse = response.json().await?;
+        debug!(
+            "Fetched {} blob sidecars for slot {block_id}",
+            blob_response.data.len()
+        );
+        Ok(blob_response)
+    } else {
+        warn!(
+            "Request {url} failed with status code: {}",
+            response.status()
+        );
+        Err(anyhow!(
+            "Request failed with status code: {}",
+            response.status()
+        ))
+    }
+}
+
+// Blob data from the beacon chain
+// type Sidecar struct {
+// Index                    string                   `json:"index"`
+// Blob                     string                   `json:"blob"`
+// SignedBeaconBlockHeader  *SignedBeaconBlockHeader `json:"signed_block_header"`
+// KzgCommitment            string                   `json:"kzg_commitment"`
+// KzgProof                 string                   `json:"kzg_proof"`
+// CommitmentInclusionProof []string
+// `json:"kzg_commitment_inclusion_proof"` }
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct GetBlobData {
+    pub index: String,
+    pub blob: String,
+    // pub signed_block_header: SignedBeaconBlockHeader, // ignore for now
+    pub kzg_commitment: String,
+    pub kzg_proof: String,
+    pub kzg_commitment_inclusion_proof: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct GetBlobsResponse {
+    pub data: Vec<GetBlobData>,
+}
+
+/// The part of a blob archive response we use.
+#[derive(Clone, Debug, Deserialize)]
+struct ArchivedBlob {
+    data: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("raiko-blobs-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn versioned_hash(blob: &[u8]) -> B256 {
+        let blob = Blob::from_bytes(blob).unwrap();
+        let kzg_settings = Arc::clone(&*MAINNET_KZG_TRUSTED_SETUP);
+        kzg_to_versioned_hash(KzgCommitment::blob_to_kzg_commitment(&blob, &kzg_settings).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_fall_back_to_next_source() {
+        let blob = vec![0u8; c_kzg::BYTES_PER_BLOB];
+        let blob_hash = versioned_hash(&blob);
+
+        // The first directory holds a different blob under the same name, the second one
+        // holds the blob as hex.
+        let wrong_dir = test_dir("wrong");
+        let mut wrong_blob = blob.clone();
+        wrong_blob[1] = 1;
+        std::fs::write(wrong_dir.join(blob_hash.to_string()), &wrong_blob).unwrap();
+        let dir = test_dir("right");
+        std::fs::write(dir.join(blob_hash.to_string()), hex::encode_prefixed(&blob)).unwrap();
+
+        let sources = BlobSources::new(vec![
+            BlobSource::Local(LocalBlobProvider::new(test_dir("empty"))),
+            BlobSource::Local(LocalBlobProvider::new(&wrong_dir)),
+            BlobSource::Local(LocalBlobProvider::new(&dir)),
+        ]);
+        assert_eq!(sources.get_blob(1, blob_hash).await.unwrap().blob, blob);
+
+        let err = sources.get_blob(1, B256::ZERO).await.unwrap_err();
+        assert!(err.to_string().contains("not available from any source"));
+    }
+
+    #[test]
+    fn test_blob_to_bytes() {
+        assert_eq!(blob_to_bytes("0x0aFf").unwrap(), vec![0x0a, 0xff]);
+        assert_eq!(blob_to_bytes("0aff").unwrap(), vec![0x0a, 0xff]);
+        assert!(blob_to_bytes("0x0g").is_err());
+    }
+
+    #[test]
+    fn test_verify_blob_proof() {
+        let blob = vec![0u8; c_kzg::BYTES_PER_BLOB];
+        let blob_hash = versioned_hash(&blob);
+        let mut data = blob_data(blob.clone()).unwrap();
+        verify_blob(&data, blob_hash).unwrap();
+
+        // A proof for another blob doesn't verify against this commitment
+        let mut other_blob = blob;
+        other_blob[1] = 1;
+        data.proof = blob_data(other_blob).unwrap().proof;
+        let err = verify_blob(&data, blob_hash).unwrap_err();
+        assert!(err.to_string().contains("does not match"));
+    }
+}
@@ -1,5 +1,7 @@
+use alloy_primitives::B256;
 use axum::{http::StatusCode, response::IntoResponse};
 use raiko_lib::prover::ProverError;
+use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
 use crate::request::ProofType;
@@ -39,6 +41,11 @@ pub enum HostError {
     #[schema(value_type = Value)]
     GuestError(#[from] ProverError),
 
+    /// For errors while gathering the data for the guest input.
+    #[error("Preflight failed: {0}")]
+    #[schema(value_type = Value)]
+    Preflight(#[from] PreflightError),
+
     /// For requesting a proof of a type that is not supported.
     #[error("Feature not supported: {0}")]
     #[schema(value_type = Value)]
@@ -74,6 +81,201 @@ impl IntoResponse for HostError {
             HostError::FeatureNotSupportedError(e) => {
                 (StatusCode::METHOD_NOT_ALLOWED, e.to_string()).into_response()
             }
+            HostError::Preflight(e @ PreflightError::MissingL1Provider { .. })
+            | HostError::Preflight(e @ PreflightError::MissingBlobProvider { .. }) => {
+                (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+            }
+            HostError::Preflight(e @ PreflightError::ProposalNotFound { .. }) => {
+                (StatusCode::NOT_FOUND, e.to_string()).into_response()
+            }
+            HostError::Preflight(e @ PreflightError::BlockHashMismatch { .. }) => {
+                (StatusCode::CONFLICT, e.to_string()).into_response()
+            }
+            HostError::Preflight(e) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+            }
+        }
+    }
+}
+
+/// The step of preflight an error happened in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreflightStage {
+    /// Fetching the block and its parent from the L2 node.
+    FetchBlock,
+    /// Fetching the L1 blocks referenced by the anchor transaction.
+    FetchL1Blocks,
+    /// Finding the `BlockProposed` event and the proposal transaction on L1.
+    ProposalEvent,
+    /// Fetching the blob holding the tx list.
+    BlobData,
+    /// Decoding the transactions of the block.
+    DecodeTransactions,
+    /// Executing the block to find the state it touches.
+    Execution,
+    /// Fetching the storage proofs and building the tries.
+    StateProofs,
+    /// Fetching the headers of the blocks read by `BLOCKHASH`.
+    AncestorHeaders,
+}
+
+impl std::fmt::Display for PreflightStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PreflightStage::FetchBlock => "fetching the block",
+            PreflightStage::FetchL1Blocks => "fetching the L1 blocks",
+            PreflightStage::ProposalEvent => "finding the block proposal",
+            PreflightStage::BlobData => "fetching the blob",
+            PreflightStage::DecodeTransactions => "decoding the transactions",
+            PreflightStage::Execution => "executing the block",
+            PreflightStage::StateProofs => "fetching the state proofs",
+            PreflightStage::AncestorHeaders => "fetching the ancestor headers",
+        })
+    }
+}
+
+/// An error while gathering the data for the guest input of a block.
+#[derive(thiserror::Error, Debug)]
+pub enum PreflightError {
+    /// Taiko blocks need an L1 node to find the block proposal.
+    #[error("block {block_number}, {stage}: no L1 provider, set `l1_rpc`")]
+    MissingL1Provider {
+        block_number: u64,
+        stage: PreflightStage,
+    },
+
+    /// Blocks with the tx list in a blob need a beacon node.
+    #[error("block {block_number}, {stage}: no blob provider, set `beacon_rpc`")]
+    MissingBlobProvider {
+        block_number: u64,
+        stage: PreflightStage,
+    },
+
+    /// The node returned a different block than the one requested by hash.
+    #[error("block {block_number}, {stage}: the block has hash {actual}, expected {expected}")]
+    BlockHashMismatch {
+        block_number: u64,
+        stage: PreflightStage,
+        expected: B256,
+        actual: B256,
+    },
+
+    /// No `BlockProposed` event for the block was found in the searched L1 blocks.
+    #[error(
+        "block {block_number}, {stage}: no proposal found in L1 blocks {from_block} to {to_block}"
+    )]
+    ProposalNotFound {
+        block_number: u64,
+        stage: PreflightStage,
+        from_block: u64,
+        to_block: u64,
+    },
+
+    /// The proposal found on L1 doesn't match the L1 block the block is anchored to.
+    #[error(
+        "block {block_number}, {stage}: invalid proposal in L1 block {l1_block_number}: {reason}"
+    )]
+    InvalidProposal {
+        block_number: u64,
+        stage: PreflightStage,
+        l1_block_number: u64,
+        reason: String,
+    },
+
+    /// The block was proposed with a blob but the proposal transaction has no blob hashes.
+    #[error("block {block_number}, {stage}: the proposal transaction {tx_hash} has no blobs")]
+    NoBlobHashes {
+        block_number: u64,
+        stage: PreflightStage,
+        tx_hash: B256,
+    },
+
+    /// None of the blob sources could provide the blob.
+    #[error("block {block_number}, {stage}: blob {blob_hash} is not available: {source:#}")]
+    BlobUnavailable {
+        block_number: u64,
+        stage: PreflightStage,
+        blob_hash: B256,
+        source: anyhow::Error,
+    },
+
+    /// Fewer transactions were decoded from the tx list than the node has in the block.
+    #[error(
+        "block {block_number}, {stage}: decoded {decoded} transactions but the block has {expected}"
+    )]
+    TransactionCountMismatch {
+        block_number: u64,
+        stage: PreflightStage,
+        decoded: usize,
+        expected: usize,
+    },
+
+    /// The node returned the transaction hashes instead of the full transactions.
+    #[error("block {block_number}, {stage}: the node did not return the full transactions")]
+    TransactionsNotFull {
+        block_number: u64,
+        stage: PreflightStage,
+    },
+
+    /// A transaction returned by the node can't be converted.
+    #[error("block {block_number}, {stage}: invalid transaction {tx_hash}: {reason}")]
+    InvalidTransaction {
+        block_number: u64,
+        stage: PreflightStage,
+        tx_hash: B256,
+        reason: String,
+    },
+
+    /// Any other error, e.g. from the providers or the block builder.
+    #[error("block {block_number}, {stage}: {source:#}")]
+    Failed {
+        block_number: u64,
+        stage: PreflightStage,
+        source: anyhow::Error,
+    },
+}
+
+impl PreflightError {
+    pub fn failed(block_number: u64, stage: PreflightStage, source: anyhow::Error) -> Self {
+        PreflightError::Failed {
+            block_number,
+            stage,
+            source,
+        }
+    }
+
+    /// The block preflight was run for.
+    pub fn block_number(&self) -> u64 {
+        match self {
+            PreflightError::MissingL1Provider { block_number, .. }
+            | PreflightError::MissingBlobProvider { block_number, .. }
+            | PreflightError::BlockHashMismatch { block_number, .. }
+            | PreflightError::ProposalNotFound { block_number, .. }
+            | PreflightError::InvalidProposal { block_number, .. }
+            | PreflightError::NoBlobHashes { block_number, .. }
+            | PreflightError::BlobUnavailable { block_number, .. }
+            | PreflightError::TransactionCountMismatch { block_number, .. }
+            | PreflightError::TransactionsNotFull { block_number, .. }
+            | PreflightError::InvalidTransaction { block_number, .. }
+            | PreflightError::Failed { block_number, .. } => *block_number,
+        }
+    }
+
+    /// The stage of preflight that failed.
+    pub fn stage(&self) -> PreflightStage {
+        match self {
+            PreflightError::MissingL1Provider { stage, .. }
+            | PreflightError::MissingBlobProvider { stage, .. }
+            | PreflightError::BlockHashMismatch { stage, .. }
+            | PreflightError::ProposalNotFound { stage, .. }
+            | PreflightError::InvalidProposal { stage, .. }
+            | PreflightError::NoBlobHashes { stage, .. }
+            | PreflightError::BlobUnavailable { stage, .. }
+            | PreflightError::TransactionCountMismatch { stage, .. }
+            | PreflightError::TransactionsNotFull { stage, .. }
+            | PreflightError::InvalidTransaction { stage, .. }
+            | PreflightError::Failed { stage, .. } => *stage,
         }
     }
 }
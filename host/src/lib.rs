@@ -12,26 +12,44 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod archive;
+pub mod blob_provider;
 pub mod error;
 pub mod metrics;
 pub mod preflight;
 pub mod provider_db;
 pub mod raiko;
 pub mod request;
+pub mod rpc_cache;
 pub mod rpc_provider;
 pub mod server;
+pub mod state_dump;
 
-use std::{alloc, collections::HashMap, fmt::Debug, path::PathBuf};
+use std::{
+    alloc,
+    collections::HashMap,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
 
 use alloy_primitives::Address;
 use alloy_rpc_types::EIP1186AccountProofResponse;
 use anyhow::{Context, Result};
 use cap::Cap;
 use clap::Parser;
+use raiko_lib::consts::{ChainSpec, SupportedChainSpecs};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tracing::{error, info};
 
-use crate::{error::HostError, request::ProofRequestOpt};
+use crate::{
+    error::HostError,
+    request::{NetworkProfile, ProofRequestOpt},
+    rpc_cache::RpcCache,
+    rpc_provider::DispatchConfig,
+};
 
 type MerkleProof = HashMap<Address, EIP1186AccountProofResponse>;
 
@@ -54,6 +72,18 @@ fn default_config_path() -> PathBuf {
     PathBuf::from("host/config/config.json")
 }
 
+fn default_rpc_cache_size() -> u64 {
+    1024
+}
+
+fn default_rpc_max_in_flight() -> usize {
+    4
+}
+
+fn default_rpc_max_batch_size() -> usize {
+    1000
+}
+
 fn default_log_level() -> String {
     "info".to_string()
 }
@@ -91,15 +121,77 @@ pub struct Cli {
     /// Use a local directory as a cache for input. Accepts a custom directory.
     cache_path: Option<PathBuf>,
 
+    #[arg(long, require_equals = true)]
+    /// Record everything fetched from the nodes for a request into an archive in this
+    /// directory. The archive can be rerun offline with the `replay` binary.
+    record_path: Option<PathBuf>,
+
+    #[arg(long, require_equals = true)]
+    /// Serve the blocks and state from state dumps in this directory instead of the nodes, for
+    /// local devnets without an archive node for `eth_getProof`. The directory has the blocks
+    /// in `blocks.json` and the state after a block in `<block number>.json`.
+    state_dump: Option<PathBuf>,
+
+    #[arg(long, require_equals = true)]
+    /// Keep immutable data fetched over JSON-RPC (headers, contract code, proofs) in this
+    /// directory so it can be reused by later requests. Only read at startup.
+    rpc_cache_path: Option<PathBuf>,
+
+    #[arg(long, require_equals = true, default_value = "1024")]
+    #[serde(default = "default_rpc_cache_size")]
+    /// The maximum size of the rpc cache in MB, the least recently used entries are removed
+    /// when it grows larger
+    rpc_cache_size: u64,
+
+    #[arg(long, require_equals = true, default_value = "4")]
+    #[serde(default = "default_rpc_max_in_flight")]
+    /// Limit the number of JSON-RPC batches in flight per node endpoint
+    rpc_max_in_flight: usize,
+
+    #[arg(long, require_equals = true, default_value = "0")]
+    /// Limit the number of JSON-RPC batches sent to a node endpoint per second, 0 for no limit
+    rpc_max_requests_per_second: u32,
+
+    #[arg(long, require_equals = true, default_value = "1000")]
+    #[serde(default = "default_rpc_max_batch_size")]
+    /// The maximum number of calls in a JSON-RPC batch. Smaller batches are used while a node
+    /// rejects batches as too large.
+    rpc_max_batch_size: usize,
+
     #[arg(long, require_equals = true, env = "RUST_LOG", default_value = "info")]
     #[serde(default = "default_log_level")]
     /// Set the log level
     pub log_level: String,
 
+    #[arg(long, default_value_t = false)]
+    /// Collect the state touched by a block with a single `debug_traceBlockByNumber` call
+    /// (`prestateTracer`) instead of discovering it through repeated execution. The L2 node
+    /// needs to expose the `debug` namespace, otherwise this falls back to execution.
+    pub prestate_tracer: bool,
+
+    #[arg(long, require_equals = true)]
+    /// Export tracing spans to an OTLP collector over gRPC, e.g. `http://localhost:4317`
+    pub otlp_endpoint: Option<String>,
+
     #[command(flatten)]
     #[serde(flatten)]
     /// Proof request options
     pub proof_request_opt: ProofRequestOpt,
+
+    #[arg(long, require_equals = true)]
+    /// Path to a TOML or JSON file with chain specifications, which are added to the built-in
+    /// networks or replace a built-in network with the same name
+    chain_spec_path: Option<PathBuf>,
+
+    #[arg(skip)]
+    #[serde(skip)]
+    /// The built-in chain specifications merged with the ones from `chain_spec_path`
+    pub chain_specs: SupportedChainSpecs,
+
+    #[arg(skip)]
+    /// Per network defaults (endpoints, proof type, prover args) keyed by network name, only
+    /// configurable through the config file
+    pub networks: HashMap<String, NetworkProfile>,
 }
 
 impl Cli {
@@ -113,6 +205,64 @@ impl Cli {
         *self = serde_json::from_value(config)?;
         Ok(())
     }
+
+    /// Build the config for a proof request: the global options are overridden by the profile
+    /// of the requested network, which are in turn overridden by the request itself.
+    pub fn request_config(&self, req: &Value) -> Result<ProofRequestOpt, HostError> {
+        let mut config = self.proof_request_opt.clone();
+        let network = req
+            .get("network")
+            .and_then(Value::as_str)
+            .or(config.network.as_deref())
+            .map(str::to_lowercase);
+        if let Some(profile) = network.and_then(|network| self.networks.get(&network)) {
+            config.merge_profile(profile)?;
+        }
+        config.merge(req)?;
+        Ok(config)
+    }
+
+    /// Re-read the chain specifications from `chain_spec_path`, if set.
+    pub fn load_chain_specs(&mut self) -> Result<(), HostError> {
+        self.chain_specs = match &self.chain_spec_path {
+            Some(path) => load_chain_specs(path)?,
+            None => SupportedChainSpecs::default(),
+        };
+        Ok(())
+    }
+
+    /// How JSON-RPC batches are dispatched to the nodes.
+    pub fn dispatch_config(&self) -> DispatchConfig {
+        DispatchConfig {
+            max_in_flight: self.rpc_max_in_flight,
+            max_requests_per_second: self.rpc_max_requests_per_second,
+            max_batch_size: self.rpc_max_batch_size,
+        }
+    }
+}
+
+/// A chain spec file: the chain specifications listed under `chains`.
+#[derive(Deserialize)]
+struct ChainSpecFile {
+    chains: Vec<ChainSpec>,
+}
+
+/// Loads the chain specifications from a TOML (by extension) or JSON file on top of the
+/// built-in ones.
+pub fn load_chain_specs(path: &Path) -> Result<SupportedChainSpecs, HostError> {
+    let contents = std::fs::read_to_string(path)?;
+    // TOML goes through a json value because TOML keys can't be deserialized as the block
+    // numbers the protocol versions are keyed by.
+    let value: Value = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::from_str(&contents).with_context(|| format!("Invalid chain spec file {path:?}"))?
+    } else {
+        serde_json::from_str(&contents)?
+    };
+    let file: ChainSpecFile = serde_json::from_value(value)?;
+
+    let mut chain_specs = SupportedChainSpecs::default();
+    chain_specs.extend(file.chains);
+    Ok(chain_specs)
 }
 
 /// Merges two json's together, overwriting `a` with the values of `b`
@@ -131,15 +281,64 @@ fn merge(a: &mut Value, b: &Value) {
 
 #[derive(Debug, Clone)]
 pub struct ProverState {
+    /// The options the host was started with. Server level options (address, concurrency
+    /// limit, logging) are only read from here and thus require a restart to change.
     pub opts: Cli,
+    /// The command line options before the config file was merged in, which keep overriding
+    /// the config file contents on every reload.
+    cli_opts: Cli,
+    /// The latest successfully loaded options, used for every new request.
+    current_opts: Arc<RwLock<Cli>>,
+    /// The rpc cache shared by all requests, if enabled.
+    pub rpc_cache: Option<Arc<RpcCache>>,
 }
 
 impl ProverState {
     pub fn init() -> Result<Self, HostError> {
         // Read the command line arguments;
-        let mut opts = Cli::parse();
+        Self::from_cli(Cli::parse())
+    }
+
+    pub fn from_cli(cli_opts: Cli) -> Result<Self, HostError> {
         // Read the config file.
+        let opts = Self::load(&cli_opts)?;
+
+        let rpc_cache = opts
+            .rpc_cache_path
+            .as_ref()
+            .map(|path| RpcCache::open(path, opts.rpc_cache_size * 1024 * 1024))
+            .transpose()?
+            .map(Arc::new);
+
+        Ok(Self {
+            current_opts: Arc::new(RwLock::new(opts.clone())),
+            opts,
+            cli_opts,
+            rpc_cache,
+        })
+    }
+
+    /// A snapshot of the current options. Requests should take one snapshot when they start
+    /// so that a reload doesn't change the config halfway through a proof.
+    pub fn current_opts(&self) -> Cli {
+        self.current_opts
+            .read()
+            .expect("config lock poisoned")
+            .clone()
+    }
+
+    /// Re-read the config file and merge the command line options on top of it, the chain
+    /// spec file is re-read as well. On failure the current options are left untouched.
+    pub fn reload(&self) -> Result<(), HostError> {
+        let opts = Self::load(&self.cli_opts)?;
+        *self.current_opts.write().expect("config lock poisoned") = opts;
+        Ok(())
+    }
+
+    fn load(cli_opts: &Cli) -> Result<Cli, HostError> {
+        let mut opts = cli_opts.clone();
         opts.merge_from_file()?;
+        opts.load_chain_specs()?;
 
         // Check if the cache path exists and create it if it doesn't.
         if let Some(cache_path) = &opts.cache_path {
@@ -147,11 +346,66 @@ impl ProverState {
                 std::fs::create_dir_all(cache_path).context("Could not create cache dir")?;
             }
         }
+        if let Some(record_path) = &opts.record_path {
+            if !record_path.exists() {
+                std::fs::create_dir_all(record_path).context("Could not create record dir")?;
+            }
+        }
 
-        Ok(Self { opts })
+        Ok(opts)
+    }
+
+    fn reload_logged(&self, reason: &str) {
+        match self.reload() {
+            Ok(()) => info!("Reloaded config {:?} ({reason})", self.cli_opts.config_path),
+            Err(e) => error!(
+                "Rejected config {:?} ({reason}), keeping the previous config: {e}",
+                self.cli_opts.config_path
+            ),
+        }
+    }
+
+    /// Reload the config whenever the config file changes or the process receives a SIGHUP.
+    pub fn spawn_config_watcher(&self) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut last_modified = config_modified(&state.cli_opts.config_path);
+            let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                let modified = config_modified(&state.cli_opts.config_path);
+                if modified != last_modified {
+                    last_modified = modified;
+                    state.reload_logged("file changed");
+                }
+            }
+        });
+
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let state = self.clone();
+            match signal(SignalKind::hangup()) {
+                Ok(mut hangup) => {
+                    tokio::spawn(async move {
+                        while hangup.recv().await.is_some() {
+                            state.reload_logged("SIGHUP");
+                        }
+                    });
+                }
+                Err(e) => error!("Could not listen for SIGHUP: {e}"),
+            }
+        }
     }
 }
 
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn config_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
 mod memory {
     use crate::ALLOCATOR;
 
@@ -172,3 +426,147 @@ mod memory {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_config() {
+        let config_path =
+            std::env::temp_dir().join(format!("raiko-reload-{}.json", std::process::id()));
+        std::fs::write(
+            &config_path,
+            r#"{"rpc": "http://rpc-a", "l1_rpc": "http://l1-a"}"#,
+        )
+        .unwrap();
+
+        let mut cli = Cli {
+            config_path: config_path.clone(),
+            ..Default::default()
+        };
+        cli.proof_request_opt.l1_rpc = Some("http://l1-cli".to_owned());
+        let state = ProverState::from_cli(cli).unwrap();
+        let opts = state.current_opts().proof_request_opt;
+        assert_eq!(opts.rpc.as_deref(), Some("http://rpc-a"));
+        assert_eq!(opts.l1_rpc.as_deref(), Some("http://l1-cli"));
+
+        // A changed config is picked up, command line options still take precedence.
+        std::fs::write(
+            &config_path,
+            r#"{"rpc": "http://rpc-b", "l1_rpc": "http://l1-b"}"#,
+        )
+        .unwrap();
+        state.reload().unwrap();
+        let opts = state.current_opts().proof_request_opt;
+        assert_eq!(opts.rpc.as_deref(), Some("http://rpc-b"));
+        assert_eq!(opts.l1_rpc.as_deref(), Some("http://l1-cli"));
+
+        // An invalid config is rejected and the previous one stays active.
+        std::fs::write(&config_path, r#"{"rpc": "#).unwrap();
+        assert!(state.reload().is_err());
+        let opts = state.current_opts().proof_request_opt;
+        assert_eq!(opts.rpc.as_deref(), Some("http://rpc-b"));
+
+        std::fs::remove_file(&config_path).unwrap();
+    }
+
+    #[test]
+    fn test_network_profile() {
+        let cli: Cli = serde_json::from_value(serde_json::json!({
+            "network": "taiko_a7",
+            "proof_type": "native",
+            "rpc": "http://global",
+            "networks": {
+                "taiko_a7": {
+                    "rpc": "http://a7",
+                    "l1_rpc": "http://l1",
+                    "proof_type": "sgx",
+                    "prover_args": { "sgx": { "instance_id": 1 } }
+                },
+                "ethereum": { "rpc": "http://eth" }
+            }
+        }))
+        .unwrap();
+
+        // The profile of the default network is used when the request doesn't set one.
+        let config = cli.request_config(&serde_json::json!({})).unwrap();
+        assert_eq!(config.rpc.as_deref(), Some("http://a7"));
+        assert_eq!(config.l1_rpc.as_deref(), Some("http://l1"));
+        assert_eq!(config.proof_type.as_deref(), Some("sgx"));
+        assert_eq!(
+            config.prover_args.sgx,
+            Some(serde_json::json!({ "instance_id": 1 }))
+        );
+
+        // The request can still override anything in the profile.
+        let config = cli
+            .request_config(&serde_json::json!({ "rpc": "http://custom", "proof_type": "sp1" }))
+            .unwrap();
+        assert_eq!(config.rpc.as_deref(), Some("http://custom"));
+        assert_eq!(config.l1_rpc.as_deref(), Some("http://l1"));
+        assert_eq!(config.proof_type.as_deref(), Some("sp1"));
+
+        // The profile follows the requested network.
+        let config = cli
+            .request_config(&serde_json::json!({ "network": "Ethereum" }))
+            .unwrap();
+        assert_eq!(config.rpc.as_deref(), Some("http://eth"));
+        assert_eq!(config.l1_rpc, None);
+        assert_eq!(config.proof_type.as_deref(), Some("native"));
+    }
+
+    #[test]
+    fn test_load_chain_specs() {
+        let dir = std::env::temp_dir();
+        let toml_path = dir.join(format!("raiko-chain-specs-{}.toml", std::process::id()));
+        std::fs::write(
+            &toml_path,
+            r#"
+[[chains]]
+name = "taiko_devnet"
+chain_id = 167001
+max_spec_id = "SHANGHAI"
+l1_contract = "0xB261C6fACc0707B674c7491338412FDF44733A4B"
+l2_contract = "0x1670010000000000000000000000000000010001"
+genesis_time = 1695902400
+seconds_per_slot = 12
+is_taiko = true
+
+[chains.hard_forks]
+SHANGHAI = { Block = 0 }
+CANCUN = "TBD"
+
+[chains.protocol_versions]
+0 = "a7"
+"#,
+        )
+        .unwrap();
+        let chain_specs = load_chain_specs(&toml_path).unwrap();
+        std::fs::remove_file(&toml_path).unwrap();
+
+        let devnet = chain_specs.get_chain_spec("taiko_devnet").unwrap();
+        assert_eq!(devnet.chain_id, 167001);
+        assert!(devnet.is_taiko());
+        assert_eq!(
+            devnet.protocol_version(100),
+            Some(raiko_lib::protocol::ProtocolVersion::A7)
+        );
+        assert_eq!(devnet.gas_constants(), &Default::default());
+        // The built-in networks stay available.
+        assert!(chain_specs.get_chain_spec("taiko_a7").is_some());
+
+        // A JSON file can replace a built-in network.
+        let json_path = dir.join(format!("raiko-chain-specs-{}.json", std::process::id()));
+        let mut holesky = chain_specs.get_chain_spec("holesky").unwrap();
+        holesky.chain_id = 1337;
+        std::fs::write(
+            &json_path,
+            serde_json::to_string(&serde_json::json!({ "chains": [holesky] })).unwrap(),
+        )
+        .unwrap();
+        let chain_specs = load_chain_specs(&json_path).unwrap();
+        std::fs::remove_file(&json_path).unwrap();
+        assert_eq!(chain_specs.get_chain_spec("holesky"), Some(holesky));
+    }
+}
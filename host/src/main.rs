@@ -1,13 +1,21 @@
 #![allow(incomplete_features)]
 use std::path::PathBuf;
 
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    runtime,
+    trace::{self, Tracer},
+    Resource,
+};
 use raiko_host::{error::HostResult, server::serve, ProverState};
-use tracing::debug;
+use tracing::{debug, Subscriber};
 use tracing_appender::{
     non_blocking::WorkerGuard,
     rolling::{Builder, Rotation},
 };
-use tracing_subscriber::FmtSubscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{layer::SubscriberExt, registry::LookupSpan, FmtSubscriber};
 
 #[tokio::main]
 async fn main() -> HostResult<()> {
@@ -20,9 +28,13 @@ async fn main() -> HostResult<()> {
         &state.opts.log_path,
         &state.opts.log_level,
         state.opts.max_log,
+        &state.opts.otlp_endpoint,
     );
 
-    serve(state).await?;
+    let result = serve(state).await;
+    // Flush any spans that are still buffered in the batch exporter.
+    opentelemetry::global::shutdown_tracer_provider();
+    result?;
     Ok(())
 }
 
@@ -30,6 +42,7 @@ fn subscribe_log(
     log_path: &Option<PathBuf>,
     log_level: &String,
     max_log: usize,
+    otlp_endpoint: &Option<String>,
 ) -> Option<WorkerGuard> {
     let subscriber_builder = FmtSubscriber::builder()
         .with_env_filter(log_level)
@@ -44,13 +57,39 @@ fn subscribe_log(
                 .expect("initializing rolling file appender failed");
             let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
             let subscriber = subscriber_builder.json().with_writer(non_blocking).finish();
+            let subscriber = subscriber.with(otlp_layer(otlp_endpoint));
             tracing::subscriber::set_global_default(subscriber).unwrap();
             Some(_guard)
         }
         None => {
             let subscriber = subscriber_builder.finish();
+            let subscriber = subscriber.with(otlp_layer(otlp_endpoint));
             tracing::subscriber::set_global_default(subscriber).unwrap();
             None
         }
     }
 }
+
+/// Creates a layer exporting all spans to the OTLP collector at `otlp_endpoint`, if one is set.
+fn otlp_layer<S>(otlp_endpoint: &Option<String>) -> Option<OpenTelemetryLayer<S, Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint = otlp_endpoint.as_ref()?;
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                "raiko-host",
+            )])),
+        )
+        .install_batch(runtime::Tokio)
+        .expect("initializing otlp exporter failed");
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
@@ -1,7 +1,7 @@
 use lazy_static::lazy_static;
 use prometheus::{
-    labels, register_histogram_vec, register_int_counter_vec, register_int_gauge, HistogramVec,
-    IntCounterVec, IntGauge,
+    exponential_buckets, labels, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge, HistogramVec, IntCounterVec, IntGauge,
 };
 
 use crate::request::ProofType;
@@ -60,6 +60,50 @@ lazy_static! {
         "number of requests currently being processed"
     )
     .unwrap();
+    pub static ref RPC_CALL_COUNT: IntCounterVec = register_int_counter_vec!(
+        "rpc_call_count",
+        "the number of JSON-RPC calls made to a node",
+        &["method", "endpoint", "status"]
+    )
+    .unwrap();
+    pub static ref RPC_LATENCY: HistogramVec = register_histogram_vec!(
+        "rpc_latency_histogram",
+        "time taken in milliseconds for a JSON-RPC request or batch to complete",
+        &["method", "endpoint", "status"],
+        exponential_buckets(5.0, 2.0, 12).unwrap()
+    )
+    .unwrap();
+    pub static ref RPC_BATCH_SIZE: HistogramVec = register_histogram_vec!(
+        "rpc_batch_size_histogram",
+        "the number of calls per method sent in a single JSON-RPC batch",
+        &["method", "endpoint"],
+        exponential_buckets(1.0, 2.0, 11).unwrap()
+    )
+    .unwrap();
+    pub static ref RPC_RETRY_COUNT: IntCounterVec = register_int_counter_vec!(
+        "rpc_retry_count",
+        "the number of times a JSON-RPC batch was retried after sending it to an endpoint failed",
+        &["endpoint"]
+    )
+    .unwrap();
+    pub static ref RPC_ENDPOINT_BENCHED_COUNT: IntCounterVec = register_int_counter_vec!(
+        "rpc_endpoint_benched_count",
+        "the number of times an endpoint was benched after failing repeatedly",
+        &["endpoint"]
+    )
+    .unwrap();
+    pub static ref RPC_CACHE_HIT_COUNT: IntCounterVec = register_int_counter_vec!(
+        "rpc_cache_hit_count",
+        "the number of lookups answered by the on-disk rpc cache",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref RPC_CACHE_MISS_COUNT: IntCounterVec = register_int_counter_vec!(
+        "rpc_cache_miss_count",
+        "the number of lookups the on-disk rpc cache couldn't answer",
+        &["kind"]
+    )
+    .unwrap();
 }
 
 /// Increase the count of requests currently being processed.
@@ -72,6 +116,23 @@ pub fn dec_current_req() {
     CONCURRENT_REQUESTS.dec();
 }
 
+/// Counts a request as being processed until it's dropped, whichever way the request ends.
+#[must_use]
+pub struct CurrentReqGuard(());
+
+/// Increase the count of requests currently being processed, and decrease it again when the
+/// returned guard is dropped.
+pub fn track_current_req() -> CurrentReqGuard {
+    inc_current_req();
+    CurrentReqGuard(())
+}
+
+impl Drop for CurrentReqGuard {
+    fn drop(&mut self) {
+        dec_current_req();
+    }
+}
+
 /// Increment the request count for the host.
 pub fn inc_host_req_count(block_id: u64) {
     let block_id = block_id.to_string();
@@ -157,3 +218,74 @@ pub fn observe_total_time(block_id: u64, time: u128, success: bool) {
     };
     TOTAL_TIME.with(&labels).observe(time as f64);
 }
+
+/// Increment the count of JSON-RPC calls for the given method and endpoint.
+pub fn inc_rpc_call(method: &str, endpoint: &str, success: bool) {
+    let status = rpc_status(success);
+    let labels = labels! {
+        "method" => method,
+        "endpoint" => endpoint,
+        "status" => status,
+    };
+    RPC_CALL_COUNT.with(&labels).inc();
+}
+
+/// Observe the time taken for a JSON-RPC request (single call or batch).
+pub fn observe_rpc_latency(method: &str, endpoint: &str, time: u128, success: bool) {
+    let status = rpc_status(success);
+    let labels = labels! {
+        "method" => method,
+        "endpoint" => endpoint,
+        "status" => status,
+    };
+    RPC_LATENCY.with(&labels).observe(time as f64);
+}
+
+/// Observe the number of calls of the given method sent in a single JSON-RPC batch.
+pub fn observe_rpc_batch_size(method: &str, endpoint: &str, size: usize) {
+    let labels = labels! {
+        "method" => method,
+        "endpoint" => endpoint,
+    };
+    RPC_BATCH_SIZE.with(&labels).observe(size as f64);
+}
+
+/// Increment the count of retries caused by the given endpoint.
+pub fn inc_rpc_retry(endpoint: &str) {
+    let labels = labels! {
+        "endpoint" => endpoint,
+    };
+    RPC_RETRY_COUNT.with(&labels).inc();
+}
+
+/// Increment the count of times the given endpoint was benched.
+pub fn inc_rpc_endpoint_benched(endpoint: &str) {
+    let labels = labels! {
+        "endpoint" => endpoint,
+    };
+    RPC_ENDPOINT_BENCHED_COUNT.with(&labels).inc();
+}
+
+/// Increment the count of rpc cache hits for the given kind of data.
+pub fn inc_rpc_cache_hit(kind: &str) {
+    let labels = labels! {
+        "kind" => kind,
+    };
+    RPC_CACHE_HIT_COUNT.with(&labels).inc();
+}
+
+/// Increment the count of rpc cache misses for the given kind of data.
+pub fn inc_rpc_cache_miss(kind: &str) {
+    let labels = labels! {
+        "kind" => kind,
+    };
+    RPC_CACHE_MISS_COUNT.with(&labels).inc();
+}
+
+fn rpc_status(success: bool) -> &'static str {
+    if success {
+        "ok"
+    } else {
+        "error"
+    }
+}
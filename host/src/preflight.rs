@@ -1,232 +1,411 @@
 use alloy_consensus::{
-    SignableTransaction, TxEip1559, TxEip2930, TxEip4844, TxEip4844Variant, TxEnvelope, TxLegacy,
+    Header as AlloyConsensusHeader, SignableTransaction, TxEip1559, TxEip2930, TxEip4844,
+    TxEip4844Variant, TxEnvelope, TxLegacy,
 };
 pub use alloy_primitives::*;
-use alloy_provider::{Provider, ReqwestProvider};
 use alloy_rpc_types::{Block, BlockTransactions, Filter, Transaction as AlloyRpcTransaction};
-use alloy_sol_types::{SolCall, SolEvent};
-use anyhow::{bail, Result};
-use c_kzg::{Blob, KzgCommitment};
+use anyhow::{anyhow, Context, Result};
 use raiko_lib::{
     builder::{
-        prepare::TaikoHeaderPrepStrategy, BlockBuilder, OptimisticDatabase, TkoTxExecStrategy,
+        prepare::{HeaderPrepStrategy, TaikoHeaderPrepStrategy, UncheckedHeaderPrepStrategy},
+        BlockBuilder, OptimisticDatabase, TkoTxExecStrategy,
     },
-    consts::{ChainSpec, Network},
-    input::{
-        decode_anchor, proposeBlockCall, taiko_a6::BlockProposed as TestnetBlockProposed,
-        BlockProposed, GuestInput, TaikoGuestInput, TaikoProverData,
-    },
-    utils::{generate_transactions, to_header, zlib_compress_data},
+    consts::ChainSpec,
+    input::{BlobTxListLocation, BlockProposed, GuestInput, TaikoGuestInput, TaikoProverData},
+    protocol::ProtocolVersion,
+    utils::{generate_transactions, to_header, zlib_compress_data, HeaderHasher},
     Measurement,
 };
-use raiko_primitives::{
-    eip4844::{kzg_to_versioned_hash, MAINNET_KZG_TRUSTED_SETUP},
-    mpt::proofs_to_tries,
-};
+use raiko_primitives::mpt::proofs_to_tries;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::RangeInclusive,
+    time::Instant,
+};
+use tracing::{debug, info, instrument};
 
 use crate::{
-    provider_db::ProviderDb, raiko::BlockDataProvider, rpc_provider::RpcBlockDataProvider,
+    error::{PreflightError, PreflightStage},
+    provider_db::{CarriedState, ProviderDb},
+    raiko::{BlobDataProvider, BlockDataProvider},
 };
 
-pub async fn preflight<BDP: BlockDataProvider>(
+/// The number of L1 blocks after the anchored L1 block searched for the proposal of a
+/// Taiko block when the request doesn't say otherwise.
+pub const DEFAULT_PROPOSAL_SEARCH_RANGE: u64 = 64;
+
+/// How preflight finds the data of a block and what the block has to match besides its
+/// number.
+#[derive(Clone, Debug)]
+pub struct PreflightOptions {
+    /// The number of L1 blocks after the anchored L1 block searched for the proposal.
+    pub proposal_search_range: u64,
+    /// The hash the block has to have.
+    pub block_hash: Option<B256>,
+    /// The L1 transaction that has to have proposed the block.
+    pub proposal_tx_hash: Option<B256>,
+    /// The state after the parent block from its own preflight.
+    pub carried_state: Option<CarriedState>,
+}
+
+impl Default for PreflightOptions {
+    fn default() -> Self {
+        Self {
+            proposal_search_range: DEFAULT_PROPOSAL_SEARCH_RANGE,
+            block_hash: None,
+            proposal_tx_hash: None,
+            carried_state: None,
+        }
+    }
+}
+
+pub async fn preflight<BDP: BlockDataProvider, L1: BlockDataProvider, BP: BlobDataProvider>(
     provider: BDP,
     block_number: u64,
     chain_spec: ChainSpec,
     prover_data: TaikoProverData,
-    l1_rpc_url: Option<String>,
-    beacon_rpc_url: Option<String>,
-) -> Result<GuestInput> {
+    provider_l1: Option<L1>,
+    blob_provider: Option<BP>,
+    options: PreflightOptions,
+) -> Result<(GuestInput, PreflightStats), PreflightError> {
+    let (input, stats, _) = preflight_carrying(
+        provider,
+        block_number,
+        chain_spec,
+        prover_data,
+        provider_l1,
+        blob_provider,
+        options,
+    )
+    .await?;
+    Ok((input, stats))
+}
+
+/// Preflights consecutive blocks, each starting from the state the block before it left
+/// behind, so that only the accounts and storage slots a block newly touches are fetched.
+/// `providers` returns the L2, L1 and blob providers to use for a block.
+pub async fn preflight_blocks<BDP, L1, BP>(
+    block_numbers: RangeInclusive<u64>,
+    chain_spec: ChainSpec,
+    prover_data: TaikoProverData,
+    proposal_search_range: u64,
+    mut providers: impl FnMut(u64) -> (BDP, Option<L1>, Option<BP>),
+) -> Result<Vec<(GuestInput, PreflightStats)>, PreflightError>
+where
+    BDP: BlockDataProvider,
+    L1: BlockDataProvider,
+    BP: BlobDataProvider,
+{
+    let mut carried_state = None;
+    let mut inputs = Vec::new();
+    for block_number in block_numbers {
+        let (provider, provider_l1, blob_provider) = providers(block_number);
+        let (input, stats, state) = preflight_carrying(
+            provider,
+            block_number,
+            chain_spec.clone(),
+            prover_data.clone(),
+            provider_l1,
+            blob_provider,
+            PreflightOptions {
+                proposal_search_range,
+                carried_state: carried_state.take(),
+                ..Default::default()
+            },
+        )
+        .await?;
+        carried_state = Some(state);
+        inputs.push((input, stats));
+    }
+    Ok(inputs)
+}
+
+/// Runs the preflight of a block and also returns the state after the block.
+#[instrument(skip_all, fields(block_number = block_number, network = %chain_spec.name))]
+async fn preflight_carrying<BDP, L1, BP>(
+    provider: BDP,
+    block_number: u64,
+    chain_spec: ChainSpec,
+    prover_data: TaikoProverData,
+    provider_l1: Option<L1>,
+    blob_provider: Option<BP>,
+    mut options: PreflightOptions,
+) -> Result<(GuestInput, PreflightStats, CarriedState), PreflightError>
+where
+    BDP: BlockDataProvider,
+    L1: BlockDataProvider,
+    BP: BlobDataProvider,
+{
+    let mut stats = PreflightStats::default();
     let measurement = Measurement::start("Fetching block data...", true);
 
     // Get the block and the parent block
+    let stage_start = Instant::now();
+    let parent_number = block_number.checked_sub(1).ok_or_else(|| {
+        PreflightError::failed(
+            block_number,
+            PreflightStage::FetchBlock,
+            anyhow!("the genesis block has no parent"),
+        )
+    })?;
     let blocks = provider
-        .get_blocks(&vec![(block_number, true), (block_number - 1, false)])
-        .await?;
-    let (block, parent_block) = (&blocks[0], &blocks[1]);
+        .get_blocks(&vec![(block_number, true), (parent_number, false)])
+        .await
+        .at_stage(block_number, PreflightStage::FetchBlock)?;
+    stats.record_stage(PreflightStage::FetchBlock, stage_start);
+    let [block, parent_block] = blocks.as_slice() else {
+        return Err(PreflightError::failed(
+            block_number,
+            PreflightStage::FetchBlock,
+            anyhow!("the node returned {} blocks instead of 2", blocks.len()),
+        ));
+    };
+    let block_hash = header_field(block_number, "hash", block.header.hash)?;
+    // The number alone is ambiguous after a reorg
+    if let Some(expected) = options.block_hash {
+        let actual = block_hash;
+        if actual != expected {
+            return Err(PreflightError::BlockHashMismatch {
+                block_number,
+                stage: PreflightStage::FetchBlock,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    info!("block.hash: {:?}", block_hash);
+    info!("block.parent_hash: {:?}", block.header.parent_hash);
+    info!("block gas used: {:?}", block.header.gas_used);
+    info!("block transactions: {:?}", block.transactions.len());
 
-    println!("\nblock.hash: {:?}", block.header.hash.unwrap());
-    println!("block.parent_hash: {:?}", block.header.parent_hash);
-    println!("block gas used: {:?}", block.header.gas_used);
-    println!("block transactions: {:?}", block.transactions.len());
+    let mut input = GuestInput {
+        chain_spec: chain_spec.clone(),
+        block_number,
+        gas_used: header_u64(block_number, "gas used", block.header.gas_used)?,
+        block_hash_reference: block_hash,
+        block_header_reference: to_header(&block.header),
+        beneficiary: block.header.miner,
+        gas_limit: header_u64(block_number, "gas limit", block.header.gas_limit)?,
+        timestamp: header_u64(block_number, "timestamp", block.header.timestamp)?,
+        extra_data: block.header.extra_data.clone(),
+        mix_hash: header_field(block_number, "mix hash", block.header.mix_hash)?,
+        withdrawals: block.withdrawals.clone().unwrap_or_default(),
+        parent_state_trie: Default::default(),
+        parent_storage: Default::default(),
+        contracts: Default::default(),
+        parent_header: to_header(&parent_block.header),
+        ancestor_headers: Default::default(),
+        base_fee_per_gas: header_u64(
+            block_number,
+            "base fee",
+            header_field(block_number, "base fee", block.header.base_fee_per_gas)?,
+        )?,
+        blob_gas_used: block
+            .header
+            .blob_gas_used
+            .map(|gas| header_u64(block_number, "blob gas used", gas))
+            .transpose()?,
+        excess_blob_gas: block
+            .header
+            .excess_blob_gas
+            .map(|gas| header_u64(block_number, "excess blob gas", gas))
+            .transpose()?,
+        parent_beacon_block_root: block.header.parent_beacon_block_root,
+        taiko: Default::default(),
+    };
+    let mut provider_db = ProviderDb::new(provider, input.parent_header.clone());
+    if let Some(state) = options.carried_state.take() {
+        provider_db.stage_carried_state(state);
+    }
 
-    let taiko_guest_input = if chain_spec.is_taiko() {
-        let provider_l1 = RpcBlockDataProvider::new(&l1_rpc_url.clone().unwrap(), block_number);
+    let (mut builder, execution_iterations) = if chain_spec.is_taiko() {
+        let provider_l1 = provider_l1.ok_or(PreflightError::MissingL1Provider {
+            block_number,
+            stage: PreflightStage::FetchL1Blocks,
+        })?;
+        let protocol = chain_spec
+            .protocol_version(block_number)
+            .with_context(|| format!("no protocol version configured for {}", chain_spec.name))
+            .at_stage(block_number, PreflightStage::FetchBlock)?;
 
         // Decode the anchor tx to find out which L1 blocks we need to fetch
         let anchor_tx = match &block.transactions {
-            BlockTransactions::Full(txs) => txs[0].to_owned(),
-            _ => unreachable!(),
+            BlockTransactions::Full(txs) => txs.first().cloned().ok_or_else(|| {
+                PreflightError::failed(
+                    block_number,
+                    PreflightStage::FetchBlock,
+                    anyhow!("the block has no anchor transaction"),
+                )
+            })?,
+            _ => {
+                return Err(PreflightError::TransactionsNotFull {
+                    block_number,
+                    stage: PreflightStage::FetchBlock,
+                })
+            }
         };
-        let anchor_call = decode_anchor(anchor_tx.input.as_ref())?;
-        // The L1 blocks we need
-        let l1_state_block_number = anchor_call.l1BlockId;
-        let l1_inclusion_block_number = l1_state_block_number + 1;
-
-        println!("anchor L1 block id: {:?}", anchor_call.l1BlockId);
-        println!("anchor L1 state root: {:?}", anchor_call.l1StateRoot);
+        let anchor_call = protocol
+            .decode_anchor(anchor_tx.input.as_ref())
+            .at_stage(block_number, PreflightStage::FetchBlock)?;
+        info!("anchor L1 block id: {:?}", anchor_call.l1BlockId);
+        info!("anchor L1 state root: {:?}", anchor_call.l1StateRoot);
 
-        // Get the L1 block in which the L2 block was included so we can fetch the DA data.
-        // Also get the L1 state block header so that we can prove the L1 state root.
+        // Get the L1 state block header so that we can prove the L1 state root.
+        let stage_start = Instant::now();
         let l1_blocks = provider_l1
-            .get_blocks(&vec![
-                (l1_inclusion_block_number, false),
-                (l1_state_block_number, false),
-            ])
-            .await?;
-        let (l1_inclusion_block, l1_state_block) = (&l1_blocks[0], &l1_blocks[1]);
-
-        println!(
-            "l1_state_root_block hash: {:?}",
-            l1_state_block.header.hash.unwrap()
-        );
+            .get_blocks(&[(anchor_call.l1BlockId, false)])
+            .await
+            .at_stage(block_number, PreflightStage::FetchL1Blocks)?;
+        stats.record_stage(PreflightStage::FetchL1Blocks, stage_start);
+        let l1_state_block = l1_blocks.first().ok_or_else(|| {
+            PreflightError::failed(
+                block_number,
+                PreflightStage::FetchL1Blocks,
+                anyhow!("L1 block {} not found", anchor_call.l1BlockId),
+            )
+        })?;
 
-        // Get the block proposal data
-        let (proposal_tx, proposal_event) = get_block_proposed_event(
-            provider_l1.provider(),
-            chain_spec.clone(),
-            l1_inclusion_block.header.hash.unwrap(),
-            block_number,
-        )
-        .await?;
+        info!("l1_state_root_block hash: {:?}", l1_state_block.header.hash);
 
-        // Fetch the tx data from either calldata or blobdata
-        let (tx_data, tx_blob_hash) = if proposal_event.meta.blobUsed {
-            println!("blob active");
-            // Get the blob hashes attached to the propose tx
-            let blob_hashes = proposal_tx.blob_versioned_hashes.unwrap_or_default();
-            assert!(!blob_hashes.is_empty());
-            // Currently the protocol enforces the first blob hash to be used
-            let blob_hash = blob_hashes[0];
-            // Get the blob data for this block
-            let slot_id = block_time_to_block_slot(
-                l1_inclusion_block.header.timestamp,
-                chain_spec.genesis_time,
-                chain_spec.seconds_per_slot,
-            )?;
-            let blobs = get_blob_data(&beacon_rpc_url.clone().unwrap(), slot_id).await?;
-            assert!(!blobs.data.is_empty(), "blob data not available anymore");
-            // Get the blob data for the blob storing the tx list
-            let tx_blob = blobs
-                .data
-                .iter()
-                .find(|blob| {
-                    // calculate from plain blob
-                    blob_hash == calc_blob_versioned_hash(&blob.blob)
-                })
-                .cloned();
-            assert!(tx_blob.is_some());
-            (blob_to_bytes(&tx_blob.unwrap().blob), Some(blob_hash))
-        } else {
-            // Get the tx list data directly from the propose transaction data
-            let proposal_call = proposeBlockCall::abi_decode(&proposal_tx.input, false).unwrap();
-            (proposal_call.txList.as_ref().to_owned(), None)
+        // The tx list has to come from L1, but the node already told us which transactions
+        // made it into the block. Discover the state they touch while the proposal and the
+        // blob are fetched, and check the result against the real tx list afterwards.
+        let taiko_input = TaikoGuestInput {
+            l1_header: to_header(&l1_state_block.header),
+            anchor_tx: serde_json::to_string(&anchor_tx)
+                .at_stage(block_number, PreflightStage::DecodeTransactions)?,
+            prover_data,
+            ..Default::default()
         };
+        let node_transactions = get_transactions_from_block(block_number, block)?;
+        let discovery_input = GuestInput {
+            taiko: TaikoGuestInput {
+                tx_data: zlib_compress_data(&alloy_rlp::encode(&node_transactions[1..]))
+                    .at_stage(block_number, PreflightStage::DecodeTransactions)?,
+                ..taiko_input.clone()
+            },
+            ..input.clone()
+        };
+        let (taiko_input, discovery) = tokio::join!(
+            fetch_taiko_input(
+                &provider_l1,
+                blob_provider.as_ref(),
+                &chain_spec,
+                protocol,
+                block_number,
+                &options,
+                taiko_input,
+            ),
+            async {
+                let stage_start = Instant::now();
+                let result = discover_state(&discovery_input, provider_db).await;
+                (result, stage_start)
+            },
+        );
+        let (taiko_input, l1_stats) = taiko_input?;
+        let (discovery, discovery_start) = discovery;
+        let (mut discovery_builder, discovery_iterations) = discovery?;
+        stats.record_stage(PreflightStage::Execution, discovery_start);
+        for (stage, millis) in l1_stats.stage_millis {
+            *stats.stage_millis.entry(stage).or_default() += millis;
+        }
+        stats.l1_rpc_calls = provider_l1.rpc_calls();
+        measurement.stop();
 
-        // Create the transactions from the proposed tx list
+        // Do a sanity check using the transactions returned by the node
+        let stage_start = Instant::now();
         let transactions = generate_transactions(
-            proposal_event.meta.blobUsed,
-            &tx_data,
+            taiko_input.block_proposed.meta.blobUsed,
+            &taiko_input.tx_data,
+            &taiko_input.blob_tx_list,
             Some(anchor_tx.clone()),
         );
-        // Do a sanity check using the transactions returned by the node
-        assert!(
-            transactions.len() >= block.transactions.len(),
-            "unexpected number of transactions"
-        );
-
-        // Create the input struct without the block data set
-        TaikoGuestInput {
-            l1_header: to_header(&l1_state_block.header),
-            tx_data,
-            anchor_tx: serde_json::to_string(&anchor_tx).unwrap(),
-            tx_blob_hash,
-            block_proposed: proposal_event,
-            prover_data,
-            skip_verify_blob: false,
+        if transactions.len() < block.transactions.len() {
+            return Err(PreflightError::TransactionCountMismatch {
+                block_number,
+                stage: PreflightStage::DecodeTransactions,
+                decoded: transactions.len(),
+                expected: block.transactions.len(),
+            });
         }
+        stats.record_stage(PreflightStage::DecodeTransactions, stage_start);
+
+        // Run the block with the tx list from L1, which normally finds everything it needs
+        // in the state discovered above.
+        input.taiko = taiko_input;
+        let mut provider_db = discovery_builder.take_db().unwrap();
+        provider_db.current_db = Default::default();
+        let stage_start = Instant::now();
+        let (builder, iterations) =
+            execute_block::<TaikoHeaderPrepStrategy, _>(&input, provider_db).await?;
+        stats.record_stage(PreflightStage::Execution, stage_start);
+        (builder, discovery_iterations + iterations)
     } else {
         // For Ethereum blocks we just convert the block transactions in a tx_list
         // so that we don't have to supports separate paths.
-        TaikoGuestInput {
-            tx_data: zlib_compress_data(&alloy_rlp::encode(&get_transactions_from_block(&block)))?,
+        let stage_start = Instant::now();
+        let transactions = get_transactions_from_block(block_number, block)?;
+        input.taiko = TaikoGuestInput {
+            tx_data: zlib_compress_data(&alloy_rlp::encode(&transactions))
+                .at_stage(block_number, PreflightStage::DecodeTransactions)?,
             ..Default::default()
-        }
-    };
-    measurement.stop();
+        };
+        stats.record_stage(PreflightStage::DecodeTransactions, stage_start);
+        measurement.stop();
 
-    let input = GuestInput {
-        chain_spec: chain_spec.clone(),
-        block_number,
-        gas_used: block.header.gas_used.try_into().unwrap(),
-        block_hash_reference: block.header.hash.unwrap(),
-        block_header_reference: to_header(&block.header),
-        beneficiary: block.header.miner,
-        gas_limit: block.header.gas_limit.try_into().unwrap(),
-        timestamp: block.header.timestamp.try_into().unwrap(),
-        extra_data: block.header.extra_data.clone(),
-        mix_hash: block.header.mix_hash.unwrap(),
-        withdrawals: block.withdrawals.clone().unwrap_or_default(),
-        parent_state_trie: Default::default(),
-        parent_storage: Default::default(),
-        contracts: Default::default(),
-        parent_header: to_header(&parent_block.header),
-        ancestor_headers: Default::default(),
-        base_fee_per_gas: block.header.base_fee_per_gas.unwrap().try_into().unwrap(),
-        blob_gas_used: block.header.blob_gas_used.map(|b| b.try_into().unwrap()),
-        excess_blob_gas: block.header.excess_blob_gas.map(|b| b.try_into().unwrap()),
-        parent_beacon_block_root: block.header.parent_beacon_block_root,
-        taiko: taiko_guest_input,
+        let stage_start = Instant::now();
+        provider_db
+            .stage_touched_state(block_number)
+            .await
+            .at_stage(block_number, PreflightStage::Execution)?;
+        let result = execute_block::<TaikoHeaderPrepStrategy, _>(&input, provider_db).await?;
+        stats.record_stage(PreflightStage::Execution, stage_start);
+        result
     };
-
-    // Create the block builder, run the transactions and extract the DB
-    let provider_db = ProviderDb::new(
-        provider,
-        chain_spec,
-        parent_block.header.number.unwrap().try_into().unwrap(),
-    )
-    .await?;
-
-    let mut builder = BlockBuilder::new(&input)
-        .with_db(provider_db)
-        .prepare_header::<TaikoHeaderPrepStrategy>()?;
-
-    // Optimize data gathering by executing the transactions multiple times so data can be requested in batches
-    let is_local = false;
-    let max_iterations = if is_local { 1 } else { 50 };
-    let mut done = false;
-    let mut num_iterations = 0;
-    while !done {
-        println!("Execution iteration {num_iterations}...");
-        builder.mut_db().unwrap().optimistic = num_iterations + 1 < max_iterations;
-        builder = builder.execute_transactions::<TkoTxExecStrategy>()?;
-        if builder.mut_db().unwrap().fetch_data().await {
-            done = true;
-        }
-        num_iterations += 1;
-    }
-    builder = builder.prepare_header::<TaikoHeaderPrepStrategy>()?;
     let provider_db = builder.mut_db().unwrap();
+    stats.execution_iterations = execution_iterations;
+    stats.accounts_fetched = provider_db.initial_db.accounts.len();
+    stats.storage_slots_fetched = provider_db
+        .initial_db
+        .storage_keys()
+        .values()
+        .map(|keys| keys.len())
+        .sum();
 
     // Gather inclusion proofs for the initial and final state
+    let stage_start = Instant::now();
     let measurement = Measurement::start("Fetching storage proofs...", true);
-    let (parent_proofs, proofs, num_storage_proofs) = provider_db.get_proofs().await?;
+    let (parent_proofs, proofs, num_storage_proofs) = provider_db
+        .get_proofs()
+        .await
+        .at_stage(block_number, PreflightStage::StateProofs)?;
     measurement.stop_with_count(&format!(
         "[{} Account/{num_storage_proofs} Storage]",
         parent_proofs.len() + proofs.len(),
     ));
+    stats.account_proofs_fetched = parent_proofs.len() + proofs.len();
+    stats.storage_proofs_fetched = num_storage_proofs;
 
     // Construct the state trie and storage from the storage proofs.
     let measurement = Measurement::start("Constructing MPT...", true);
     let (state_trie, storage) =
-        proofs_to_tries(input.parent_header.state_root, parent_proofs, proofs)?;
+        proofs_to_tries(input.parent_header.state_root, parent_proofs, proofs)
+            .at_stage(block_number, PreflightStage::StateProofs)?;
     measurement.stop();
+    stats.record_stage(PreflightStage::StateProofs, stage_start);
 
     // Gather proofs for block history
+    let stage_start = Instant::now();
     let measurement = Measurement::start("Fetching historical block headers...", true);
-    let ancestor_headers = provider_db.get_ancestor_headers().await?;
+    let ancestor_headers = provider_db
+        .get_ancestor_headers()
+        .await
+        .at_stage(block_number, PreflightStage::AncestorHeaders)?;
     measurement.stop();
+    stats.record_stage(PreflightStage::AncestorHeaders, stage_start);
+    stats.ancestor_headers = ancestor_headers.len();
 
     // Get the contracts from the initial db.
     let measurement = Measurement::start("Fetching contract code...", true);
@@ -239,17 +418,272 @@ pub async fn preflight<BDP: BlockDataProvider>(
         }
     }
     measurement.stop();
+    stats.rpc_calls = provider_db.provider.rpc_calls();
+    let carried_state = provider_db.carried_state(input.block_header_reference.clone());
 
     // Add the collected data to the input
-    Ok(GuestInput {
+    let input = GuestInput {
         parent_state_trie: state_trie,
         parent_storage: storage,
         contracts: contracts.into_iter().map(Bytes).collect(),
         ancestor_headers,
         ..input
+    };
+    stats
+        .record_input_sizes(&input)
+        .at_stage(block_number, PreflightStage::StateProofs)?;
+    Ok((input, stats, carried_state))
+}
+
+/// Returns a header field of the block that the node left out as an error.
+fn header_field<T>(block_number: u64, field: &str, value: Option<T>) -> Result<T, PreflightError> {
+    value.ok_or_else(|| {
+        PreflightError::failed(
+            block_number,
+            PreflightStage::FetchBlock,
+            anyhow!("the node returned the block without its {field}"),
+        )
     })
 }
 
+/// Converts a numeric header field of the block to the `u64` used in the guest input.
+fn header_u64<T: TryInto<u64>>(
+    block_number: u64,
+    field: &str,
+    value: T,
+) -> Result<u64, PreflightError> {
+    value.try_into().map_err(|_| {
+        PreflightError::failed(
+            block_number,
+            PreflightStage::FetchBlock,
+            anyhow!("the {field} of the block doesn't fit in 64 bits"),
+        )
+    })
+}
+
+/// Looks up the proposal of a Taiko block on L1 and fetches its tx list, filling in the
+/// rest of `taiko_input`, which already has the anchored L1 header.
+async fn fetch_taiko_input<L1: BlockDataProvider, BP: BlobDataProvider>(
+    provider_l1: &L1,
+    blob_provider: Option<&BP>,
+    chain_spec: &ChainSpec,
+    protocol: ProtocolVersion,
+    block_number: u64,
+    options: &PreflightOptions,
+    taiko_input: TaikoGuestInput,
+) -> Result<(TaikoGuestInput, PreflightStats), PreflightError> {
+    let mut stats = PreflightStats::default();
+
+    // Get the block proposal data
+    let stage_start = Instant::now();
+    let (proposal_tx, proposal_event, blob_tx_list, l1_inclusion_block_number) =
+        get_block_proposed_event(
+            provider_l1,
+            chain_spec,
+            protocol,
+            block_number,
+            &taiko_input.l1_header,
+            options.proposal_search_range,
+        )
+        .await?;
+    if let Some(expected) = options.proposal_tx_hash {
+        if proposal_tx.hash != expected {
+            return Err(PreflightError::InvalidProposal {
+                block_number,
+                stage: PreflightStage::ProposalEvent,
+                l1_block_number: l1_inclusion_block_number,
+                reason: format!(
+                    "the block was proposed by {}, not by the requested {expected}",
+                    proposal_tx.hash
+                ),
+            });
+        }
+    }
+    stats.record_stage(PreflightStage::ProposalEvent, stage_start);
+
+    // Fetch the tx data from either calldata or blobdata
+    let stage_start = Instant::now();
+    let mut taiko_input = TaikoGuestInput {
+        block_proposed: proposal_event,
+        blob_tx_list,
+        ..taiko_input
+    };
+    if taiko_input.block_proposed.meta.blobUsed {
+        debug!("blob active");
+        // The metadata has the hash of the blob holding the tx list, find it among the blobs
+        // attached to the propose tx. A tx list spanning several blobs can't be committed to by
+        // the metadata, so only a single blob is supported.
+        let blob_hashes = proposal_tx.blob_versioned_hashes.unwrap_or_default();
+        if blob_hashes.is_empty() {
+            return Err(PreflightError::NoBlobHashes {
+                block_number,
+                stage: PreflightStage::BlobData,
+                tx_hash: proposal_tx.hash,
+            });
+        }
+        let blob_hash = taiko_input.block_proposed.meta.blobHash;
+        if !blob_hashes.contains(&blob_hash) {
+            return Err(PreflightError::failed(
+                block_number,
+                PreflightStage::BlobData,
+                anyhow!(
+                    "the proposal transaction {} has no blob {blob_hash}",
+                    proposal_tx.hash
+                ),
+            ));
+        }
+
+        // Get the blob data for this block from the slot of the L1 block the proposal is in
+        let l1_inclusion_block = provider_l1
+            .get_blocks(&[(l1_inclusion_block_number, false)])
+            .await
+            .at_stage(block_number, PreflightStage::BlobData)?;
+        let slot_id = block_time_to_block_slot(
+            l1_inclusion_block[0].header.timestamp,
+            chain_spec.genesis_time,
+            chain_spec.seconds_per_slot,
+        )
+        .at_stage(block_number, PreflightStage::BlobData)?;
+        let blob_provider = blob_provider.ok_or(PreflightError::MissingBlobProvider {
+            block_number,
+            stage: PreflightStage::BlobData,
+        })?;
+        let blob = blob_provider
+            .get_blob(slot_id, blob_hash)
+            .await
+            .map_err(|source| PreflightError::BlobUnavailable {
+                block_number,
+                stage: PreflightStage::BlobData,
+                blob_hash,
+                source,
+            })?;
+        stats.record_stage(PreflightStage::BlobData, stage_start);
+
+        taiko_input.tx_data = blob.blob;
+        taiko_input.tx_blob_hash = Some(blob_hash);
+        taiko_input.blob_commitment = Some(blob.commitment);
+        taiko_input.blob_proof = Some(blob.proof);
+    } else {
+        // Get the tx list data directly from the propose transaction data
+        taiko_input.tx_data = protocol
+            .decode_calldata_tx_list(&proposal_tx.input)
+            .at_stage(block_number, PreflightStage::ProposalEvent)?;
+    }
+
+    Ok((taiko_input, stats))
+}
+
+/// Finds the state touched by a block that was built from the transactions reported by the
+/// node, and prefetches the proofs for it. The input has no block proposal yet, so the
+/// header isn't validated.
+async fn discover_state<BDP: BlockDataProvider>(
+    input: &GuestInput,
+    mut provider_db: ProviderDb<BDP>,
+) -> Result<(BlockBuilder<ProviderDb<BDP>>, usize), PreflightError> {
+    provider_db
+        .stage_touched_state(input.block_number)
+        .await
+        .at_stage(input.block_number, PreflightStage::Execution)?;
+    let (mut builder, num_iterations) =
+        execute_block::<UncheckedHeaderPrepStrategy, _>(input, provider_db).await?;
+    builder
+        .mut_db()
+        .unwrap()
+        .prefetch_proofs()
+        .await
+        .at_stage(input.block_number, PreflightStage::StateProofs)?;
+    Ok((builder, num_iterations))
+}
+
+/// Executes the block until the database has all the state it reads, preparing the header
+/// with `H`. Returns the builder and the number of times the block was executed.
+async fn execute_block<H: HeaderPrepStrategy, BDP: BlockDataProvider>(
+    input: &GuestInput,
+    provider_db: ProviderDb<BDP>,
+) -> Result<(BlockBuilder<ProviderDb<BDP>>, usize), PreflightError> {
+    let block_number = input.block_number;
+    let mut builder = BlockBuilder::new(input)
+        .with_db(provider_db)
+        .prepare_header::<H>()
+        .at_stage(block_number, PreflightStage::Execution)?;
+
+    // Optimize data gathering by executing the transactions multiple times so data can be requested in batches.
+    // This also picks up anything that wasn't staged up front.
+    let is_local = false;
+    let max_iterations = if is_local { 1 } else { 50 };
+    let mut done = false;
+    let mut num_iterations = 0;
+    while !done {
+        info!("Execution iteration {num_iterations}...");
+        builder.mut_db().unwrap().optimistic = num_iterations + 1 < max_iterations;
+        builder = builder
+            .execute_transactions::<TkoTxExecStrategy>()
+            .at_stage(block_number, PreflightStage::Execution)?;
+        if builder
+            .mut_db()
+            .unwrap()
+            .fetch_data()
+            .await
+            .at_stage(block_number, PreflightStage::Execution)?
+        {
+            done = true;
+        }
+        num_iterations += 1;
+    }
+    builder = builder
+        .prepare_header::<H>()
+        .at_stage(block_number, PreflightStage::Execution)?;
+    Ok((builder, num_iterations))
+}
+
+/// Where preflight spent its time and how much data it gathered for a block.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PreflightStats {
+    /// The number of times the block was executed to discover the state it touches.
+    pub execution_iterations: usize,
+    /// Accounts and storage slots read by the block.
+    pub accounts_fetched: usize,
+    pub storage_slots_fetched: usize,
+    /// Account and storage proofs fetched for the parent and the new state.
+    pub account_proofs_fetched: usize,
+    pub storage_proofs_fetched: usize,
+    /// Serialized sizes of the state passed to the guest.
+    pub parent_state_trie_bytes: u64,
+    pub parent_storage_bytes: u64,
+    pub contracts_bytes: u64,
+    /// The number of headers included for `BLOCKHASH`.
+    pub ancestor_headers: usize,
+    /// JSON-RPC calls sent to the L2 and the L1 node, retries included.
+    pub rpc_calls: u64,
+    pub l1_rpc_calls: u64,
+    /// Time spent in each stage in milliseconds.
+    pub stage_millis: BTreeMap<PreflightStage, u64>,
+}
+
+impl PreflightStats {
+    fn record_stage(&mut self, stage: PreflightStage, start: Instant) {
+        *self.stage_millis.entry(stage).or_default() += start.elapsed().as_millis() as u64;
+    }
+
+    fn record_input_sizes(&mut self, input: &GuestInput) -> Result<()> {
+        self.parent_state_trie_bytes = bincode::serialized_size(&input.parent_state_trie)?;
+        self.parent_storage_bytes = bincode::serialized_size(&input.parent_storage)?;
+        self.contracts_bytes = input.contracts.iter().map(|code| code.len() as u64).sum();
+        Ok(())
+    }
+}
+
+/// Attaches the block and the preflight stage to errors from the providers and the builder.
+trait AtStage<T> {
+    fn at_stage(self, block_number: u64, stage: PreflightStage) -> Result<T, PreflightError>;
+}
+
+impl<T, E: Into<anyhow::Error>> AtStage<T> for Result<T, E> {
+    fn at_stage(self, block_number: u64, stage: PreflightStage) -> Result<T, PreflightError> {
+        self.map_err(|e| PreflightError::failed(block_number, stage, e.into()))
+    }
+}
+
 // block_time_to_block_slot returns the slots of the given timestamp.
 fn block_time_to_block_slot(
     block_time: u64,
@@ -265,170 +699,214 @@ fn block_time_to_block_slot(
     }
 }
 
-fn blob_to_bytes(blob_str: &str) -> Vec<u8> {
-    match hex::decode(blob_str.to_lowercase().trim_start_matches("0x")) {
-        Ok(b) => b,
-        Err(_) => Vec::new(),
-    }
-}
-
-fn calc_blob_versioned_hash(blob_str: &str) -> [u8; 32] {
-    let blob_bytes: Vec<u8> =
-        hex::decode(blob_str.to_lowercase().trim_start_matches("0x")).unwrap();
-    let kzg_settings = Arc::clone(&*MAINNET_KZG_TRUSTED_SETUP);
-    let blob = Blob::from_bytes(&blob_bytes).unwrap();
-    let kzg_commit = KzgCommitment::blob_to_kzg_commitment(&blob, &kzg_settings).unwrap();
-    let version_hash: [u8; 32] = kzg_to_versioned_hash(kzg_commit).0;
-    version_hash
-}
+/// Returns the number of the L2 block proposed by an L1 transaction.
+pub async fn get_proposed_block_number<BDP: BlockDataProvider>(
+    provider_l1: &BDP,
+    chain_spec: &ChainSpec,
+    proposal_tx_hash: B256,
+) -> Result<u64> {
+    let l1_address = chain_spec
+        .l1_contract
+        .with_context(|| format!("no L1 contract configured for {}", chain_spec.name))?;
+    let tx = provider_l1
+        .get_transaction_by_hash(proposal_tx_hash)
+        .await?;
+    let l1_block_hash = tx
+        .block_hash
+        .with_context(|| format!("the proposal transaction {proposal_tx_hash} is pending"))?;
 
-async fn get_blob_data(beacon_rpc_url: &str, block_id: u64) -> Result<GetBlobsResponse> {
-    let url = format!(
-        "{}/eth/v1/beacon/blob_sidecars/{block_id}",
-        beacon_rpc_url.trim_end_matches('/'),
-    );
-    let response = reqwest::get(url.clone()).await?;
-    if response.status().is_success() {
-        let blob_response: GetBlobsResponse = response.json().await?;
-        Ok(blob_response)
-    } else {
-        println!(
-            "Request {url} failed with status code: {}",
-            response.status()
-        );
-        Err(anyhow::anyhow!(
-            "Request failed with status code: {}",
-            response.status()
-        ))
+    // The block can be proposed with any of the protocol versions of the chain
+    let filter = Filter::new()
+        .address(l1_address)
+        .at_block_hash(l1_block_hash);
+    let mut block_ids = Vec::new();
+    for log in provider_l1.get_logs(&filter).await? {
+        if log.removed || log.transaction_hash != Some(proposal_tx_hash) {
+            continue;
+        }
+        let Some(protocol) = chain_spec
+            .protocol_versions
+            .values()
+            .find(|protocol| log.topics().first() == Some(&protocol.block_proposed_signature()))
+        else {
+            continue;
+        };
+        let log_data = Log::new(
+            log.address(),
+            log.topics().to_vec(),
+            log.data().data.clone(),
+        )
+        .context("invalid BlockProposed log")?;
+        let (event, _) = protocol.decode_block_proposed(&log_data)?;
+        block_ids.push(event.blockId);
     }
-}
-
-// Blob data from the beacon chain
-// type Sidecar struct {
-// Index                    string                   `json:"index"`
-// Blob                     string                   `json:"blob"`
-// SignedBeaconBlockHeader  *SignedBeaconBlockHeader `json:"signed_block_header"`
-// KzgCommitment            string                   `json:"kzg_commitment"`
-// KzgProof                 string                   `json:"kzg_proof"`
-// CommitmentInclusionProof []string
-// `json:"kzg_commitment_inclusion_proof"` }
-#[derive(Clone, Debug, Deserialize, Serialize)]
-struct GetBlobData {
-    pub index: String,
-    pub blob: String,
-    // pub signed_block_header: SignedBeaconBlockHeader, // ignore for now
-    pub kzg_commitment: String,
-    pub kzg_proof: String,
-    pub kzg_commitment_inclusion_proof: Vec<String>,
-}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-struct GetBlobsResponse {
-    pub data: Vec<GetBlobData>,
+    match block_ids[..] {
+        [block_id] => block_id
+            .try_into()
+            .map_err(|_| anyhow!("invalid block id {block_id}")),
+        [] => Err(anyhow!(
+            "the transaction {proposal_tx_hash} did not propose a block"
+        )),
+        _ => Err(anyhow!(
+            "the transaction {proposal_tx_hash} proposed blocks {block_ids:?}, pick one with the block number"
+        )),
+    }
 }
 
-async fn get_block_proposed_event(
-    provider: &ReqwestProvider,
-    chain_spec: ChainSpec,
-    block_hash: B256,
+/// Finds the `BlockProposed` event of an L2 block in the `search_range` L1 blocks after the
+/// anchored L1 block, and checks that the proposal was made on top of that block.
+async fn get_block_proposed_event<BDP: BlockDataProvider>(
+    provider: &BDP,
+    chain_spec: &ChainSpec,
+    protocol: ProtocolVersion,
     l2_block_number: u64,
-) -> Result<(AlloyRpcTransaction, BlockProposed)> {
-    // Get the address that emited the event
-    let l1_address = chain_spec.l1_contract.unwrap();
+    anchor_header: &AlloyConsensusHeader,
+    search_range: u64,
+) -> Result<(AlloyRpcTransaction, BlockProposed, BlobTxListLocation, u64), PreflightError> {
+    let stage = PreflightStage::ProposalEvent;
 
-    let network = chain_spec.network().unwrap();
+    // Get the address that emited the event
+    let l1_address = chain_spec
+        .l1_contract
+        .with_context(|| format!("no L1 contract configured for {}", chain_spec.name))
+        .at_stage(l2_block_number, stage)?;
 
-    // Get the event signature (value can differ between chains)
-    let event_signature = if network == Network::TaikoA6 {
-        TestnetBlockProposed::SIGNATURE_HASH
-    } else {
-        BlockProposed::SIGNATURE_HASH
-    };
-    // Setup the filter to get the relevant events
+    // Get the event signature (value can differ between protocol versions)
+    let event_signature = protocol.block_proposed_signature();
+    // The block can't be proposed before the L1 block it is anchored to
+    let from_block = anchor_header.number + 1;
+    let to_block = anchor_header.number + search_range;
+    // Setup the filter to get the events for this L2 block, `blockId` is the first topic
     let filter = Filter::new()
         .address(l1_address)
-        .at_block_hash(block_hash)
-        .event_signature(event_signature);
+        .event_signature(event_signature)
+        .topic1(B256::from(U256::from(l2_block_number)))
+        .from_block(from_block)
+        .to_block(to_block);
     // Now fetch the events
-    let logs = provider.get_logs(&filter).await?;
-
-    // Run over the logs returned to find the matching event for the specified L2 block number
-    // (there can be multiple blocks proposed in the same block and even same tx)
-    for log in logs {
-        if network == Network::TaikoA6 {
-            let event = TestnetBlockProposed::decode_log(
-                &Log::new(
-                    log.address(),
-                    log.topics().to_vec(),
-                    log.data().data.clone(),
-                )
-                .unwrap(),
-                false,
-            )
-            .unwrap();
-            if event.blockId == raiko_primitives::U256::from(l2_block_number) {
-                let tx = provider
-                    .get_transaction_by_hash(log.transaction_hash.unwrap())
-                    .await
-                    .expect("could not find the propose tx");
-                return Ok((tx, event.data.into()));
-            }
-        } else {
-            let event = BlockProposed::decode_log(
-                &Log::new(
-                    log.address(),
-                    log.topics().to_vec(),
-                    log.data().data.clone(),
-                )
-                .unwrap(),
-                false,
-            )
-            .unwrap();
-            if event.blockId == raiko_primitives::U256::from(l2_block_number) {
-                let tx = provider
-                    .get_transaction_by_hash(log.transaction_hash.unwrap())
-                    .await
-                    .expect("could not find the propose tx");
-                return Ok((tx, event.data));
-            }
+    let logs = provider
+        .get_logs(&filter)
+        .await
+        .at_stage(l2_block_number, stage)?;
+
+    // Skip logs that were reorged out, and any the node returned for other blocks
+    for log in logs.into_iter().filter(|log| !log.removed) {
+        let Some(log_data) = Log::new(
+            log.address(),
+            log.topics().to_vec(),
+            log.data().data.clone(),
+        ) else {
+            return Err(PreflightError::failed(
+                l2_block_number,
+                stage,
+                anyhow!(
+                    "invalid BlockProposed log in L1 block {:?}",
+                    log.block_number
+                ),
+            ));
+        };
+        let (event, blob_tx_list) = protocol
+            .decode_block_proposed(&log_data)
+            .at_stage(l2_block_number, stage)?;
+        if event.blockId != U256::from(l2_block_number) {
+            continue;
+        }
+
+        // The proposal has to build on the L1 block the anchor transaction commits to
+        let l1_block_number: u64 = log
+            .block_number
+            .and_then(|number| number.try_into().ok())
+            .context("the BlockProposed log has no block number")
+            .at_stage(l2_block_number, stage)?;
+        if event.meta.l1Height != anchor_header.number || event.meta.l1Hash != anchor_header.hash()
+        {
+            return Err(PreflightError::InvalidProposal {
+                block_number: l2_block_number,
+                stage,
+                l1_block_number,
+                reason: format!(
+                    "the proposal is based on L1 block {} ({}) but the anchor is L1 block {} ({})",
+                    event.meta.l1Height,
+                    event.meta.l1Hash,
+                    anchor_header.number,
+                    anchor_header.hash()
+                ),
+            });
         }
+
+        let tx_hash = log
+            .transaction_hash
+            .context("the BlockProposed log has no transaction hash")
+            .at_stage(l2_block_number, stage)?;
+        let tx = provider
+            .get_transaction_by_hash(tx_hash)
+            .await
+            .with_context(|| format!("could not find the propose tx {tx_hash}"))
+            .at_stage(l2_block_number, stage)?;
+        info!("Found the proposal of block {l2_block_number} in L1 block {l1_block_number}");
+        return Ok((tx, event, blob_tx_list, l1_block_number));
     }
-    bail!("No BlockProposed event found for block {l2_block_number}");
+    Err(PreflightError::ProposalNotFound {
+        block_number: l2_block_number,
+        stage,
+        from_block,
+        to_block,
+    })
 }
 
-fn get_transactions_from_block(block: &Block) -> Vec<TxEnvelope> {
+fn get_transactions_from_block(
+    block_number: u64,
+    block: &Block,
+) -> Result<Vec<TxEnvelope>, PreflightError> {
     let mut transactions: Vec<TxEnvelope> = Vec::new();
     if !block.transactions.is_empty() {
         match &block.transactions {
             BlockTransactions::Full(txs) => {
                 for tx in txs {
-                    transactions.push(from_block_tx(tx));
+                    transactions.push(from_block_tx(tx).map_err(|reason| {
+                        PreflightError::InvalidTransaction {
+                            block_number,
+                            stage: PreflightStage::DecodeTransactions,
+                            tx_hash: tx.hash,
+                            reason,
+                        }
+                    })?);
                 }
-            },
-            _ => unreachable!("Block is too old, please connect to an archive node or use a block that is at most 128 blocks old."),
+            }
+            // Happens when the node can't serve the block anymore, please connect to an
+            // archive node or use a block that is at most 128 blocks old.
+            _ => {
+                return Err(PreflightError::TransactionsNotFull {
+                    block_number,
+                    stage: PreflightStage::DecodeTransactions,
+                })
+            }
         };
-        assert!(
-            transactions.len() == block.transactions.len(),
-            "unexpected number of transactions"
-        );
     }
-    transactions
+    Ok(transactions)
 }
 
-fn from_block_tx(tx: &AlloyRpcTransaction) -> TxEnvelope {
-    let signature = Signature::from_rs_and_parity(
-        tx.signature.unwrap().r,
-        tx.signature.unwrap().s,
-        tx.signature.unwrap().v.as_limbs()[0],
-    )
-    .unwrap();
-    match tx.transaction_type.unwrap_or_default() {
+/// Reads a field the transaction type requires, converted to the type of the consensus field.
+fn required<T, U: TryInto<T>>(value: Option<U>, name: &str) -> Result<T, String> {
+    value
+        .ok_or_else(|| format!("missing {name}"))?
+        .try_into()
+        .map_err(|_| format!("{name} is out of range"))
+}
+
+fn from_block_tx(tx: &AlloyRpcTransaction) -> Result<TxEnvelope, String> {
+    let signature = tx.signature.ok_or("missing signature")?;
+    let signature =
+        Signature::from_rs_and_parity(signature.r, signature.s, signature.v.as_limbs()[0])
+            .map_err(|e| format!("invalid signature: {e}"))?;
+    let tx = match tx.transaction_type.unwrap_or_default() {
         0 => TxEnvelope::Legacy(
             TxLegacy {
                 chain_id: tx.chain_id,
                 nonce: tx.nonce,
-                gas_price: tx.gas_price.unwrap().try_into().unwrap(),
-                gas_limit: tx.gas.try_into().unwrap(),
+                gas_price: required(tx.gas_price, "gasPrice")?,
+                gas_limit: required(Some(tx.gas), "gas")?,
                 to: if tx.to.is_none() {
                     TxKind::Create
                 } else {
@@ -441,10 +919,10 @@ fn from_block_tx(tx: &AlloyRpcTransaction) -> TxEnvelope {
         ),
         1 => TxEnvelope::Eip2930(
             TxEip2930 {
-                chain_id: tx.chain_id.unwrap(),
+                chain_id: required(tx.chain_id, "chainId")?,
                 nonce: tx.nonce,
-                gas_price: tx.gas_price.unwrap().try_into().unwrap(),
-                gas_limit: tx.gas.try_into().unwrap(),
+                gas_price: required(tx.gas_price, "gasPrice")?,
+                gas_limit: required(Some(tx.gas), "gas")?,
                 to: if tx.to.is_none() {
                     TxKind::Create
                 } else {
@@ -458,11 +936,14 @@ fn from_block_tx(tx: &AlloyRpcTransaction) -> TxEnvelope {
         ),
         2 => TxEnvelope::Eip1559(
             TxEip1559 {
-                chain_id: tx.chain_id.unwrap(),
+                chain_id: required(tx.chain_id, "chainId")?,
                 nonce: tx.nonce,
-                gas_limit: tx.gas.try_into().unwrap(),
-                max_fee_per_gas: tx.max_fee_per_gas.unwrap().try_into().unwrap(),
-                max_priority_fee_per_gas: tx.max_priority_fee_per_gas.unwrap().try_into().unwrap(),
+                gas_limit: required(Some(tx.gas), "gas")?,
+                max_fee_per_gas: required(tx.max_fee_per_gas, "maxFeePerGas")?,
+                max_priority_fee_per_gas: required(
+                    tx.max_priority_fee_per_gas,
+                    "maxPriorityFeePerGas",
+                )?,
                 to: if tx.to.is_none() {
                     TxKind::Create
                 } else {
@@ -476,31 +957,44 @@ fn from_block_tx(tx: &AlloyRpcTransaction) -> TxEnvelope {
         ),
         3 => TxEnvelope::Eip4844(
             TxEip4844Variant::TxEip4844(TxEip4844 {
-                chain_id: tx.chain_id.unwrap(),
+                chain_id: required(tx.chain_id, "chainId")?,
                 nonce: tx.nonce,
-                gas_limit: tx.gas.try_into().unwrap(),
-                max_fee_per_gas: tx.max_fee_per_gas.unwrap().try_into().unwrap(),
-                max_priority_fee_per_gas: tx.max_priority_fee_per_gas.unwrap().try_into().unwrap(),
-                to: tx.to.unwrap(),
+                gas_limit: required(Some(tx.gas), "gas")?,
+                max_fee_per_gas: required(tx.max_fee_per_gas, "maxFeePerGas")?,
+                max_priority_fee_per_gas: required(
+                    tx.max_priority_fee_per_gas,
+                    "maxPriorityFeePerGas",
+                )?,
+                to: required(tx.to, "to")?,
                 value: tx.value,
                 access_list: tx.access_list.clone().unwrap_or_default(),
                 input: tx.input.clone(),
                 blob_versioned_hashes: tx.blob_versioned_hashes.clone().unwrap_or_default(),
-                max_fee_per_blob_gas: tx.max_fee_per_blob_gas.unwrap().try_into().unwrap(),
+                max_fee_per_blob_gas: required(tx.max_fee_per_blob_gas, "maxFeePerBlobGas")?,
             })
             .into_signed(signature),
         ),
-        _ => unimplemented!(),
-    }
+        tx_type => return Err(format!("unsupported transaction type {tx_type}")),
+    };
+    Ok(tx)
 }
 
 #[cfg(test)]
 mod test {
+    use c_kzg::KzgCommitment;
     use ethers_core::types::Transaction;
-    use raiko_lib::{consts::get_network_spec, utils::decode_transactions};
-    use raiko_primitives::{eip4844::parse_kzg_trusted_setup, kzg::KzgSettings};
+    use raiko_lib::{
+        consts::{TAIKO_A6_CHAIN_SPEC, TAIKO_A7_CHAIN_SPEC},
+        utils::decode_transactions,
+    };
+    use raiko_primitives::{
+        eip4844::{kzg_to_versioned_hash, parse_kzg_trusted_setup, MAINNET_KZG_TRUSTED_SETUP},
+        kzg::KzgSettings,
+    };
+    use std::sync::Arc;
 
     use super::*;
+    use crate::blob_provider::blob_to_bytes;
 
     #[allow(dead_code)]
     fn calc_commit_versioned_hash(commitment: &str) -> [u8; 32] {
@@ -602,7 +1096,7 @@ mod test {
             00000000000000000000000000000000";
         // println!("valid blob: {:?}", valid_blob_str);
         let blob_str = format!("{:0<262144}", valid_blob_str);
-        let dec_blob = blob_to_bytes(&blob_str);
+        let dec_blob = blob_to_bytes(&blob_str).unwrap();
         println!("dec blob tx len: {:?}", dec_blob.len());
         let txs = decode_transactions(&dec_blob);
         println!("dec blob tx: {txs:?}");
@@ -733,10 +1227,123 @@ mod test {
     // .unwrap();
     // }
 
+    #[test]
+    fn test_preflight_stats_json() {
+        let mut stats = PreflightStats {
+            execution_iterations: 2,
+            ..Default::default()
+        };
+        stats.record_stage(PreflightStage::Execution, Instant::now());
+        stats.record_stage(PreflightStage::Execution, Instant::now());
+        stats.record_stage(PreflightStage::FetchBlock, Instant::now());
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["execution_iterations"], 2);
+        let stage_millis = json["stage_millis"].as_object().unwrap();
+        assert_eq!(stage_millis.len(), 2);
+        assert!(stage_millis.contains_key("fetch_block"));
+        assert!(stage_millis.contains_key("execution"));
+    }
+
+    #[test]
+    fn test_transactions_not_full() {
+        let mut block = Block::default();
+        block.transactions = BlockTransactions::Hashes(vec![B256::ZERO]);
+        let err = get_transactions_from_block(7, &block).unwrap_err();
+        assert!(matches!(
+            err,
+            PreflightError::TransactionsNotFull {
+                block_number: 7,
+                stage: PreflightStage::DecodeTransactions,
+            }
+        ));
+        assert_eq!(err.block_number(), 7);
+    }
+
+    /// An L1 node without any `BlockProposed` events, where every transaction is mined.
+    struct NoProposalsProvider;
+
+    impl BlockDataProvider for NoProposalsProvider {
+        async fn get_blocks(&self, _blocks_to_fetch: &[(u64, bool)]) -> Result<Vec<Block>> {
+            Err(anyhow!("not supported by mock"))
+        }
+
+        async fn get_accounts(
+            &self,
+            _accounts: &[Address],
+        ) -> Result<Vec<revm::primitives::AccountInfo>> {
+            Err(anyhow!("not supported by mock"))
+        }
+
+        async fn get_storage_values(&self, _accounts: &[(Address, U256)]) -> Result<Vec<U256>> {
+            Err(anyhow!("not supported by mock"))
+        }
+
+        async fn get_merkle_proofs(
+            &self,
+            _block_number: u64,
+            _accounts: std::collections::HashMap<Address, Vec<U256>>,
+            _offset: usize,
+            _num_storage_proofs: usize,
+        ) -> Result<crate::MerkleProof> {
+            Err(anyhow!("not supported by mock"))
+        }
+
+        async fn get_logs(&self, _filter: &Filter) -> Result<Vec<alloy_rpc_types::Log>> {
+            Ok(Vec::new())
+        }
+
+        async fn get_transaction_by_hash(&self, hash: B256) -> Result<AlloyRpcTransaction> {
+            Ok(AlloyRpcTransaction {
+                hash,
+                block_hash: Some(B256::with_last_byte(1)),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proposed_block_number_without_proposal() {
+        let chain_spec = TAIKO_A7_CHAIN_SPEC.clone();
+        let err =
+            get_proposed_block_number(&NoProposalsProvider, &chain_spec, B256::with_last_byte(2))
+                .await
+                .unwrap_err();
+        assert!(err.to_string().contains("did not propose a block"));
+    }
+
+    #[tokio::test]
+    async fn test_proposal_not_found() {
+        let chain_spec = TAIKO_A7_CHAIN_SPEC.clone();
+        let anchor_header = AlloyConsensusHeader {
+            number: 100,
+            ..Default::default()
+        };
+        let err = get_block_proposed_event(
+            &NoProposalsProvider,
+            &chain_spec,
+            ProtocolVersion::A7,
+            7,
+            &anchor_header,
+            DEFAULT_PROPOSAL_SEARCH_RANGE,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            PreflightError::ProposalNotFound {
+                block_number: 7,
+                stage: PreflightStage::ProposalEvent,
+                from_block: 101,
+                to_block: 164,
+            }
+        ));
+    }
+
     #[ignore]
     #[test]
     fn test_slot_block_num_mapping() {
-        let chain_spec = get_network_spec(Network::TaikoA6);
+        let chain_spec = TAIKO_A6_CHAIN_SPEC.clone();
         let expected_slot = 1000u64;
         let second_per_slot = 12u64;
         let block_time = chain_spec.genesis_time + expected_slot * second_per_slot;
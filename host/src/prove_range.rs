@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use raiko_host::{
+    error::{HostError, HostResult},
+    load_chain_specs,
+    raiko::{resolve_block_number, Raiko},
+    request::{ProofRequest, ProofRequestOpt},
+    rpc_provider::RpcBlockDataProvider,
+};
+use raiko_lib::consts::SupportedChainSpecs;
+
+#[derive(Debug, Parser)]
+#[command(name = "prove-range")]
+#[command(about = "Proves consecutive blocks, reusing the state after each block for the next one", long_about = None)]
+struct Args {
+    /// The last block to prove, the first one is the block number of the request
+    #[arg(long, require_equals = true)]
+    last_block_number: u64,
+
+    /// Chain spec file with the networks that aren't built in
+    #[arg(long, require_equals = true)]
+    chain_spec_path: Option<PathBuf>,
+
+    #[command(flatten)]
+    proof_request_opt: ProofRequestOpt,
+}
+
+#[tokio::main]
+async fn main() -> HostResult<()> {
+    env_logger::init();
+    let mut args = Args::parse();
+
+    let chain_specs = match &args.chain_spec_path {
+        Some(path) => load_chain_specs(path)?,
+        None => SupportedChainSpecs::default(),
+    };
+    resolve_block_number(&mut args.proof_request_opt, &chain_specs).await?;
+    let request = ProofRequest::from_config(args.proof_request_opt, &chain_specs)?;
+    if args.last_block_number < request.block_number {
+        return Err(HostError::InvalidRequestConfig(format!(
+            "The last block {} is before the first block {}",
+            args.last_block_number, request.block_number
+        )));
+    }
+    let chain_spec = chain_specs
+        .get_chain_spec(&request.network.to_string())
+        .ok_or_else(|| {
+            HostError::InvalidRequestConfig(format!("Unsupported network: {}", request.network))
+        })?;
+    println!(
+        "Proving blocks {} to {} on {}",
+        request.block_number, args.last_block_number, request.network
+    );
+
+    let raiko = Raiko::new(chain_spec, request.clone()).with_chain_specs(chain_specs);
+    raiko.validate_request().await?;
+    let inputs = raiko
+        .generate_inputs(args.last_block_number, |block_number| {
+            RpcBlockDataProvider::new(&request.rpc, block_number)
+        })
+        .await?;
+    for (input, stats) in inputs {
+        let block_number = input.block_number;
+        println!(
+            "Preflight stats of block {block_number}: {}",
+            serde_json::to_string_pretty(&stats)?
+        );
+        let output = raiko.get_output(&input)?;
+        let proof = raiko.prove(input, &output).await?;
+        println!("Proof of block {block_number}: {proof}");
+    }
+    Ok(())
+}
@@ -11,17 +11,26 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use std::{collections::HashSet, mem::take};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    mem::take,
+};
 
 use alloy_consensus::Header as AlloyConsensusHeader;
 use alloy_primitives::Bytes;
-use raiko_lib::{builder::OptimisticDatabase, consts::ChainSpec, mem_db::MemDb, utils::to_header};
+use anyhow::anyhow;
+use raiko_lib::{
+    builder::OptimisticDatabase,
+    mem_db::{AccountState, DbAccount, MemDb},
+    utils::{to_header, HeaderHasher},
+};
 use raiko_primitives::{Address, B256, U256};
 use revm::{
     primitives::{Account, AccountInfo, Bytecode, HashMap},
     Database, DatabaseCommit,
 };
 use tokio::runtime::Handle;
+use tracing::{info, instrument, warn};
 
 use crate::{raiko::BlockDataProvider, MerkleProof};
 
@@ -29,6 +38,7 @@ pub struct ProviderDb<BDP: BlockDataProvider> {
     pub provider: BDP,
     pub block_number: u64,
     pub initial_db: MemDb,
+    /// Headers of the blocks fetched for `BLOCKHASH`, including the parent block
     pub initial_headers: HashMap<u64, AlloyConsensusHeader>,
     pub current_db: MemDb,
     async_executor: Handle,
@@ -38,14 +48,38 @@ pub struct ProviderDb<BDP: BlockDataProvider> {
     pub pending_accounts: HashSet<Address>,
     pub pending_slots: HashSet<(Address, U256)>,
     pub pending_block_hashes: HashSet<u64>,
+
+    /// Proofs fetched ahead of time by [ProviderDb::prefetch_proofs], with the keys they
+    /// were fetched for
+    prefetched_proofs: Option<(ProofKeys, (MerkleProof, MerkleProof, usize))>,
+}
+
+/// The state after a block as far as its preflight read or wrote it. The preflight of the next
+/// block starts from it, so that only the state the next block newly touches is fetched.
+#[derive(Clone, Debug, Default)]
+pub struct CarriedState {
+    /// The block the state is after.
+    pub block_number: u64,
+    pub block_hash: B256,
+    /// Accounts, storage slots and block hashes after the block.
+    pub db: MemDb,
+    /// Headers fetched for `BLOCKHASH`, including the header of the block itself.
+    pub headers: HashMap<u64, AlloyConsensusHeader>,
 }
 
+/// The accounts and storage slots that need a proof against the parent state and against
+/// the state after the block.
+type ProofKeys = (
+    BTreeMap<Address, BTreeSet<U256>>,
+    BTreeMap<Address, BTreeSet<U256>>,
+);
+
 impl<BDP: BlockDataProvider> ProviderDb<BDP> {
-    pub async fn new(
-        provider: BDP,
-        chain_spec: ChainSpec,
-        block_number: u64,
-    ) -> Result<Self, anyhow::Error> {
+    /// Creates a database on top of the state after `parent_header`. Block hashes are
+    /// fetched when they are read, except for the parent block hash which the Taiko anchor
+    /// transaction always reads and which is known from the parent header.
+    pub fn new(provider: BDP, parent_header: AlloyConsensusHeader) -> Self {
+        let block_number = parent_header.number;
         let mut provider_db = ProviderDb {
             provider,
             block_number,
@@ -58,30 +92,177 @@ impl<BDP: BlockDataProvider> ProviderDb<BDP> {
             pending_accounts: HashSet::new(),
             pending_slots: HashSet::new(),
             pending_block_hashes: HashSet::new(),
+            prefetched_proofs: None,
+        };
+        provider_db
+            .initial_db
+            .insert_block_hash(block_number, parent_header.hash());
+        provider_db
+            .initial_headers
+            .insert(block_number, parent_header);
+        provider_db
+    }
+
+    /// Stages the state touched by the block when the provider can collect it up front, so
+    /// that the first execution already has all the data it needs. Returns whether any
+    /// state was staged.
+    pub async fn stage_touched_state(&mut self, block_number: u64) -> Result<bool, anyhow::Error> {
+        let Some(state) = self.provider.get_touched_state(block_number).await? else {
+            return Ok(false);
         };
-        if chain_spec.is_taiko() {
-            // Get the 256 history block hashes from the provider at first time for anchor
-            // transaction.
-            let start = block_number.saturating_sub(255);
-            let block_numbers = (start..=block_number)
-                .map(|block_number| (block_number, false))
-                .collect::<Vec<_>>();
-            let initial_history_blocks = provider_db.provider.get_blocks(&block_numbers).await?;
-            for block in initial_history_blocks {
-                let block_number: u64 = block.header.number.unwrap().try_into().unwrap();
-                let block_hash = block.header.hash.unwrap();
-                provider_db
-                    .initial_db
-                    .insert_block_hash(block_number, block_hash);
-                provider_db
-                    .initial_headers
-                    .insert(block_number, to_header(&block.header));
+        info!(
+            "Staging {} accounts and {} storage slots touched by block {block_number}",
+            state.accounts.len(),
+            state
+                .storage
+                .values()
+                .map(|slots| slots.len())
+                .sum::<usize>(),
+        );
+        for (address, info) in state.accounts {
+            // Accounts carried over from the parent block are already staged
+            self.staging_db
+                .accounts
+                .entry(address)
+                .or_insert_with(|| DbAccount::new(info));
+        }
+        for (address, slots) in state.storage {
+            for (index, value) in slots {
+                self.staging_db
+                    .insert_account_storage(&address, index, value);
             }
         }
-        Ok(provider_db)
+        Ok(true)
+    }
+
+    /// Stages the state after the parent block that was carried over from its preflight.
+    /// Returns whether the state was staged, which it isn't if it is after another block.
+    pub fn stage_carried_state(&mut self, state: CarriedState) -> bool {
+        let Some(parent_hash) = self
+            .initial_headers
+            .get(&self.block_number)
+            .map(|header| header.hash())
+        else {
+            warn!(
+                "Not reusing the state after block {}, the parent header isn't known",
+                state.block_number
+            );
+            return false;
+        };
+        if state.block_number != self.block_number || state.block_hash != parent_hash {
+            warn!(
+                "Not reusing the state after block {} ({}), the parent is block {} ({parent_hash})",
+                state.block_number, state.block_hash, self.block_number
+            );
+            return false;
+        }
+        info!(
+            "Reusing {} accounts from the preflight of block {}",
+            state.db.accounts.len(),
+            state.block_number
+        );
+        self.staging_db.accounts.extend(state.db.accounts);
+        self.staging_db.block_hashes.extend(state.db.block_hashes);
+        for (block_number, header) in state.headers {
+            self.initial_headers.entry(block_number).or_insert(header);
+        }
+        true
+    }
+
+    /// Returns the state after the block with the given header, as far as it was read or
+    /// written, to carry over to the preflight of the next block.
+    pub fn carried_state(&self, header: AlloyConsensusHeader) -> CarriedState {
+        // Everything only read by the block is unchanged
+        let mut db = self.initial_db.clone();
+        for (address, account) in &self.current_db.accounts {
+            let Some(initial) = self.initial_db.accounts.get(address) else {
+                continue;
+            };
+            let carried = match account.state {
+                // Deleted accounts read as empty accounts without storage
+                AccountState::Deleted => DbAccount {
+                    info: AccountInfo::default(),
+                    state: AccountState::StorageCleared,
+                    storage: Default::default(),
+                },
+                // The code of a new contract is kept as revm analyzed it, which doesn't
+                // match its code hash anymore, so these are fetched again
+                _ if account.info.code_hash != initial.info.code_hash => {
+                    db.accounts.remove(address);
+                    continue;
+                }
+                AccountState::StorageCleared => DbAccount {
+                    info: AccountInfo {
+                        code: initial.info.code.clone(),
+                        ..account.info.clone()
+                    },
+                    state: AccountState::StorageCleared,
+                    storage: account.storage.clone(),
+                },
+                AccountState::Touched | AccountState::None => {
+                    let mut storage = initial.storage.clone();
+                    storage.extend(account.storage.iter().map(|(k, v)| (*k, *v)));
+                    DbAccount {
+                        info: AccountInfo {
+                            code: initial.info.code.clone(),
+                            ..account.info.clone()
+                        },
+                        state: AccountState::None,
+                        storage,
+                    }
+                }
+            };
+            db.accounts.insert(*address, carried);
+        }
+
+        let block_number = header.number;
+        let block_hash = header.hash();
+        db.block_hashes.insert(block_number, block_hash);
+        let mut headers = self.initial_headers.clone();
+        headers.insert(block_number, header);
+        CarriedState {
+            block_number,
+            block_hash,
+            db,
+            headers,
+        }
+    }
+
+    /// Fetches the proofs for the state read and written so far, so that [ProviderDb::get_proofs]
+    /// doesn't have to wait for them if the state doesn't change anymore.
+    pub async fn prefetch_proofs(&mut self) -> Result<(), anyhow::Error> {
+        let keys = self.proof_keys();
+        let proofs = self.fetch_proofs().await?;
+        self.prefetched_proofs = Some((keys, proofs));
+        Ok(())
     }
 
     pub async fn get_proofs(&mut self) -> Result<(MerkleProof, MerkleProof, usize), anyhow::Error> {
+        if let Some((keys, proofs)) = self.prefetched_proofs.take() {
+            if keys == self.proof_keys() {
+                info!("Reusing prefetched proofs");
+                return Ok(proofs);
+            }
+        }
+        self.fetch_proofs().await
+    }
+
+    fn proof_keys(&self) -> ProofKeys {
+        let collect = |storage_keys: std::collections::HashMap<Address, Vec<U256>>| {
+            storage_keys
+                .into_iter()
+                .map(|(address, keys)| (address, keys.into_iter().collect()))
+                .collect::<BTreeMap<_, BTreeSet<_>>>()
+        };
+        let initial_keys = collect(self.initial_db.storage_keys());
+        let mut latest_keys = initial_keys.clone();
+        for (address, keys) in collect(self.current_db.storage_keys()) {
+            latest_keys.entry(address).or_default().extend(keys);
+        }
+        (initial_keys, latest_keys)
+    }
+
+    async fn fetch_proofs(&mut self) -> Result<(MerkleProof, MerkleProof, usize), anyhow::Error> {
         // Latest proof keys
         let mut storage_keys = self.initial_db.storage_keys();
         for (address, mut indices) in self.current_db.storage_keys() {
@@ -126,6 +307,8 @@ impl<BDP: BlockDataProvider> ProviderDb<BDP> {
         Ok((initial_proofs, latest_proofs, num_storage_proofs))
     }
 
+    /// Returns the headers linking the parent block to the oldest block read by `BLOCKHASH`,
+    /// newest first.
     pub async fn get_ancestor_headers(
         &mut self,
     ) -> Result<Vec<AlloyConsensusHeader>, anyhow::Error> {
@@ -133,22 +316,32 @@ impl<BDP: BlockDataProvider> ProviderDb<BDP> {
             .initial_db
             .block_hashes
             .keys()
+            .copied()
             .min()
-            .unwrap_or(&self.block_number);
-
-        let mut headers = Vec::new();
-        for block_number in (*earliest_block..self.block_number).rev() {
-            if !self.initial_headers.contains_key(&block_number) {
-                let block = &self
-                    .provider
-                    .get_blocks(&vec![(block_number, false)])
-                    .await?[0];
-                self.initial_headers
-                    .insert(block_number, to_header(&block.header));
-            }
-            headers.push(self.initial_headers[&block_number].clone());
+            .unwrap_or(self.block_number);
+
+        // Fetch the headers in between that weren't read themselves in one go
+        let missing = (earliest_block..self.block_number)
+            .filter(|block_number| !self.initial_headers.contains_key(block_number))
+            .map(|block_number| (block_number, false))
+            .collect::<Vec<_>>();
+        for ((block_number, _), block) in missing
+            .iter()
+            .zip(self.provider.get_blocks(&missing).await?)
+        {
+            self.initial_headers
+                .insert(*block_number, to_header(&block.header));
         }
-        Ok(headers)
+
+        (earliest_block..self.block_number)
+            .rev()
+            .map(|block_number| {
+                self.initial_headers
+                    .get(&block_number)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("header of block {block_number} not fetched"))
+            })
+            .collect()
     }
 
     pub fn is_valid_run(&self) -> bool {
@@ -240,7 +433,8 @@ impl<BDP: BlockDataProvider> Database for ProviderDb<BDP> {
     }
 
     fn block_hash(&mut self, number: U256) -> Result<B256, Self::Error> {
-        let block_number = u64::try_from(number).unwrap();
+        let block_number =
+            u64::try_from(number).map_err(|_| anyhow!("invalid block number {number}"))?;
 
         // Check if the block hash is in the current database.
         if let Ok(block_hash) = self.initial_db.block_hash(number) {
@@ -260,22 +454,26 @@ impl<BDP: BlockDataProvider> Database for ProviderDb<BDP> {
         }
 
         // Get the block hash from the provider.
-        let block_hash = tokio::task::block_in_place(|| {
+        let block = tokio::task::block_in_place(|| {
             self.async_executor
                 .block_on(self.provider.get_blocks(&vec![(block_number, false)]))
-        })
-        .unwrap()[0]
+        })?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("block {block_number} not found"))?;
+        let block_hash = block
             .header
             .hash
-            .unwrap()
-            .0
-            .into();
+            .ok_or_else(|| anyhow!("block {block_number} has no hash"))?;
         self.initial_db.insert_block_hash(block_number, block_hash);
+        self.initial_headers
+            .insert(block_number, to_header(&block.header));
         Ok(block_hash)
     }
 
-    fn code_by_hash(&mut self, _code_hash: B256) -> Result<Bytecode, Self::Error> {
-        unreachable!()
+    fn code_by_hash(&mut self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        // The code is always loaded together with the account
+        Err(anyhow!("code {code_hash} requested by hash"))
     }
 }
 
@@ -286,7 +484,15 @@ impl<BDP: BlockDataProvider> DatabaseCommit for ProviderDb<BDP> {
 }
 
 impl<BDP: BlockDataProvider> OptimisticDatabase for ProviderDb<BDP> {
-    async fn fetch_data(&mut self) -> bool {
+    #[instrument(
+        skip_all,
+        fields(
+            accounts = self.pending_accounts.len(),
+            slots = self.pending_slots.len(),
+            block_hashes = self.pending_block_hashes.len(),
+        )
+    )]
+    async fn fetch_data(&mut self) -> Result<bool, anyhow::Error> {
         //println!("all accounts touched: {:?}", self.pending_accounts);
         //println!("all slots touched: {:?}", self.pending_slots);
         //println!("all block hashes touched: {:?}", self.pending_block_hashes);
@@ -297,8 +503,7 @@ impl<BDP: BlockDataProvider> OptimisticDatabase for ProviderDb<BDP> {
         let accounts = self
             .provider
             .get_accounts(&self.pending_accounts.iter().cloned().collect::<Vec<_>>())
-            .await
-            .unwrap();
+            .await?;
         for (address, account) in take(&mut self.pending_accounts)
             .into_iter()
             .zip(accounts.iter())
@@ -310,8 +515,7 @@ impl<BDP: BlockDataProvider> OptimisticDatabase for ProviderDb<BDP> {
         let slots = self
             .provider
             .get_storage_values(&self.pending_slots.iter().cloned().collect::<Vec<_>>())
-            .await
-            .unwrap();
+            .await?;
         for ((address, index), value) in take(&mut self.pending_slots).into_iter().zip(slots.iter())
         {
             self.staging_db
@@ -328,14 +532,23 @@ impl<BDP: BlockDataProvider> OptimisticDatabase for ProviderDb<BDP> {
                     .map(|block_number| (block_number, false))
                     .collect::<Vec<_>>(),
             )
-            .await
-            .unwrap();
+            .await?;
+        if blocks.len() != self.pending_block_hashes.len() {
+            return Err(anyhow!(
+                "requested {} blocks but got {}",
+                self.pending_block_hashes.len(),
+                blocks.len()
+            ));
+        }
         for (block_number, block) in take(&mut self.pending_block_hashes)
             .into_iter()
             .zip(blocks.iter())
         {
-            self.staging_db
-                .insert_block_hash(block_number, block.header.hash.unwrap());
+            let block_hash = block
+                .header
+                .hash
+                .ok_or_else(|| anyhow!("block {block_number} has no hash"))?;
+            self.staging_db.insert_block_hash(block_number, block_hash);
             self.initial_headers
                 .insert(block_number, to_header(&block.header));
         }
@@ -345,10 +558,240 @@ impl<BDP: BlockDataProvider> OptimisticDatabase for ProviderDb<BDP> {
             self.current_db = Default::default();
         }
 
-        valid_run
+        Ok(valid_run)
     }
 
     fn is_optimistic(&self) -> bool {
         self.optimistic
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use alloy_rpc_types::{Block, Filter, Log, Transaction};
+    use anyhow::Result;
+
+    use super::*;
+
+    /// Serves headers and empty proofs for any block and remembers which blocks were
+    /// requested. With `no_blocks` set no blocks are returned, like a node that doesn't know
+    /// them.
+    #[derive(Default)]
+    struct HeaderProvider {
+        requested: Mutex<Vec<u64>>,
+        proofs_requested: Mutex<Vec<u64>>,
+        no_blocks: bool,
+    }
+
+    impl BlockDataProvider for HeaderProvider {
+        async fn get_blocks(&self, blocks_to_fetch: &[(u64, bool)]) -> Result<Vec<Block>> {
+            if self.no_blocks {
+                return Ok(Vec::new());
+            }
+            Ok(blocks_to_fetch
+                .iter()
+                .map(|&(block_number, _)| {
+                    self.requested.lock().unwrap().push(block_number);
+                    let mut block = Block::default();
+                    block.header.number = Some(block_number.try_into().unwrap());
+                    block.header.hash = Some(B256::with_last_byte(block_number as u8));
+                    block.header.mix_hash = Some(B256::ZERO);
+                    block.header.nonce = Some(Default::default());
+                    block
+                })
+                .collect())
+        }
+
+        async fn get_accounts(&self, accounts: &[Address]) -> Result<Vec<AccountInfo>> {
+            assert!(accounts.is_empty());
+            Ok(Vec::new())
+        }
+
+        async fn get_storage_values(&self, accounts: &[(Address, U256)]) -> Result<Vec<U256>> {
+            assert!(accounts.is_empty());
+            Ok(Vec::new())
+        }
+
+        async fn get_merkle_proofs(
+            &self,
+            block_number: u64,
+            _accounts: std::collections::HashMap<Address, Vec<U256>>,
+            _offset: usize,
+            _num_storage_proofs: usize,
+        ) -> Result<MerkleProof> {
+            self.proofs_requested.lock().unwrap().push(block_number);
+            Ok(Default::default())
+        }
+
+        async fn get_logs(&self, _filter: &Filter) -> Result<Vec<Log>> {
+            Err(anyhow!("not supported by mock"))
+        }
+
+        async fn get_transaction_by_hash(&self, _hash: B256) -> Result<Transaction> {
+            Err(anyhow!("not supported by mock"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_block_hashes_fetched_on_demand() {
+        let parent_header = AlloyConsensusHeader {
+            number: 300,
+            ..Default::default()
+        };
+        let mut db = ProviderDb::new(HeaderProvider::default(), parent_header.clone());
+        assert!(db.provider.requested.lock().unwrap().is_empty());
+
+        // The parent block hash is known without asking the provider
+        assert_eq!(
+            db.block_hash(U256::from(300)).unwrap(),
+            parent_header.hash()
+        );
+
+        // Other block hashes are collected while executing optimistically
+        db.optimistic = true;
+        assert_eq!(db.block_hash(U256::from(297)).unwrap(), B256::ZERO);
+        assert!(!db.fetch_data().await.unwrap());
+        assert_eq!(
+            db.block_hash(U256::from(297)).unwrap(),
+            B256::with_last_byte(297u64 as u8)
+        );
+        assert!(db.fetch_data().await.unwrap());
+
+        // Only the blocks between the parent and the oldest block read are included
+        let headers = db.get_ancestor_headers().await.unwrap();
+        assert_eq!(
+            headers.iter().map(|h| h.number).collect::<Vec<_>>(),
+            vec![299, 298, 297]
+        );
+        assert_eq!(*db.provider.requested.lock().unwrap(), vec![297, 298, 299]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unknown_block_hash_is_an_error() {
+        let provider = HeaderProvider {
+            no_blocks: true,
+            ..Default::default()
+        };
+        let parent_header = AlloyConsensusHeader {
+            number: 300,
+            ..Default::default()
+        };
+        let mut db = ProviderDb::new(provider, parent_header);
+        assert!(db.block_hash(U256::from(297)).is_err());
+        assert!(db.block_hash(U256::MAX).is_err());
+
+        // The same holds when the block hash is fetched after an optimistic run
+        db.optimistic = true;
+        assert_eq!(db.block_hash(U256::from(297)).unwrap(), B256::ZERO);
+        assert!(db.fetch_data().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prefetched_proofs_reused() {
+        let address = Address::with_last_byte(1);
+        let parent_header = AlloyConsensusHeader {
+            number: 300,
+            ..Default::default()
+        };
+        let mut db = ProviderDb::new(HeaderProvider::default(), parent_header);
+        db.initial_db
+            .insert_account_info(address, AccountInfo::default());
+        db.initial_db
+            .insert_account_storage(&address, U256::from(1), U256::ZERO);
+
+        db.prefetch_proofs().await.unwrap();
+        assert_eq!(
+            *db.provider.proofs_requested.lock().unwrap(),
+            vec![300, 301]
+        );
+
+        // Nothing changed, so the prefetched proofs are used
+        db.get_proofs().await.unwrap();
+        assert_eq!(db.provider.proofs_requested.lock().unwrap().len(), 2);
+
+        // A slot that wasn't read before needs new proofs
+        db.prefetch_proofs().await.unwrap();
+        db.current_db
+            .insert_account_info(address, AccountInfo::default());
+        db.current_db
+            .insert_account_storage(&address, U256::from(2), U256::from(1));
+        db.get_proofs().await.unwrap();
+        assert_eq!(
+            *db.provider.proofs_requested.lock().unwrap(),
+            vec![300, 301, 300, 301, 300, 301]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_carried_state() {
+        let (a, b) = (Address::with_last_byte(1), Address::with_last_byte(2));
+        let info = AccountInfo {
+            balance: U256::from(100),
+            ..Default::default()
+        };
+        let parent_header = AlloyConsensusHeader {
+            number: 300,
+            ..Default::default()
+        };
+        let mut db = ProviderDb::new(HeaderProvider::default(), parent_header.clone());
+        db.initial_db.insert_account_info(a, info.clone());
+        db.initial_db
+            .insert_account_storage(&a, U256::from(1), U256::from(5));
+        db.initial_db
+            .insert_account_storage(&a, U256::from(2), U256::from(6));
+        db.initial_db.insert_account_info(b, info.clone());
+        // The block changes the balance and a slot of `a` and deletes `b`
+        db.current_db.accounts.insert(
+            a,
+            DbAccount {
+                info: AccountInfo {
+                    balance: U256::from(50),
+                    ..info.clone()
+                },
+                state: AccountState::Touched,
+                storage: [(U256::from(1), U256::from(7))].into(),
+            },
+        );
+        db.current_db.accounts.insert(
+            b,
+            DbAccount {
+                state: AccountState::Deleted,
+                ..Default::default()
+            },
+        );
+
+        let header = AlloyConsensusHeader {
+            number: 301,
+            parent_hash: parent_header.hash(),
+            ..Default::default()
+        };
+        let state = db.carried_state(header.clone());
+
+        // The next block reads the state without asking the provider
+        let mut next = ProviderDb::new(HeaderProvider::default(), header);
+        assert!(next.stage_carried_state(state.clone()));
+        assert_eq!(next.basic(a).unwrap().unwrap().balance, U256::from(50));
+        assert_eq!(next.storage(a, U256::from(1)).unwrap(), U256::from(7));
+        assert_eq!(next.storage(a, U256::from(2)).unwrap(), U256::from(6));
+        assert_eq!(next.basic(b).unwrap().unwrap(), AccountInfo::default());
+        assert_eq!(next.storage(b, U256::from(1)).unwrap(), U256::ZERO);
+        assert_eq!(
+            next.block_hash(U256::from(300)).unwrap(),
+            parent_header.hash()
+        );
+        assert!(next.provider.requested.lock().unwrap().is_empty());
+        assert_eq!(next.initial_db.accounts.len(), 2);
+
+        // The state is only used on top of the block it is after
+        let mut other = ProviderDb::new(
+            HeaderProvider::default(),
+            AlloyConsensusHeader {
+                number: 302,
+                ..Default::default()
+            },
+        );
+        assert!(!other.stage_carried_state(state));
+    }
+}
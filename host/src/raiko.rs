@@ -1,8 +1,8 @@
 use alloy_primitives::{Address, FixedBytes, B256, U256};
-use alloy_rpc_types::Block;
+use alloy_rpc_types::{Block, Filter, Log, Transaction};
 use anyhow::Result;
 use raiko_lib::builder::{BlockBuilderStrategy, TaikoStrategy};
-use raiko_lib::consts::ChainSpec;
+use raiko_lib::consts::{ChainSpec, SupportedChainSpecs};
 use raiko_lib::input::{GuestInput, GuestOutput, TaikoProverData, WrappedHeader};
 use raiko_lib::protocol_instance::{assemble_protocol_instance, ProtocolInstance};
 use raiko_lib::prover::{to_proof, Proof, Prover, ProverError, ProverResult};
@@ -10,11 +10,20 @@ use raiko_lib::utils::HeaderHasher;
 use revm::primitives::AccountInfo;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tracing::{trace, warn};
+use std::path::Path;
+use tracing::{info, trace, warn};
 
+use crate::archive::Recorder;
+use crate::blob_provider::{
+    BeaconBlobProvider, BlobArchiveProvider, BlobSource, BlobSources, LocalBlobProvider,
+};
 use crate::error::{self, HostError};
-use crate::preflight::preflight;
-use crate::request::ProofRequest;
+use crate::preflight::{
+    get_proposed_block_number, preflight, preflight_blocks, PreflightOptions, PreflightStats,
+    DEFAULT_PROPOSAL_SEARCH_RANGE,
+};
+use crate::request::{ProofRequest, ProofRequestOpt};
+use crate::rpc_provider::{endpoint_label, parse_endpoints, RpcBlockDataProvider};
 use crate::MerkleProof;
 
 #[allow(async_fn_in_trait)]
@@ -35,11 +44,52 @@ pub trait BlockDataProvider {
         offset: usize,
         num_storage_proofs: usize,
     ) -> Result<MerkleProof, anyhow::Error>;
+
+    /// Returns the state of all accounts and storage slots accessed by the block, as it was
+    /// before the block was executed. Providers that can't collect this in one go return
+    /// `None`, in which case the state is discovered by executing the block optimistically.
+    async fn get_touched_state(
+        &self,
+        _block_number: u64,
+    ) -> Result<Option<TouchedState>, anyhow::Error> {
+        Ok(None)
+    }
+
+    async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>, anyhow::Error>;
+    async fn get_transaction_by_hash(&self, hash: B256) -> Result<Transaction, anyhow::Error>;
+
+    /// The number of JSON-RPC calls sent to the node so far, retries included. Providers that
+    /// don't talk to a node return 0.
+    fn rpc_calls(&self) -> u64 {
+        0
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait BlobDataProvider {
+    /// Returns the blob with the given versioned hash that was published in the beacon slot.
+    async fn get_blob(&self, slot_id: u64, blob_hash: B256) -> Result<BlobData, anyhow::Error>;
+}
+
+/// A blob together with its KZG commitment and the proof that the blob matches it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobData {
+    pub blob: Vec<u8>,
+    pub commitment: FixedBytes<48>,
+    pub proof: FixedBytes<48>,
+}
+
+/// The pre-state of the accounts and storage slots accessed by a block.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TouchedState {
+    pub accounts: HashMap<Address, AccountInfo>,
+    pub storage: HashMap<Address, HashMap<U256, U256>>,
 }
 
 pub struct Raiko {
     chain_spec: ChainSpec,
     request: ProofRequest,
+    chain_specs: SupportedChainSpecs,
 }
 
 impl Raiko {
@@ -47,14 +97,118 @@ impl Raiko {
         Self {
             chain_spec,
             request,
+            chain_specs: SupportedChainSpecs::default(),
+        }
+    }
+
+    /// Use the given registry to look up other chains, like the L1 of a Taiko chain.
+    pub fn with_chain_specs(mut self, chain_specs: SupportedChainSpecs) -> Self {
+        self.chain_specs = chain_specs;
+        self
+    }
+
+    /// Checks the request against the live chains before any expensive work is done, so that
+    /// a wrong network or endpoint fails early with a clear error.
+    pub async fn validate_request(&self) -> Result<(), HostError> {
+        let block_number = self.request.block_number;
+        if block_number == 0 {
+            return Err(HostError::InvalidRequestConfig(
+                "The genesis block cannot be proven".to_string(),
+            ));
+        }
+
+        for (name, provider) in connect(&self.request.rpc, "rpc", block_number)? {
+            let (chain_id, head) = query_chain(&provider, &name).await?;
+            check_chain_id(&name, chain_id, &self.chain_spec)?;
+            if block_number > head {
+                return Err(HostError::InvalidRequestConfig(format!(
+                    "Block {block_number} does not exist yet, the head of {name} is at block {head}"
+                )));
+            }
+        }
+
+        if self.chain_spec.is_taiko() {
+            let l1_chain_spec = self
+                .chain_specs
+                .get_chain_spec(&self.request.l1_network)
+                .ok_or_else(|| {
+                    HostError::InvalidRequestConfig(format!(
+                        "Invalid l1_network: {}",
+                        self.request.l1_network
+                    ))
+                })?;
+            for (name, provider_l1) in connect(&self.request.l1_rpc, "l1_rpc", block_number)? {
+                let (l1_chain_id, _) = query_chain(&provider_l1, &name).await?;
+                check_chain_id(&name, l1_chain_id, &l1_chain_spec)?;
+            }
+            reqwest::Url::parse(&self.request.beacon_rpc).map_err(|e| {
+                HostError::InvalidRequestConfig(format!("Invalid beacon_rpc url: {e}"))
+            })?;
+            if let Some(url) = &self.request.blob_archive_url {
+                reqwest::Url::parse(url).map_err(|e| {
+                    HostError::InvalidRequestConfig(format!("Invalid blob_archive_url: {e}"))
+                })?;
+            }
+            if self.request.proposal_search_range == Some(0) {
+                return Err(HostError::InvalidRequestConfig(
+                    "proposal_search_range has to be at least 1".to_string(),
+                ));
+            }
+        } else if self.request.proposal_tx_hash.is_some() {
+            return Err(HostError::InvalidRequestConfig(format!(
+                "{} has no proposal transactions",
+                self.chain_spec.name
+            )));
         }
+
+        Ok(())
     }
 
     pub async fn generate_input<BDP: BlockDataProvider>(
         &self,
         provider: BDP,
-    ) -> Result<GuestInput, HostError> {
-        preflight(
+    ) -> Result<(GuestInput, PreflightStats), HostError> {
+        self.generate_input_with_providers(provider, self.l1_provider(), self.blob_provider())
+            .await
+    }
+
+    /// Generates the input like [`Self::generate_input`] and writes everything fetched from
+    /// the nodes to an archive at `path`, even when the input generation fails.
+    pub async fn generate_input_recorded<BDP: BlockDataProvider>(
+        &self,
+        provider: BDP,
+        path: &Path,
+    ) -> Result<(GuestInput, PreflightStats), HostError> {
+        let recorder = Recorder::new(self.request.clone());
+        let result = self
+            .generate_input_with_providers(
+                recorder.record_l2(provider),
+                self.l1_provider()
+                    .map(|provider| recorder.record_l1(provider)),
+                self.blob_provider()
+                    .map(|provider| recorder.record_blobs(provider)),
+            )
+            .await;
+        match recorder.save(path) {
+            Ok(()) => info!("Recorded the preflight data to {path:?}"),
+            Err(e) => warn!("Could not record the preflight data to {path:?}: {e:#}"),
+        }
+        result
+    }
+
+    /// Generates the input with the given providers instead of the ones configured in the
+    /// request. The L1 and blob providers are only used for Taiko blocks.
+    pub async fn generate_input_with_providers<
+        BDP: BlockDataProvider,
+        L1: BlockDataProvider,
+        BP: BlobDataProvider,
+    >(
+        &self,
+        provider: BDP,
+        provider_l1: Option<L1>,
+        blob_provider: Option<BP>,
+    ) -> Result<(GuestInput, PreflightStats), HostError> {
+        let (input, stats) = preflight(
             provider,
             self.request.block_number,
             self.chain_spec.clone(),
@@ -62,11 +216,84 @@ impl Raiko {
                 graffiti: self.request.graffiti,
                 prover: self.request.prover,
             },
-            Some(self.request.l1_rpc.clone()),
-            Some(self.request.beacon_rpc.clone()),
+            provider_l1,
+            blob_provider,
+            PreflightOptions {
+                proposal_search_range: self
+                    .request
+                    .proposal_search_range
+                    .unwrap_or(DEFAULT_PROPOSAL_SEARCH_RANGE),
+                block_hash: self.request.block_hash,
+                proposal_tx_hash: self.request.proposal_tx_hash,
+                carried_state: None,
+            },
         )
         .await
-        .map_err(Into::<error::HostError>::into)
+        .map_err(Into::<error::HostError>::into)?;
+        info!("Preflight stats: {}", serde_json::to_string(&stats)?);
+        Ok((input, stats))
+    }
+
+    /// Generates the inputs of the requested block and of the blocks after it up to
+    /// `last_block_number`, reusing the state after each block for the next one. `provider`
+    /// returns the L2 provider serving the state after the given block.
+    pub async fn generate_inputs<BDP: BlockDataProvider>(
+        &self,
+        last_block_number: u64,
+        provider: impl Fn(u64) -> BDP,
+    ) -> Result<Vec<(GuestInput, PreflightStats)>, HostError> {
+        let inputs = preflight_blocks(
+            self.request.block_number..=last_block_number,
+            self.chain_spec.clone(),
+            TaikoProverData {
+                graffiti: self.request.graffiti,
+                prover: self.request.prover,
+            },
+            self.request
+                .proposal_search_range
+                .unwrap_or(DEFAULT_PROPOSAL_SEARCH_RANGE),
+            |block_number| {
+                (
+                    provider(block_number.saturating_sub(1)),
+                    self.l1_provider(),
+                    self.blob_provider(),
+                )
+            },
+        )
+        .await
+        .map_err(Into::<error::HostError>::into)?;
+        for (input, stats) in &inputs {
+            info!(
+                "Preflight stats of block {}: {}",
+                input.block_number,
+                serde_json::to_string(stats)?
+            );
+        }
+        Ok(inputs)
+    }
+
+    fn l1_provider(&self) -> Option<RpcBlockDataProvider> {
+        self.chain_spec
+            .is_taiko()
+            .then(|| RpcBlockDataProvider::new(&self.request.l1_rpc, self.request.block_number))
+    }
+
+    /// The blob sources in the order they are tried: the beacon node, then the blob archive
+    /// and the local blob directory when configured.
+    fn blob_provider(&self) -> Option<BlobSources> {
+        if !self.chain_spec.is_taiko() {
+            return None;
+        }
+        let mut sources = vec![BlobSource::Beacon(BeaconBlobProvider::new(
+            &self.request.beacon_rpc,
+        ))];
+        if let Some(url) = &self.request.blob_archive_url {
+            sources.push(BlobSource::Archive(BlobArchiveProvider::new(url)));
+        }
+        if let Some(dir) = &self.request.blob_dir {
+            sources.push(BlobSource::Local(LocalBlobProvider::new(dir)));
+        }
+        Some(BlobSources::new(sources))
     }
 
     pub fn get_output(&self, input: &GuestInput) -> Result<GuestOutput, HostError> {
@@ -199,6 +426,119 @@ impl Prover for NativeProver {
     }
 }
 
+/// Fills in the block number of a request that only names the block by its hash or by the L1
+/// transaction that proposed it. The hashes stay in the request, so the preflight still checks
+/// that the block it fetched is the one that was asked for.
+pub async fn resolve_block_number(
+    config: &mut ProofRequestOpt,
+    chain_specs: &SupportedChainSpecs,
+) -> Result<(), HostError> {
+    if config.block_number.is_some() {
+        return Ok(());
+    }
+
+    if let Some(block_hash) = &config.block_hash {
+        let block_hash: B256 = block_hash
+            .parse()
+            .map_err(|_| HostError::InvalidRequestConfig("Invalid block_hash".to_string()))?;
+        let rpc = config
+            .rpc
+            .as_deref()
+            .ok_or(HostError::InvalidRequestConfig("Missing rpc".to_string()))?;
+        let provider = lookup_provider(rpc, "rpc")?;
+        let block_number = provider
+            .get_block_number_by_hash(block_hash)
+            .await
+            .map_err(|e| {
+                HostError::InvalidRequestConfig(format!(
+                    "Could not look up block {block_hash}: {e:#}"
+                ))
+            })?
+            .ok_or_else(|| {
+                HostError::InvalidRequestConfig(format!("Block {block_hash} is not known to rpc"))
+            })?;
+        config.block_number = Some(block_number);
+    } else if let Some(proposal_tx_hash) = &config.proposal_tx_hash {
+        let proposal_tx_hash: B256 = proposal_tx_hash
+            .parse()
+            .map_err(|_| HostError::InvalidRequestConfig("Invalid proposal_tx_hash".to_string()))?;
+        let chain_spec = config
+            .network
+            .as_deref()
+            .ok_or(HostError::InvalidRequestConfig(
+                "Missing network".to_string(),
+            ))
+            .and_then(|network| {
+                chain_specs
+                    .get_chain_spec(network)
+                    .ok_or(HostError::InvalidRequestConfig(
+                        "Invalid network".to_string(),
+                    ))
+            })?;
+        let l1_rpc = config
+            .l1_rpc
+            .as_deref()
+            .ok_or(HostError::InvalidRequestConfig(
+                "Missing l1_rpc".to_string(),
+            ))?;
+        let provider_l1 = lookup_provider(l1_rpc, "l1_rpc")?;
+        let block_number = get_proposed_block_number(&provider_l1, &chain_spec, proposal_tx_hash)
+            .await
+            .map_err(|e| {
+                HostError::InvalidRequestConfig(format!(
+                    "Could not find the block proposed by {proposal_tx_hash}: {e:#}"
+                ))
+            })?;
+        config.block_number = Some(block_number);
+    }
+
+    Ok(())
+}
+
+/// Creates a provider for looking up blocks and transactions, rejecting invalid urls instead
+/// of panicking on them.
+fn lookup_provider(urls: &str, name: &str) -> Result<RpcBlockDataProvider, HostError> {
+    parse_endpoints(urls)
+        .map_err(|e| HostError::InvalidRequestConfig(format!("Invalid {name}: {e:#}")))?;
+    Ok(RpcBlockDataProvider::new(urls, 0))
+}
+
+/// Creates a separate provider for every endpoint in `urls` so each one can be checked.
+fn connect(
+    urls: &str,
+    name: &str,
+    block_number: u64,
+) -> Result<Vec<(String, RpcBlockDataProvider)>, HostError> {
+    let endpoints = parse_endpoints(urls)
+        .map_err(|e| HostError::InvalidRequestConfig(format!("Invalid {name}: {e:#}")))?;
+    Ok(endpoints
+        .into_iter()
+        .map(|url| {
+            (
+                format!("{name} {}", endpoint_label(&url)),
+                RpcBlockDataProvider::new(url.as_str(), block_number),
+            )
+        })
+        .collect())
+}
+
+async fn query_chain(provider: &RpcBlockDataProvider, name: &str) -> Result<(u64, u64), HostError> {
+    provider.get_chain_id_and_head().await.map_err(|e| {
+        HostError::InvalidRequestConfig(format!("Could not query the chain of {name}: {e}"))
+    })
+}
+
+fn check_chain_id(name: &str, chain_id: u64, chain_spec: &ChainSpec) -> Result<(), HostError> {
+    if chain_id != chain_spec.chain_id() {
+        return Err(HostError::InvalidRequestConfig(format!(
+            "{name} is connected to chain {chain_id}, but network {} has chain id {}",
+            chain_spec.name,
+            chain_spec.chain_id()
+        )));
+    }
+    Ok(())
+}
+
 fn check_eq<T: std::cmp::PartialEq + std::fmt::Debug>(expected: T, actual: T, message: &str) {
     if expected != actual {
         println!(
@@ -210,12 +550,15 @@ fn check_eq<T: std::cmp::PartialEq + std::fmt::Debug>(expected: T, actual: T, me
 
 #[cfg(test)]
 mod tests {
-    use crate::raiko::{ChainSpec, Raiko};
-    use crate::request::{ProofRequest, ProofType};
+    use crate::error::HostError;
+    use crate::raiko::{check_chain_id, resolve_block_number, ChainSpec, Raiko};
+    use crate::request::{ProofRequest, ProofRequestOpt, ProofType};
     use crate::rpc_provider::RpcBlockDataProvider;
     use alloy_primitives::Address;
     use clap::ValueEnum;
-    use raiko_lib::consts::{get_network_spec, Network};
+    use raiko_lib::consts::{
+        Network, SupportedChainSpecs, ETH_HOLESKY_CHAIN_SPEC, TAIKO_A7_CHAIN_SPEC,
+    };
     use raiko_primitives::B256;
     use serde_json::{json, Value};
     use std::collections::HashMap;
@@ -262,7 +605,7 @@ mod tests {
         let provider =
             RpcBlockDataProvider::new(&proof_request.rpc.clone(), proof_request.block_number - 1);
         let raiko = Raiko::new(chain_spec, proof_request.clone());
-        let mut input = raiko
+        let (mut input, _stats) = raiko
             .generate_input(provider)
             .await
             .expect("input generation failed");
@@ -276,17 +619,58 @@ mod tests {
             .expect("proof generation failed");
     }
 
+    #[test]
+    fn test_check_chain_id() {
+        let chain_spec = TAIKO_A7_CHAIN_SPEC.clone();
+        assert!(check_chain_id("rpc", chain_spec.chain_id(), &chain_spec).is_ok());
+        assert!(matches!(
+            check_chain_id("rpc", ETH_HOLESKY_CHAIN_SPEC.chain_id(), &chain_spec),
+            Err(HostError::InvalidRequestConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_block_number_invalid_rpc() {
+        let mut config = ProofRequestOpt {
+            block_hash: Some(B256::ZERO.to_string()),
+            rpc: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            resolve_block_number(&mut config, &SupportedChainSpecs::default()).await,
+            Err(HostError::InvalidRequestConfig(_))
+        ));
+
+        let mut config = ProofRequestOpt {
+            proposal_tx_hash: Some(B256::ZERO.to_string()),
+            network: Some(Network::TaikoA7.to_string()),
+            l1_rpc: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            resolve_block_number(&mut config, &SupportedChainSpecs::default()).await,
+            Err(HostError::InvalidRequestConfig(_))
+        ));
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_prove_block_taiko_a7() {
         let proof_type = get_proof_type_from_env();
         let network = Network::TaikoA7;
         let block_number = 105987;
-        let chain_spec = get_network_spec(network);
+        let chain_spec = SupportedChainSpecs::default()
+            .get_chain_spec(&network.to_string())
+            .unwrap();
         let proof_request = ProofRequest {
             block_number,
             rpc: "https://rpc.hekla.taiko.xyz".to_string(),
             l1_rpc: "https://ethereum-holesky-rpc.publicnode.com".to_string(),
             beacon_rpc: "https://l1beacon.hekla.taiko.xyz".to_string(),
+            blob_archive_url: None,
+            blob_dir: None,
+            proposal_search_range: None,
+            block_hash: None,
+            proposal_tx_hash: None,
             network,
             graffiti: B256::ZERO,
             prover: Address::ZERO,
@@ -304,12 +688,19 @@ mod tests {
         if proof_type != ProofType::Sp1 {
             let network = Network::Ethereum;
             let block_number = 19707175;
-            let chain_spec = get_network_spec(network);
+            let chain_spec = SupportedChainSpecs::default()
+                .get_chain_spec(&network.to_string())
+                .unwrap();
             let proof_request = ProofRequest {
                 block_number,
                 rpc: "https://rpc.ankr.com/eth".to_string(),
                 l1_rpc: String::new(),
                 beacon_rpc: String::new(),
+                blob_archive_url: None,
+                blob_dir: None,
+                proposal_search_range: None,
+                block_hash: None,
+                proposal_tx_hash: None,
                 network,
                 graffiti: B256::ZERO,
                 prover: Address::ZERO,
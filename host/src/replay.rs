@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use raiko_host::{
+    archive::PreflightArchive,
+    error::{HostError, HostResult},
+    load_chain_specs,
+    raiko::Raiko,
+};
+use raiko_lib::{consts::SupportedChainSpecs, input::GuestOutput, utils::HeaderHasher};
+
+#[derive(Debug, Parser)]
+#[command(name = "replay")]
+#[command(about = "Reruns a recorded proof request without access to any node", long_about = None)]
+struct Args {
+    /// Archive written by the host when started with `--record-path`
+    archive: PathBuf,
+
+    /// Chain spec file the request was proven with, if the network isn't a built-in one
+    #[arg(long, require_equals = true)]
+    chain_spec_path: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> HostResult<()> {
+    env_logger::init();
+    let args = Args::parse();
+
+    let archive = PreflightArchive::load(&args.archive)?;
+    let request = archive.request.clone();
+    println!(
+        "Replaying block {} on {} from {:?}",
+        request.block_number, request.network, args.archive
+    );
+
+    let chain_specs = match &args.chain_spec_path {
+        Some(path) => load_chain_specs(path)?,
+        None => SupportedChainSpecs::default(),
+    };
+    let chain_spec = chain_specs
+        .get_chain_spec(&request.network.to_string())
+        .ok_or_else(|| {
+            HostError::InvalidRequestConfig(format!("Unsupported network: {}", request.network))
+        })?;
+    let raiko = Raiko::new(chain_spec, request).with_chain_specs(chain_specs);
+    let (input, stats) = raiko
+        .generate_input_with_providers(
+            archive.replay_l2(),
+            Some(archive.replay_l1()),
+            Some(archive.replay_blobs()),
+        )
+        .await?;
+    println!("Preflight stats: {}", serde_json::to_string_pretty(&stats)?);
+    if let GuestOutput::Success((header, _)) = raiko.get_output(&input)? {
+        println!("Block hash: {}", header.header.hash());
+    }
+    Ok(())
+}
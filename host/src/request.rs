@@ -1,10 +1,14 @@
 use core::fmt::Debug;
-use std::{collections::HashMap, path::Path, str::FromStr};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use alloy_primitives::{Address, B256};
 use clap::{Args, ValueEnum};
 use raiko_lib::{
-    consts::Network,
+    consts::{Network, SupportedChainSpecs},
     input::{GuestInput, GuestOutput},
     protocol_instance::ProtocolInstance,
     prover::{Proof, Prover},
@@ -12,6 +16,7 @@ use raiko_lib::{
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_with::{serde_as, DisplayFromStr};
+use tracing::instrument;
 use utoipa::ToSchema;
 
 use crate::{
@@ -95,6 +100,7 @@ impl ProofType {
     }
 
     /// Run the prover driver depending on the proof type.
+    #[instrument(skip_all, fields(proof_type = %self, block_number = input.block_number))]
     pub async fn run_prover(
         &self,
         input: GuestInput,
@@ -139,6 +145,12 @@ impl ProofType {
 pub struct ProofRequest {
     /// The block number for the block to generate a proof for.
     pub block_number: u64,
+    #[serde(default)]
+    /// The hash the block has to have.
+    pub block_hash: Option<B256>,
+    #[serde(default)]
+    /// The L1 transaction that has to have proposed the block.
+    pub proposal_tx_hash: Option<B256>,
     /// RPC URL for retrieving block by block number.
     pub rpc: String,
     /// The L1 node URL for signal root verify and get txlist info from proposed
@@ -146,6 +158,15 @@ pub struct ProofRequest {
     pub l1_rpc: String,
     /// The beacon node URL for retrieving data blobs.
     pub beacon_rpc: String,
+    #[serde(default)]
+    /// A blob archive API to get blobs from once the beacon node pruned them.
+    pub blob_archive_url: Option<String>,
+    #[serde(default)]
+    /// A local directory with blobs named by versioned hash, used as the last resort.
+    pub blob_dir: Option<PathBuf>,
+    #[serde(default)]
+    /// The number of L1 blocks after the anchored L1 block to search for the proposal.
+    pub proposal_search_range: Option<u64>,
     /// The network to generate the proof for.
     pub network: Network,
     /// The L1 network to grnerate the proof for.
@@ -170,17 +191,39 @@ pub struct ProofRequestOpt {
     /// The block number for the block to generate a proof for.
     pub block_number: Option<u64>,
     #[arg(long, require_equals = true)]
-    /// RPC URL for retrieving block by block number.
+    /// The hash of the block to generate a proof for, instead of or on top of the block
+    /// number.
+    pub block_hash: Option<String>,
+    #[arg(long, require_equals = true)]
+    /// The L1 transaction that proposed the block to generate a proof for, instead of or
+    /// on top of the block number.
+    pub proposal_tx_hash: Option<String>,
+    #[arg(long, require_equals = true)]
+    /// RPC URL for retrieving block by block number. Accepts a comma separated list of
+    /// URLs, the ones after the first are used as fallbacks.
     pub rpc: Option<String>,
     #[arg(long, require_equals = true)]
     /// The L1 node URL for signal root verify and get txlist info from proposed
-    /// transaction.
+    /// transaction. Accepts a comma separated list of URLs like `rpc`.
     pub l1_rpc: Option<String>,
     #[arg(long, require_equals = true)]
     /// The beacon node URL for retrieving data blobs.
     pub beacon_rpc: Option<String>,
     #[arg(long, require_equals = true)]
-    /// The network to generate the proof for.
+    /// A blob archive API (e.g. Blobscan) serving `GET /blobs/{versioned_hash}`, used for
+    /// blobs the beacon node pruned.
+    pub blob_archive_url: Option<String>,
+    #[arg(long, require_equals = true)]
+    /// A local directory with blobs named by versioned hash, tried after the beacon node
+    /// and the blob archive.
+    pub blob_dir: Option<PathBuf>,
+    #[arg(long, require_equals = true)]
+    /// The number of L1 blocks after the anchored L1 block to search for the block
+    /// proposal. Defaults to 64.
+    pub proposal_search_range: Option<u64>,
+    #[arg(long, require_equals = true)]
+    /// The network to generate the proof for, a built-in network or one from the chain spec
+    /// file.
     pub network: Option<String>,
     #[arg(long, require_equals = true)]
     /// The L1 network to generate the proof for.
@@ -199,6 +242,32 @@ pub struct ProofRequestOpt {
     pub prover_args: ProverSpecificOpts,
 }
 
+#[derive(Default, Clone, Serialize, Deserialize, Debug, ToSchema)]
+#[serde(default)]
+/// The defaults used for all requests of a single network. Fields left empty fall back to the
+/// global config.
+pub struct NetworkProfile {
+    /// RPC URL for retrieving block by block number.
+    pub rpc: Option<String>,
+    /// The L1 node URL for signal root verify and get txlist info from proposed
+    /// transaction.
+    pub l1_rpc: Option<String>,
+    /// The beacon node URL for retrieving data blobs.
+    pub beacon_rpc: Option<String>,
+    /// A blob archive API to get blobs from once the beacon node pruned them.
+    pub blob_archive_url: Option<String>,
+    /// A local directory with blobs named by versioned hash.
+    pub blob_dir: Option<PathBuf>,
+    /// The number of L1 blocks after the anchored L1 block to search for the proposal.
+    pub proposal_search_range: Option<u64>,
+    /// The L1 network to generate the proof for.
+    pub l1_network: Option<String>,
+    /// The default proof type.
+    pub proof_type: Option<String>,
+    /// Any additional prover params in JSON format.
+    pub prover_args: ProverSpecificOpts,
+}
+
 #[derive(Default, Clone, Serialize, Deserialize, Debug, ToSchema, Args)]
 pub struct ProverSpecificOpts {
     pub native: Option<Value>,
@@ -241,6 +310,11 @@ impl ProofRequestOpt {
         *self = serde_json::from_value(this)?;
         Ok(())
     }
+
+    /// Merge the defaults of a network profile into the current config.
+    pub fn merge_profile(&mut self, profile: &NetworkProfile) -> Result<(), HostError> {
+        self.merge(&serde_json::to_value(profile)?)
+    }
 }
 
 impl TryFrom<ProofRequestOpt> for ProofRequest {
@@ -249,8 +323,20 @@ impl TryFrom<ProofRequestOpt> for ProofRequest {
     fn try_from(value: ProofRequestOpt) -> Result<Self, Self::Error> {
         Ok(Self {
             block_number: value.block_number.ok_or(HostError::InvalidRequestConfig(
-                "Missing block number".to_string(),
+                "Missing block number, block_hash or proposal_tx_hash".to_string(),
             ))?,
+            block_hash: value
+                .block_hash
+                .map(|hash| hash.parse())
+                .transpose()
+                .map_err(|_| HostError::InvalidRequestConfig("Invalid block_hash".to_string()))?,
+            proposal_tx_hash: value
+                .proposal_tx_hash
+                .map(|hash| hash.parse())
+                .transpose()
+                .map_err(|_| {
+                    HostError::InvalidRequestConfig("Invalid proposal_tx_hash".to_string())
+                })?,
             rpc: value
                 .rpc
                 .ok_or(HostError::InvalidRequestConfig("Missing rpc".to_string()))?,
@@ -260,6 +346,9 @@ impl TryFrom<ProofRequestOpt> for ProofRequest {
             beacon_rpc: value.beacon_rpc.ok_or(HostError::InvalidRequestConfig(
                 "Missing beacon_rpc".to_string(),
             ))?,
+            blob_archive_url: value.blob_archive_url,
+            blob_dir: value.blob_dir,
+            proposal_search_range: value.proposal_search_range,
             network: value
                 .network
                 .ok_or(HostError::InvalidRequestConfig(
@@ -295,3 +384,53 @@ impl TryFrom<ProofRequestOpt> for ProofRequest {
         })
     }
 }
+
+impl ProofRequest {
+    /// Builds a proof request from a config, rejecting networks without a chain spec. Any
+    /// name parses as a network, so a typo would otherwise only show up when proving.
+    pub fn from_config(
+        config: ProofRequestOpt,
+        chain_specs: &SupportedChainSpecs,
+    ) -> Result<Self, HostError> {
+        let request = Self::try_from(config)?;
+        for network in [request.network.to_string(), request.l1_network.clone()] {
+            if chain_specs.get_chain_spec(&network).is_none() {
+                return Err(HostError::InvalidRequestConfig(format!(
+                    "Unsupported network: {network}"
+                )));
+            }
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsupported_network_rejected() {
+        let config = |network: &str| ProofRequestOpt {
+            block_number: Some(1),
+            rpc: Some("http://localhost:8545".to_string()),
+            l1_rpc: Some("http://localhost:8546".to_string()),
+            beacon_rpc: Some("http://localhost:5052".to_string()),
+            network: Some(network.to_string()),
+            l1_network: Some("holesky".to_string()),
+            graffiti: Some(B256::ZERO.to_string()),
+            prover: Some(Address::ZERO.to_string()),
+            proof_type: Some("native".to_string()),
+            ..Default::default()
+        };
+        let chain_specs = SupportedChainSpecs::default();
+        let request = ProofRequest::from_config(config("Taiko_A7"), &chain_specs).unwrap();
+        assert_eq!(request.network, Network::TaikoA7);
+
+        // The name parses, but there's no chain spec for it
+        assert!(ProofRequest::try_from(config("taiko_a77")).is_ok());
+        assert!(matches!(
+            ProofRequest::from_config(config("taiko_a77"), &chain_specs),
+            Err(HostError::InvalidRequestConfig(_))
+        ));
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use alloy_primitives::{keccak256, Address, Bytes, B256};
+use alloy_rpc_types::Block;
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{debug, warn};
+
+use crate::metrics::{inc_rpc_cache_hit, inc_rpc_cache_miss};
+
+/// A size bounded on-disk cache for data fetched over JSON-RPC that can't change anymore,
+/// shared by all requests:
+/// - `{network}/headers/{number}-{hash}.json`: blocks without their transactions
+/// - `code/{code_hash}`: contract code, which is the same on every network
+/// - `{network}/proofs/{block_hash}/{address}-{slots_hash}.json`: `eth_getProof` responses
+///
+/// Headers and proofs are only returned for the block hash they were stored under, so data of
+/// blocks that were reorged out is never used.
+///
+/// When the cache grows over its maximum size the least recently used entries are removed.
+/// Failing to read or write the cache is never an error, the data is fetched from the node
+/// instead.
+#[derive(Debug)]
+pub struct RpcCache {
+    dir: PathBuf,
+    max_size: u64,
+    index: Mutex<CacheIndex>,
+}
+
+#[derive(Debug, Default)]
+struct CacheIndex {
+    entries: HashMap<PathBuf, CacheEntry>,
+    /// The header file of every (network, block number) pair.
+    headers: HashMap<(String, u64), PathBuf>,
+    total_size: u64,
+    /// Incremented on every access to keep track of the least recently used entries.
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+    header: Option<(String, u64)>,
+}
+
+impl RpcCache {
+    /// Opens the cache in `dir`, picking up the entries written by earlier runs.
+    pub fn open(dir: &Path, max_size: u64) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("could not create {dir:?}"))?;
+        let mut files = Vec::new();
+        scan(dir, &mut files)?;
+        // Files are touched when they are used, so the modification time gives the order
+        // in which they were last used.
+        files.sort_by_key(|(_, _, modified)| *modified);
+
+        let mut index = CacheIndex::default();
+        for (path, size, _) in files {
+            let path = path.strip_prefix(dir)?.to_path_buf();
+            let header = parse_header_path(&path);
+            if let Some(key) = &header {
+                index.headers.insert(key.clone(), path.clone());
+            }
+            index.clock += 1;
+            index.total_size += size;
+            index.entries.insert(
+                path,
+                CacheEntry {
+                    size,
+                    last_used: index.clock,
+                    header,
+                },
+            );
+        }
+        debug!(
+            "Opened the rpc cache in {dir:?} with {} entries ({} bytes)",
+            index.entries.len(),
+            index.total_size
+        );
+
+        let cache = Self {
+            dir: dir.to_path_buf(),
+            max_size,
+            index: Mutex::new(index),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    /// Returns the block with the given number and hash on `network` without its
+    /// transactions. A cached block with the same number but another hash isn't returned.
+    pub fn get_header(&self, network: &str, number: u64, hash: B256) -> Option<Block> {
+        let path = header_path(network, number, hash);
+        let cached = self
+            .index
+            .lock()
+            .unwrap()
+            .headers
+            .get(&(network.to_string(), number))
+            .is_some_and(|cached| *cached == path);
+        if !cached {
+            inc_rpc_cache_miss("header");
+            return None;
+        }
+        self.get_json(&path, "header")
+    }
+
+    /// Stores a block without its transactions, replacing any other block with the same
+    /// number.
+    pub fn put_header(&self, network: &str, block: &Block) {
+        let (Some(number), Some(hash)) = (block.header.number, block.header.hash) else {
+            return;
+        };
+        let Ok(number) = u64::try_from(number) else {
+            return;
+        };
+        self.remove_header(network, number);
+        self.put_json(header_path(network, number, hash), block);
+    }
+
+    /// Removes the block with the given number, e.g. because it was reorged out.
+    pub fn remove_header(&self, network: &str, number: u64) {
+        let path = self
+            .index
+            .lock()
+            .unwrap()
+            .headers
+            .get(&(network.to_string(), number))
+            .cloned();
+        if let Some(path) = path {
+            self.remove(&path);
+        }
+    }
+
+    /// Returns the contract code with the given hash.
+    pub fn get_code(&self, code_hash: B256) -> Option<Bytes> {
+        self.get(&code_path(code_hash), "code").map(Bytes::from)
+    }
+
+    /// Stores contract code under its hash.
+    pub fn put_code(&self, code: &Bytes) {
+        self.put(code_path(keccak256(code)), code);
+    }
+
+    /// Returns the `eth_getProof` response for the given slots of `address` at the block
+    /// with hash `block_hash`.
+    pub fn get_proof<T: DeserializeOwned>(
+        &self,
+        network: &str,
+        block_hash: B256,
+        address: Address,
+        slots: &[B256],
+    ) -> Option<T> {
+        self.get_json(&proof_path(network, block_hash, address, slots), "proof")
+    }
+
+    /// Stores the `eth_getProof` response for the given slots of `address` at the block with
+    /// hash `block_hash`.
+    pub fn put_proof<T: Serialize>(
+        &self,
+        network: &str,
+        block_hash: B256,
+        address: Address,
+        slots: &[B256],
+        proof: &T,
+    ) {
+        self.put_json(proof_path(network, block_hash, address, slots), proof);
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, path: &Path, kind: &str) -> Option<T> {
+        let data = self.get(path, kind)?;
+        match serde_json::from_slice(&data) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("Removing unreadable rpc cache entry {path:?}: {e}");
+                self.remove(path);
+                None
+            }
+        }
+    }
+
+    fn put_json<T: Serialize>(&self, path: PathBuf, value: &T) {
+        match serde_json::to_vec(value) {
+            Ok(data) => self.put(path, &data),
+            Err(e) => warn!("Could not serialize rpc cache entry {path:?}: {e}"),
+        }
+    }
+
+    fn get(&self, path: &Path, kind: &str) -> Option<Vec<u8>> {
+        {
+            let mut index = self.index.lock().unwrap();
+            index.clock += 1;
+            let clock = index.clock;
+            match index.entries.get_mut(path) {
+                Some(entry) => entry.last_used = clock,
+                None => {
+                    inc_rpc_cache_miss(kind);
+                    return None;
+                }
+            }
+        }
+
+        let full_path = self.dir.join(path);
+        match fs::read(&full_path) {
+            Ok(data) => {
+                inc_rpc_cache_hit(kind);
+                // Keep the order of use for the next time the cache is opened.
+                if let Ok(file) = File::options().write(true).open(&full_path) {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+                Some(data)
+            }
+            Err(e) => {
+                warn!("Could not read rpc cache entry {path:?}: {e}");
+                inc_rpc_cache_miss(kind);
+                self.remove(path);
+                None
+            }
+        }
+    }
+
+    fn put(&self, path: PathBuf, data: &[u8]) {
+        let full_path = self.dir.join(&path);
+        if let Err(e) = write_atomic(&full_path, data) {
+            warn!("Could not write rpc cache entry {path:?}: {e}");
+            return;
+        }
+
+        {
+            let mut index = self.index.lock().unwrap();
+            let header = parse_header_path(&path);
+            if let Some(key) = &header {
+                index.headers.insert(key.clone(), path.clone());
+            }
+            index.clock += 1;
+            let entry = CacheEntry {
+                size: data.len() as u64,
+                last_used: index.clock,
+                header,
+            };
+            index.total_size += entry.size;
+            if let Some(old) = index.entries.insert(path, entry) {
+                index.total_size -= old.size;
+            }
+        }
+        self.evict();
+    }
+
+    fn remove(&self, path: &Path) {
+        let mut index = self.index.lock().unwrap();
+        index.remove(path);
+        let _ = fs::remove_file(self.dir.join(path));
+    }
+
+    /// Removes the least recently used entries until the cache is back under 90% of its
+    /// maximum size, so that evictions don't happen on every write.
+    fn evict(&self) {
+        let mut index = self.index.lock().unwrap();
+        if index.total_size <= self.max_size {
+            return;
+        }
+        let mut entries = index
+            .entries
+            .iter()
+            .map(|(path, entry)| (entry.last_used, path.clone()))
+            .collect::<Vec<_>>();
+        entries.sort();
+        let target = self.max_size / 10 * 9;
+        for (_, path) in entries {
+            if index.total_size <= target {
+                break;
+            }
+            index.remove(&path);
+            if let Err(e) = fs::remove_file(self.dir.join(&path)) {
+                warn!("Could not remove rpc cache entry {path:?}: {e}");
+            }
+        }
+    }
+}
+
+impl CacheIndex {
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.total_size -= entry.size;
+            if let Some(key) = entry.header {
+                if self.headers.get(&key).is_some_and(|p| p == path) {
+                    self.headers.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+fn code_path(code_hash: B256) -> PathBuf {
+    Path::new("code").join(code_hash.to_string())
+}
+
+fn header_path(network: &str, number: u64, hash: B256) -> PathBuf {
+    Path::new(network)
+        .join("headers")
+        .join(format!("{number}-{hash}.json"))
+}
+
+fn proof_path(network: &str, block_hash: B256, address: Address, slots: &[B256]) -> PathBuf {
+    // The slots are hashed in a fixed order so the same proof is found independent of the
+    // order in which the slots were requested.
+    let mut slots = slots.to_vec();
+    slots.sort();
+    let slots_hash = keccak256(slots.iter().flat_map(|slot| slot.0).collect::<Vec<_>>());
+    Path::new(network)
+        .join("proofs")
+        .join(block_hash.to_string())
+        .join(format!("{address}-{slots_hash}.json"))
+}
+
+/// Returns the network and block number of a header file.
+fn parse_header_path(path: &Path) -> Option<(String, u64)> {
+    let mut components = path.iter().map(|c| c.to_str());
+    let network = components.next()??;
+    if components.next()?? != "headers" {
+        return None;
+    }
+    let file_name = components.next()??;
+    let (number, _) = file_name.split_once('-')?;
+    Some((network.to_string(), number.parse().ok()?))
+}
+
+/// Collects every file below `dir` with its size and modification time. Leftovers of
+/// interrupted writes are removed.
+fn scan(dir: &Path, files: &mut Vec<(PathBuf, u64, SystemTime)>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            scan(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "tmp") {
+            let _ = fs::remove_file(&path);
+        } else {
+            files.push((path, metadata.len(), metadata.modified()?));
+        }
+    }
+    Ok(())
+}
+
+/// Writes to a temporary file first so that readers never see a partially written entry.
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::U256;
+
+    use super::*;
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("raiko-rpc-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn header(number: u64, hash: B256) -> Block {
+        let mut block = Block::default();
+        block.header.number = Some(number.try_into().unwrap());
+        block.header.hash = Some(hash);
+        block
+    }
+
+    #[test]
+    fn test_cache_persists_entries() {
+        let dir = cache_dir("persist");
+        let code = Bytes::from(vec![0x60, 0x00, 0x60, 0x00]);
+        let slots = [B256::with_last_byte(2), B256::with_last_byte(1)];
+        let (hash, other_hash) = (B256::repeat_byte(1), B256::repeat_byte(2));
+        {
+            let cache = RpcCache::open(&dir, 1 << 20).unwrap();
+            cache.put_header("taiko_a7", &header(10, hash));
+            cache.put_code(&code);
+            cache.put_proof("taiko_a7", hash, Address::ZERO, &slots, &U256::from(7));
+        }
+
+        let cache = RpcCache::open(&dir, 1 << 20).unwrap();
+        let block = cache.get_header("taiko_a7", 10, hash).unwrap();
+        assert_eq!(block.header.hash, Some(hash));
+        assert!(cache.get_header("taiko_a7", 11, hash).is_none());
+        assert!(cache.get_header("ethereum", 10, hash).is_none());
+        assert_eq!(cache.get_code(keccak256(&code)), Some(code));
+
+        // The order of the slots doesn't matter.
+        let reversed = [slots[1], slots[0]];
+        let proof: Option<U256> = cache.get_proof("taiko_a7", hash, Address::ZERO, &reversed);
+        assert_eq!(proof, Some(U256::from(7)));
+        let proof: Option<U256> = cache.get_proof("taiko_a7", hash, Address::ZERO, &slots[..1]);
+        assert_eq!(proof, None);
+
+        // A block with the same number but another hash, e.g. after a reorg, isn't served
+        // from the data of the old block.
+        assert!(cache.get_header("taiko_a7", 10, other_hash).is_none());
+        let proof: Option<U256> = cache.get_proof("taiko_a7", other_hash, Address::ZERO, &slots);
+        assert_eq!(proof, None);
+
+        // A header for the same number replaces the old one.
+        cache.put_header("taiko_a7", &header(10, other_hash));
+        assert!(cache.get_header("taiko_a7", 10, hash).is_none());
+        let block = cache.get_header("taiko_a7", 10, other_hash).unwrap();
+        assert_eq!(block.header.hash, Some(other_hash));
+        assert_eq!(
+            fs::read_dir(dir.join("taiko_a7/headers")).unwrap().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let dir = cache_dir("evict");
+        let codes = (0..4u8)
+            .map(|i| Bytes::from(vec![i; 100]))
+            .collect::<Vec<_>>();
+        let cache = RpcCache::open(&dir, 350).unwrap();
+        for code in &codes[..3] {
+            cache.put_code(code);
+        }
+        // Use the first entry so the second one is now the least recently used.
+        assert!(cache.get_code(keccak256(&codes[0])).is_some());
+        cache.put_code(&codes[3]);
+
+        assert!(cache.get_code(keccak256(&codes[0])).is_some());
+        assert!(cache.get_code(keccak256(&codes[1])).is_none());
+        assert!(cache.get_code(keccak256(&codes[2])).is_some());
+        assert!(cache.get_code(keccak256(&codes[3])).is_some());
+    }
+}
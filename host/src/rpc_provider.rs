@@ -1,173 +1,974 @@
 pub use alloy_primitives::*;
-use alloy_provider::{ProviderBuilder, ReqwestProvider, RootProvider};
-use alloy_rpc_client::{ClientBuilder, RpcClient};
-use alloy_rpc_types::{Block, BlockId, BlockNumberOrTag, EIP1186AccountProofResponse};
-use alloy_transport_http::Http;
-use anyhow::Result;
+use alloy_rpc_types::{
+    Block, BlockId, BlockNumberOrTag, EIP1186AccountProofResponse, Filter, Log, Transaction,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{stream, StreamExt, TryStreamExt};
 use raiko_lib::{clear_line, inplace_print};
 use reqwest_alloy::Client;
-use revm::primitives::{AccountInfo, Bytecode};
-use std::collections::HashMap;
+use revm::primitives::{AccountInfo, Bytecode, KECCAK_EMPTY};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+use tracing::warn;
 
-use crate::{raiko::BlockDataProvider, MerkleProof};
+use crate::{
+    metrics::{
+        inc_rpc_call, inc_rpc_endpoint_benched, inc_rpc_retry, observe_rpc_batch_size,
+        observe_rpc_latency,
+    },
+    raiko::{BlockDataProvider, TouchedState},
+    rpc_cache::RpcCache,
+    MerkleProof,
+};
+
+/// Controls how failed JSON-RPC calls are retried.
+#[derive(Clone, Debug)]
+pub struct RetryConfig {
+    /// The maximum number of times a call is sent before giving up.
+    pub max_attempts: usize,
+    /// The backoff before the first retry, doubled on every following retry.
+    pub initial_backoff: Duration,
+    /// The upper bound for the backoff.
+    pub max_backoff: Duration,
+    /// The number of consecutive failures after which an endpoint is benched.
+    pub max_failures: u32,
+    /// How long a benched endpoint is skipped while other endpoints are available.
+    pub bench_duration: Duration,
+    /// The timeout of a single HTTP request.
+    pub timeout: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 6,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(8),
+            max_failures: 3,
+            bench_duration: Duration::from_secs(60),
+            timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Controls how many JSON-RPC batches are sent at the same time and how large they are.
+#[derive(Clone, Debug)]
+pub struct DispatchConfig {
+    /// The maximum number of batches in flight per endpoint.
+    pub max_in_flight: usize,
+    /// The maximum number of batches sent to an endpoint per second, 0 for no limit.
+    pub max_requests_per_second: u32,
+    /// The maximum number of calls in a single batch. The size is halved when an endpoint
+    /// rejects a batch as too large and grows back towards this limit on success.
+    pub max_batch_size: usize,
+}
+
+impl Default for DispatchConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 4,
+            max_requests_per_second: 0,
+            max_batch_size: 1000,
+        }
+    }
+}
+
+/// An error returned by the node for a single call.
+#[derive(thiserror::Error, Debug, Clone, Deserialize)]
+#[error("rpc error {code}: {message}")]
+pub struct RpcCallError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcCallError {
+    fn missing_response() -> Self {
+        Self {
+            code: 0,
+            message: "no response for the call in the batch".to_string(),
+        }
+    }
+
+    /// Malformed requests fail the same way on every endpoint, so retrying them is pointless.
+    fn is_retryable(&self) -> bool {
+        !matches!(self.code, -32700 | -32600 | -32601 | -32602)
+    }
+}
+
+/// The endpoint rejected a batch because it contained too many calls.
+#[derive(thiserror::Error, Debug)]
+#[error("{endpoint} rejected a batch of {size} calls as too large")]
+struct BatchTooLarge {
+    endpoint: String,
+    size: usize,
+}
+
+/// A JSON-RPC endpoint together with its health and the limits on what's sent to it.
+struct Endpoint {
+    url: reqwest::Url,
+    label: String,
+    health: Mutex<EndpointHealth>,
+    in_flight: Semaphore,
+    /// The earliest time the next batch may be sent when the request rate is capped.
+    next_request: Mutex<Option<Instant>>,
+}
+
+#[derive(Default)]
+struct EndpointHealth {
+    consecutive_failures: u32,
+    benched_until: Option<Instant>,
+}
+
+impl Endpoint {
+    fn new(url: reqwest::Url, dispatch: &DispatchConfig) -> Self {
+        Self {
+            label: endpoint_label(&url),
+            url,
+            health: Mutex::default(),
+            in_flight: Semaphore::new(dispatch.max_in_flight.max(1)),
+            next_request: Mutex::default(),
+        }
+    }
+
+    /// Waits until a batch may be sent without exceeding `max_requests_per_second`.
+    async fn wait_for_rate_limit(&self, max_requests_per_second: u32) {
+        if max_requests_per_second == 0 {
+            return;
+        }
+        let interval = Duration::from_secs(1) / max_requests_per_second;
+        let slot = {
+            let now = Instant::now();
+            let mut next_request = self.next_request.lock().unwrap();
+            let slot = next_request.map_or(now, |next| next.max(now));
+            *next_request = Some(slot + interval);
+            slot
+        };
+        tokio::time::sleep_until(slot.into()).await;
+    }
+
+    fn benched_until(&self, now: Instant) -> Option<Instant> {
+        let health = self.health.lock().unwrap();
+        health.benched_until.filter(|until| *until > now)
+    }
+
+    fn record_success(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures = 0;
+        health.benched_until = None;
+    }
+
+    /// Returns true if the endpoint got benched because of this failure.
+    fn record_failure(&self, retry: &RetryConfig) -> bool {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= retry.max_failures {
+            health.consecutive_failures = 0;
+            health.benched_until = Some(Instant::now() + retry.bench_duration);
+            true
+        } else {
+            false
+        }
+    }
+}
 
 pub struct RpcBlockDataProvider {
-    pub provider: ReqwestProvider,
-    pub client: RpcClient<Http<Client>>,
+    client: Client,
+    endpoints: Vec<Endpoint>,
+    /// The endpoint requests are sent to first, which is the last one that worked.
+    current: AtomicUsize,
+    retry: RetryConfig,
+    dispatch: DispatchConfig,
+    /// The number of calls sent in a single batch, adapted to what the endpoints accept.
+    batch_size: AtomicUsize,
     block_number: u64,
+    prestate_tracer: bool,
+    /// The on-disk cache for immutable data and the network it's used for.
+    cache: Option<(Arc<RpcCache>, String)>,
+    /// The number of calls sent to the endpoints, retries included.
+    rpc_calls: AtomicU64,
 }
 
 impl RpcBlockDataProvider {
-    pub fn new(url: &str, block_number: u64) -> Self {
-        let url = reqwest::Url::parse(&url).expect("invalid rpc url");
+    /// Creates a provider from a comma separated list of RPC urls. The first url is used
+    /// until it fails repeatedly, the others are fallbacks.
+    pub fn new(urls: &str, block_number: u64) -> Self {
+        Self::with_retry_config(urls, block_number, RetryConfig::default())
+    }
+
+    pub fn with_retry_config(urls: &str, block_number: u64, retry: RetryConfig) -> Self {
+        let endpoints = parse_endpoints(urls).expect("invalid rpc url");
+        let dispatch = DispatchConfig::default();
         Self {
-            provider: ProviderBuilder::new().on_provider(RootProvider::new_http(url.clone())),
-            client: ClientBuilder::default().http(url),
+            client: Client::builder()
+                .timeout(retry.timeout)
+                .build()
+                .expect("could not create the http client"),
+            endpoints: endpoints
+                .into_iter()
+                .map(|url| Endpoint::new(url, &dispatch))
+                .collect(),
+            current: AtomicUsize::new(0),
+            retry,
+            batch_size: AtomicUsize::new(dispatch.max_batch_size),
+            dispatch,
             block_number,
+            prestate_tracer: false,
+            cache: None,
+            rpc_calls: AtomicU64::new(0),
         }
     }
 
-    pub fn provider(&self) -> &ReqwestProvider {
-        &self.provider
+    /// Collect the state touched by a block with a single `debug_traceBlockByNumber` call
+    /// using the `prestateTracer`. Requires the node to expose the `debug` namespace.
+    pub fn with_prestate_tracer(mut self, enabled: bool) -> Self {
+        self.prestate_tracer = enabled;
+        self
     }
-}
 
-impl BlockDataProvider for RpcBlockDataProvider {
-    async fn get_blocks(
-        &self,
-        blocks_to_fetch: &[(u64, bool)],
-    ) -> Result<Vec<Block>, anyhow::Error> {
-        let mut all_blocks = Vec::new();
+    /// Limit the number of batches in flight and their rate and size.
+    pub fn with_dispatch_config(mut self, dispatch: DispatchConfig) -> Self {
+        self.endpoints = self
+            .endpoints
+            .iter()
+            .map(|endpoint| Endpoint::new(endpoint.url.clone(), &dispatch))
+            .collect();
+        self.batch_size = AtomicUsize::new(dispatch.max_batch_size.max(1));
+        self.dispatch = dispatch;
+        self
+    }
+
+    /// Look up headers, contract code and proofs in `cache` before asking the node, and store
+    /// what was fetched for later requests. Entries are kept apart per `network`.
+    pub fn with_cache(mut self, cache: Arc<RpcCache>, network: &str) -> Self {
+        self.cache = Some((cache, network.to_string()));
+        self
+    }
+
+    /// Fetches the chain id and the latest block number of the node in a single batch.
+    pub async fn get_chain_id_and_head(&self) -> Result<(u64, u64)> {
+        let values: Vec<U64> = self
+            .call_batch(&[("eth_chainId", json!([])), ("eth_blockNumber", json!([]))])
+            .await?;
+        Ok((values[0].to(), values[1].to()))
+    }
 
+    /// Returns the number of the block with the given hash, or `None` if the node doesn't
+    /// know the block.
+    pub async fn get_block_number_by_hash(&self, block_hash: B256) -> Result<Option<u64>> {
+        let blocks: Vec<Option<Block>> = self
+            .call_batch(&[("eth_getBlockByHash", json!([block_hash, false]))])
+            .await?;
+        Ok(blocks
+            .into_iter()
+            .next()
+            .flatten()
+            .and_then(|block| block.header.number)
+            .and_then(|number| number.try_into().ok()))
+    }
+
+    async fn fetch_blocks(&self, blocks_to_fetch: &[(u64, bool)]) -> Result<Vec<Block>> {
         let max_batch_size = 32;
-        for blocks_to_fetch in blocks_to_fetch.chunks(max_batch_size) {
-            let mut batch = self.client.new_batch();
-            let mut requests = vec![];
-
-            for (block_number, full) in blocks_to_fetch.iter() {
-                requests.push(Box::pin(batch.add_call(
-                    "eth_getBlockByNumber",
-                    &(BlockNumberOrTag::from(*block_number), full),
-                )?));
+        self.dispatch_chunks(blocks_to_fetch.chunks(max_batch_size), |blocks_to_fetch| {
+            let calls = blocks_to_fetch
+                .iter()
+                .map(|(block_number, full)| {
+                    (
+                        "eth_getBlockByNumber",
+                        json!([BlockNumberOrTag::from(*block_number), full]),
+                    )
+                })
+                .collect::<Vec<_>>();
+            async move { self.call_batch(&calls).await }
+        })
+        .await
+    }
+
+    /// Returns the hash of the block with the given number on the chain of the node.
+    async fn get_block_hash(&self, block_number: u64) -> Result<B256> {
+        let blocks = self.fetch_blocks(&[(block_number, false)]).await?;
+        blocks
+            .into_iter()
+            .next()
+            .and_then(|block| block.header.hash)
+            .with_context(|| format!("node returned no hash for block {block_number}"))
+    }
+
+    /// Fetches the proofs for the given keys of the accounts in a single batch, skipping the
+    /// ones that are cached. When the cache is used the proofs are requested by `block_hash`,
+    /// the key they are cached under.
+    async fn get_proof_batch(
+        &self,
+        block_number: u64,
+        block_hash: Option<B256>,
+        batch: Vec<(Address, Vec<StorageKey>)>,
+    ) -> Result<Vec<EIP1186AccountProofResponse>> {
+        let cache = self.cache.as_ref().zip(block_hash);
+        let block_id = block_hash.map_or(BlockId::from(block_number), BlockId::from);
+        let mut proofs = Vec::new();
+        let mut calls = Vec::new();
+        let mut requested = Vec::new();
+        for (address, keys) in batch {
+            let cached = cache.and_then(|((cache, network), block_hash)| {
+                cache.get_proof(network, block_hash, address, &keys)
+            });
+            match cached {
+                Some(proof) => proofs.push(proof),
+                None => {
+                    calls.push(("eth_getProof", json!([address, keys, block_id])));
+                    requested.push((address, keys));
+                }
+            }
+        }
+
+        if !calls.is_empty() {
+            let values = self.send_batch(&calls).await?;
+            for ((address, keys), value) in requested.into_iter().zip(values) {
+                if let Some(((cache, network), block_hash)) = cache {
+                    cache.put_proof(network, block_hash, address, &keys, &value);
+                }
+                proofs.push(serde_json::from_value(value)?);
             }
+        }
+        Ok(proofs)
+    }
 
-            batch.send().await?;
+    /// Runs `fetch` for every chunk, with up to `max_in_flight` chunks at the same time, and
+    /// concatenates the results in the order of the chunks.
+    async fn dispatch_chunks<C, T, F, Fut>(
+        &self,
+        chunks: impl Iterator<Item = C>,
+        fetch: F,
+    ) -> Result<Vec<T>>
+    where
+        F: FnMut(C) -> Fut,
+        Fut: Future<Output = Result<Vec<T>>>,
+    {
+        stream::iter(chunks)
+            .map(fetch)
+            .buffered(self.dispatch.max_in_flight.max(1))
+            .try_concat()
+            .await
+    }
 
-            let mut blocks = vec![];
-            // Collect the data from the batch
-            for request in requests.into_iter() {
-                blocks.push(request.await?);
+    /// Fetches the blocks at the `missing` positions and adds the headers to the cache.
+    async fn fetch_missing_blocks(
+        &self,
+        blocks_to_fetch: &[(u64, bool)],
+        blocks: &mut [Option<Block>],
+        missing: Vec<usize>,
+        cache: &RpcCache,
+        network: &str,
+    ) -> Result<()> {
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let fetched = self
+            .fetch_blocks(
+                &missing
+                    .iter()
+                    .map(|i| blocks_to_fetch[*i])
+                    .collect::<Vec<_>>(),
+            )
+            .await?;
+        for (i, block) in missing.into_iter().zip(fetched) {
+            if !blocks_to_fetch[i].1 {
+                cache.put_header(network, &block);
             }
+            blocks[i] = Some(block);
+        }
+        Ok(())
+    }
 
-            all_blocks.append(&mut blocks);
+    async fn get_accounts_uncached(&self, accounts: &[Address]) -> Result<Vec<AccountInfo>> {
+        let block_id = BlockId::from(self.block_number);
+        let mut calls = Vec::new();
+        for address in accounts {
+            calls.push(("eth_getTransactionCount", json!([address, block_id])));
+            calls.push(("eth_getBalance", json!([address, block_id])));
+            calls.push(("eth_getCode", json!([address, block_id])));
         }
 
-        Ok(all_blocks)
+        let values = self.send_batch(&calls).await?;
+
+        let mut accounts = vec![];
+        // Collect the data from the batch
+        for values in values.chunks(3) {
+            let nonce: Uint<64, 1> =
+                serde_json::from_value(values[0].clone()).context("invalid nonce")?;
+            let balance: Uint<256, 4> =
+                serde_json::from_value(values[1].clone()).context("invalid balance")?;
+            let code: Bytes = serde_json::from_value(values[2].clone()).context("invalid code")?;
+
+            let account_info = AccountInfo::new(
+                balance,
+                nonce.to(),
+                Bytecode::new_raw(code.clone()).hash_slow(),
+                Bytecode::new_raw(code),
+            );
+
+            accounts.push(account_info);
+        }
+
+        Ok(accounts)
     }
 
-    async fn get_accounts(&self, accounts: &[Address]) -> Result<Vec<AccountInfo>, anyhow::Error> {
-        let mut all_accounts = Vec::new();
+    /// Fetches the accounts with `eth_getProof`, which includes the code hash, so the code
+    /// only needs to be fetched when it's not in the cache yet.
+    async fn get_accounts_cached(
+        &self,
+        accounts: &[Address],
+        block_hash: B256,
+        cache: &RpcCache,
+        network: &str,
+    ) -> Result<Vec<AccountInfo>> {
+        let block_id = BlockId::from(block_hash);
+        let no_slots: [StorageKey; 0] = [];
+        let calls = accounts
+            .iter()
+            .map(|address| ("eth_getProof", json!([address, no_slots, block_id])))
+            .collect::<Vec<_>>();
+        let proofs = self.send_batch(&calls).await?;
 
-        let max_batch_size = 250;
-        for accounts in accounts.chunks(max_batch_size) {
-            let mut batch = self.client.new_batch();
-
-            let mut nonce_requests = Vec::new();
-            let mut balance_requests = Vec::new();
-            let mut code_requests = Vec::new();
-
-            for address in accounts {
-                nonce_requests.push(Box::pin(
-                    batch
-                        .add_call::<_, Uint<64, 1>>(
-                            "eth_getTransactionCount",
-                            &(address, Some(BlockId::from(self.block_number))),
-                        )
-                        .unwrap(),
-                ));
-                balance_requests.push(Box::pin(
-                    batch
-                        .add_call::<_, Uint<256, 4>>(
-                            "eth_getBalance",
-                            &(address, Some(BlockId::from(self.block_number))),
-                        )
-                        .unwrap(),
-                ));
-                code_requests.push(Box::pin(
-                    batch
-                        .add_call::<_, Bytes>(
-                            "eth_getCode",
-                            &(address, Some(BlockId::from(self.block_number))),
-                        )
-                        .unwrap(),
-                ));
+        let mut account_proofs = Vec::new();
+        for (address, proof) in accounts.iter().zip(proofs) {
+            let mut account: AccountProof = serde_json::from_value(proof.clone())?;
+            // Some nodes return a zero code hash for accounts that don't exist.
+            if account.code_hash.is_zero() {
+                account.code_hash = KECCAK_EMPTY;
             }
+            // The same proof is needed again for the accounts without any storage reads.
+            cache.put_proof(network, block_hash, *address, &[], &proof);
+            account_proofs.push(account);
+        }
 
-            batch.send().await?;
+        // Fetch the code that's not cached yet, in a single batch.
+        let mut code = HashMap::new();
+        let mut missing = Vec::new();
+        for (address, account) in accounts.iter().zip(&account_proofs) {
+            if account.code_hash == KECCAK_EMPTY || code.contains_key(&account.code_hash) {
+                continue;
+            }
+            match cache.get_code(account.code_hash) {
+                Some(cached) => {
+                    code.insert(account.code_hash, cached);
+                }
+                None => missing.push((*address, account.code_hash)),
+            }
+        }
+        if !missing.is_empty() {
+            let calls = missing
+                .iter()
+                .map(|(address, _)| ("eth_getCode", json!([address, block_id])))
+                .collect::<Vec<_>>();
+            let fetched: Vec<Bytes> = self.call_batch(&calls).await?;
+            for ((address, code_hash), fetched) in missing.into_iter().zip(fetched) {
+                if keccak256(&fetched) != code_hash {
+                    bail!("code of {address} doesn't match its code hash {code_hash}");
+                }
+                cache.put_code(&fetched);
+                code.insert(code_hash, fetched);
+            }
+        }
 
-            let mut accounts = vec![];
-            // Collect the data from the batch
-            for (nonce_request, (balance_request, code_request)) in nonce_requests
-                .into_iter()
-                .zip(balance_requests.into_iter().zip(code_requests.into_iter()))
-            {
-                let (nonce, balance, code) = (
-                    nonce_request.await?,
-                    balance_request.await?,
-                    code_request.await?,
-                );
+        Ok(account_proofs
+            .into_iter()
+            .map(|account| {
+                let bytecode = match code.get(&account.code_hash) {
+                    Some(code) => Bytecode::new_raw(code.clone()),
+                    None => Bytecode::new(),
+                };
+                AccountInfo::new(
+                    account.balance,
+                    account.nonce.to(),
+                    account.code_hash,
+                    bytecode,
+                )
+            })
+            .collect())
+    }
+
+    /// Sends the calls with [`Self::send_batch`] and deserializes the results.
+    async fn call_batch<T: DeserializeOwned>(
+        &self,
+        calls: &[(&'static str, Value)],
+    ) -> Result<Vec<T>> {
+        self.send_batch(calls)
+            .await?
+            .into_iter()
+            .map(|value| serde_json::from_value(value).map_err(Into::into))
+            .collect()
+    }
+
+    /// Sends the calls in JSON-RPC batches and returns the results in the same order. The
+    /// batches are sent concurrently and are split up further when the endpoint rejects them
+    /// as too large.
+    ///
+    /// Failed calls are retried with exponential backoff. An endpoint that fails
+    /// `max_failures` times in a row is benched for `bench_duration` and the calls fail over
+    /// to the next endpoint.
+    async fn send_batch(&self, calls: &[(&'static str, Value)]) -> Result<Vec<Value>> {
+        let mut results = vec![None; calls.len()];
+        let mut pending = (0..calls.len()).collect::<Vec<_>>();
+        let mut backoff = self.retry.initial_backoff;
+        let mut last_error = None;
+
+        let mut attempt = 0;
+        while !pending.is_empty() && attempt < self.retry.max_attempts {
+            let index = self.select_endpoint();
+            let endpoint = &self.endpoints[index];
 
-                let account_info = AccountInfo::new(
-                    balance,
-                    nonce.try_into().unwrap(),
-                    Bytecode::new_raw(code.clone()).hash_slow(),
-                    Bytecode::new_raw(code),
+            // Split the calls over batches the endpoint accepts and send them concurrently.
+            let batch_size = self.batch_size.load(Ordering::Relaxed);
+            let outcomes = stream::iter(pending.chunks(batch_size))
+                .map(|ids| async move { (ids, self.post_batch(endpoint, calls, ids).await) })
+                .buffer_unordered(self.dispatch.max_in_flight.max(1))
+                .collect::<Vec<_>>()
+                .await;
+
+            let mut failed = Vec::new();
+            let mut endpoint_failed = false;
+            let mut too_large = false;
+            for (ids, outcome) in outcomes {
+                let responses = match outcome {
+                    Ok(responses) => responses,
+                    Err(e) if e.is::<BatchTooLarge>() && batch_size > 1 => {
+                        too_large = true;
+                        failed.extend_from_slice(ids);
+                        last_error = Some(e);
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("RPC batch to {} failed: {e:#}", endpoint.label);
+                        endpoint_failed = true;
+                        failed.extend_from_slice(ids);
+                        last_error = Some(e);
+                        continue;
+                    }
+                };
+                for (&id, response) in ids.iter().zip(responses) {
+                    match response.and_then(|value| check_result(calls[id].0, value)) {
+                        Ok(value) => results[id] = Some(value),
+                        Err(e) if !e.is_retryable() => {
+                            return Err(e).context(format!("{} failed", calls[id].0));
+                        }
+                        Err(e) => {
+                            failed.push(id);
+                            last_error = Some(anyhow::Error::new(e).context(calls[id].0));
+                        }
+                    }
+                }
+            }
+
+            if too_large {
+                // Oversized batches are split up and sent again right away, without counting
+                // against the endpoint's health or the retry attempts.
+                self.shrink_batch_size(batch_size, &endpoint.label);
+                if !endpoint_failed && failed.len() == pending.len() {
+                    pending = failed;
+                    continue;
+                }
+            }
+            if failed.is_empty() {
+                endpoint.record_success();
+                self.current.store(index, Ordering::Relaxed);
+                self.grow_batch_size();
+            } else if !too_large || endpoint_failed {
+                warn!(
+                    "{} of {} RPC calls to {} failed",
+                    failed.len(),
+                    results.len(),
+                    endpoint.label
                 );
+                self.record_failure(index);
+            }
+            pending = failed;
 
-                accounts.push(account_info);
+            attempt += 1;
+            if !pending.is_empty() && attempt < self.retry.max_attempts {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(self.retry.max_backoff);
             }
+        }
 
-            all_accounts.append(&mut accounts);
+        if !pending.is_empty() {
+            let e = last_error.unwrap_or_else(|| anyhow!("no attempts made"));
+            return Err(e.context(format!(
+                "{} RPC calls still failing after {} attempts",
+                pending.len(),
+                self.retry.max_attempts
+            )));
         }
+        Ok(results.into_iter().flatten().collect())
+    }
 
-        Ok(all_accounts)
+    /// Halves the batch size after a batch of `batch_size` calls was rejected as too large.
+    fn shrink_batch_size(&self, batch_size: usize, endpoint: &str) {
+        let smaller = (batch_size / 2).max(1);
+        // Concurrent rejections of the same size only shrink the batch size once.
+        if self
+            .batch_size
+            .compare_exchange(batch_size, smaller, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            warn!("{endpoint} rejected a batch of {batch_size} calls, sending at most {smaller}");
+        }
     }
 
-    async fn get_storage_values(
+    /// Grows the batch size back towards the configured maximum after a successful batch.
+    fn grow_batch_size(&self) {
+        let max_batch_size = self.dispatch.max_batch_size.max(1);
+        let _ = self
+            .batch_size
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |batch_size| {
+                (batch_size < max_batch_size)
+                    .then(|| (batch_size + batch_size / 4 + 1).min(max_batch_size))
+            });
+    }
+
+    /// Picks the first endpoint that isn't benched, starting at the current one. When all
+    /// endpoints are benched the one that gets unbenched first is used.
+    fn select_endpoint(&self) -> usize {
+        let now = Instant::now();
+        let num_endpoints = self.endpoints.len();
+        let current = self.current.load(Ordering::Relaxed);
+        let candidates = (0..num_endpoints).map(|offset| (current + offset) % num_endpoints);
+        candidates
+            .clone()
+            .find(|&index| self.endpoints[index].benched_until(now).is_none())
+            .or_else(|| candidates.min_by_key(|&index| self.endpoints[index].benched_until(now)))
+            .unwrap_or(current)
+    }
+
+    fn record_failure(&self, index: usize) {
+        let endpoint = &self.endpoints[index];
+        inc_rpc_retry(&endpoint.label);
+        if endpoint.record_failure(&self.retry) {
+            warn!(
+                "Benching RPC endpoint {} for {:?}",
+                endpoint.label, self.retry.bench_duration
+            );
+            inc_rpc_endpoint_benched(&endpoint.label);
+            // Make the next endpoint the preferred one so we don't come back to this one
+            // as soon as the bench expires.
+            let _ = self.current.compare_exchange(
+                index,
+                (index + 1) % self.endpoints.len(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Sends the calls with the given ids to the endpoint and returns their responses in the
+    /// order of `ids`. Only fails when the batch as a whole was rejected.
+    async fn post_batch(
         &self,
-        accounts: &[(Address, U256)],
-    ) -> Result<Vec<U256>, anyhow::Error> {
-        let mut all_values = Vec::new();
+        endpoint: &Endpoint,
+        calls: &[(&'static str, Value)],
+        ids: &[usize],
+    ) -> Result<Vec<Result<Value, RpcCallError>>> {
+        let requests = ids
+            .iter()
+            .map(|&id| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "method": calls[id].0,
+                    "params": calls[id].1,
+                })
+            })
+            .collect::<Vec<_>>();
 
-        let max_batch_size = 1000;
-        for accounts in accounts.chunks(max_batch_size) {
-            let mut batch = self.client.new_batch();
-
-            let mut requests = Vec::new();
-
-            for (address, key) in accounts {
-                requests.push(Box::pin(
-                    batch
-                        .add_call::<_, U256>(
-                            "eth_getStorageAt",
-                            &(address, key, Some(BlockId::from(self.block_number))),
-                        )
-                        .unwrap(),
-                ));
+        let mut methods = Vec::<(&str, usize)>::new();
+        for &id in ids {
+            match methods
+                .iter_mut()
+                .find(|(method, _)| *method == calls[id].0)
+            {
+                Some((_, count)) => *count += 1,
+                None => methods.push((calls[id].0, 1)),
             }
+        }
+
+        let _permit = endpoint.in_flight.acquire().await?;
+        self.rpc_calls
+            .fetch_add(ids.len() as u64, Ordering::Relaxed);
+        endpoint
+            .wait_for_rate_limit(self.dispatch.max_requests_per_second)
+            .await;
+        let start = Instant::now();
+        let result = self.post(endpoint, &requests).await;
+        self.observe_batch(endpoint, &methods, start, result.is_ok());
+        let mut responses = result?;
 
-            batch.send().await?;
+        Ok(ids
+            .iter()
+            .map(|&id| {
+                let response = responses
+                    .remove(&id)
+                    .unwrap_or_else(|| Err(RpcCallError::missing_response()));
+                inc_rpc_call(calls[id].0, &endpoint.label, response.is_ok());
+                response
+            })
+            .collect())
+    }
+
+    async fn post(
+        &self,
+        endpoint: &Endpoint,
+        requests: &[Value],
+    ) -> Result<HashMap<usize, Result<Value, RpcCallError>>> {
+        let response = self
+            .client
+            .post(endpoint.url.clone())
+            .json(requests)
+            .send()
+            .await?;
+        let status = response.status();
+        let too_large = BatchTooLarge {
+            endpoint: endpoint.label.clone(),
+            size: requests.len(),
+        };
+        if status == reqwest_alloy::StatusCode::PAYLOAD_TOO_LARGE {
+            return Err(too_large.into());
+        }
+        if !status.is_success() {
+            bail!("{} responded with status {status}", endpoint.label);
+        }
+
+        let responses = match response.json::<Value>().await? {
+            Value::Array(responses) => responses,
+            // Nodes reply with a single error object when they reject the batch as a whole
+            response if is_batch_limit_error(&response.to_string()) => return Err(too_large.into()),
+            response => bail!("{} rejected the batch: {response}", endpoint.label),
+        };
+        Ok(responses
+            .into_iter()
+            .filter_map(|mut response| {
+                let id = response.get("id")?.as_u64()? as usize;
+                let result = match response.get("error") {
+                    Some(error) => {
+                        Err(serde_json::from_value(error.clone()).unwrap_or_else(|_| {
+                            RpcCallError {
+                                code: 0,
+                                message: error.to_string(),
+                            }
+                        }))
+                    }
+                    None => Ok(response
+                        .get_mut("result")
+                        .map(Value::take)
+                        .unwrap_or_default()),
+                };
+                Some((id, result))
+            })
+            .collect())
+    }
 
-            let mut values = vec![];
-            // Collect the data from the batch
-            for request in requests.into_iter() {
-                values.push(request.await?);
+    /// Records the outcome of sending a batch in the metrics. `methods` contains the number
+    /// of calls in the batch for each method.
+    fn observe_batch(
+        &self,
+        endpoint: &Endpoint,
+        methods: &[(&str, usize)],
+        start: Instant,
+        success: bool,
+    ) {
+        let time = start.elapsed().as_millis();
+        for (method, size) in methods {
+            observe_rpc_batch_size(method, &endpoint.label, *size);
+            observe_rpc_latency(method, &endpoint.label, time, success);
+            if !success {
+                for _ in 0..*size {
+                    inc_rpc_call(method, &endpoint.label, false);
+                }
             }
+        }
+    }
+}
+
+/// The result of tracing a single transaction with the `prestateTracer`.
+#[derive(Deserialize)]
+struct PrestateTrace {
+    result: HashMap<Address, PrestateAccount>,
+}
+
+/// An account as returned by the `prestateTracer`, which leaves out empty fields.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PrestateAccount {
+    balance: U256,
+    nonce: u64,
+    code: Bytes,
+    storage: HashMap<B256, B256>,
+}
 
-            all_values.append(&mut values);
+/// The account fields of an `eth_getProof` response.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AccountProof {
+    balance: U256,
+    nonce: U64,
+    code_hash: B256,
+}
+
+/// Parses a comma separated list of RPC urls.
+pub fn parse_endpoints(urls: &str) -> Result<Vec<reqwest::Url>> {
+    let endpoints = urls
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| reqwest::Url::parse(url).with_context(|| format!("invalid url {url}")))
+        .collect::<Result<Vec<_>>>()?;
+    if endpoints.is_empty() {
+        bail!("no rpc url given");
+    }
+    Ok(endpoints)
+}
+
+/// Returns the label used to identify an endpoint in the metrics (host and port only, so
+/// that API keys in the path or query are never exported).
+pub(crate) fn endpoint_label(url: &reqwest::Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    }
+}
+
+/// Checks that the result of an account call can be decoded. A malformed result is treated
+/// like a failed call, so it's retried and fails over to the next endpoint.
+fn check_result(method: &str, value: Value) -> Result<Value, RpcCallError> {
+    let decoded = match method {
+        "eth_getTransactionCount" => serde_json::from_value::<U64>(value.clone()).map(drop),
+        "eth_getBalance" => serde_json::from_value::<U256>(value.clone()).map(drop),
+        "eth_getCode" => serde_json::from_value::<Bytes>(value.clone()).map(drop),
+        _ => Ok(()),
+    };
+    decoded.map(|()| value).map_err(|e| RpcCallError {
+        code: 0,
+        message: format!("malformed result: {e}"),
+    })
+}
+
+/// Recognizes the messages nodes use to reject a batch for containing too many calls, e.g.
+/// "batch too large" or "batch size limit exceeded".
+fn is_batch_limit_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("batch")
+        && ["too large", "too many", "limit", "exceed"]
+            .iter()
+            .any(|reason| message.contains(reason))
+}
+
+/// Returns for every requested block without its transactions the position of the requested
+/// block right after it. The parent hash of that block is the hash of the cached header to use.
+fn child_positions(blocks_to_fetch: &[(u64, bool)]) -> Vec<Option<usize>> {
+    let positions = blocks_to_fetch
+        .iter()
+        .enumerate()
+        .map(|(i, (block_number, _))| (*block_number, i))
+        .collect::<HashMap<_, _>>();
+    blocks_to_fetch
+        .iter()
+        .map(|(block_number, full)| {
+            let child = block_number.checked_add(1)?;
+            (!full).then(|| positions.get(&child).copied()).flatten()
+        })
+        .collect()
+}
+
+impl BlockDataProvider for RpcBlockDataProvider {
+    fn rpc_calls(&self) -> u64 {
+        self.rpc_calls.load(Ordering::Relaxed)
+    }
+
+    async fn get_blocks(
+        &self,
+        blocks_to_fetch: &[(u64, bool)],
+    ) -> Result<Vec<Block>, anyhow::Error> {
+        let Some((cache, network)) = &self.cache else {
+            return self.fetch_blocks(blocks_to_fetch).await;
+        };
+
+        // Only blocks without their transactions are cached, and only used when their hash is
+        // known from their child, so a header that was reorged out is never returned. The
+        // blocks without a requested child are fetched first.
+        let children = child_positions(blocks_to_fetch);
+        let mut blocks = vec![None; blocks_to_fetch.len()];
+        let without_child = (0..blocks.len())
+            .filter(|i| children[*i].is_none())
+            .collect::<Vec<_>>();
+        self.fetch_missing_blocks(blocks_to_fetch, &mut blocks, without_child, cache, network)
+            .await?;
+
+        // Going down from the highest block, every cached header found gives the hash of the
+        // header below it.
+        let mut with_child = (0..blocks.len())
+            .filter(|i| children[*i].is_some())
+            .collect::<Vec<_>>();
+        with_child.sort_by_key(|i| std::cmp::Reverse(blocks_to_fetch[*i].0));
+        for i in with_child {
+            let parent_hash = children[i]
+                .and_then(|child| blocks[child].as_ref())
+                .map(|child| child.header.parent_hash);
+            if let Some(hash) = parent_hash {
+                blocks[i] = cache.get_header(network, blocks_to_fetch[i].0, hash);
+            }
         }
 
-        Ok(all_values)
+        let missing = (0..blocks.len())
+            .filter(|i| blocks[*i].is_none())
+            .collect::<Vec<_>>();
+        self.fetch_missing_blocks(blocks_to_fetch, &mut blocks, missing, cache, network)
+            .await?;
+
+        Ok(blocks.into_iter().flatten().collect())
+    }
+
+    async fn get_accounts(&self, accounts: &[Address]) -> Result<Vec<AccountInfo>, anyhow::Error> {
+        let max_batch_size = 250;
+        // The proofs are cached under the block hash, which is looked up once for all chunks.
+        let block_hash = match &self.cache {
+            Some(_) => Some(self.get_block_hash(self.block_number).await?),
+            None => None,
+        };
+        self.dispatch_chunks(accounts.chunks(max_batch_size), |accounts| async move {
+            match (&self.cache, block_hash) {
+                (Some((cache, network)), Some(block_hash)) => {
+                    self.get_accounts_cached(accounts, block_hash, cache, network)
+                        .await
+                }
+                _ => self.get_accounts_uncached(accounts).await,
+            }
+        })
+        .await
+    }
+
+    async fn get_storage_values(
+        &self,
+        accounts: &[(Address, U256)],
+    ) -> Result<Vec<U256>, anyhow::Error> {
+        let max_batch_size = 1000;
+        self.dispatch_chunks(accounts.chunks(max_batch_size), |accounts| {
+            let block_id = BlockId::from(self.block_number);
+            let calls = accounts
+                .iter()
+                .map(|(address, key)| ("eth_getStorageAt", json!([address, key, block_id])))
+                .collect::<Vec<_>>();
+            async move { self.call_batch(&calls).await }
+        })
+        .await
     }
 
     async fn get_merkle_proofs(
@@ -177,86 +978,441 @@ impl BlockDataProvider for RpcBlockDataProvider {
         offset: usize,
         num_storage_proofs: usize,
     ) -> Result<MerkleProof, anyhow::Error> {
-        let mut storage_proofs: MerkleProof = HashMap::new();
-        let mut idx = offset;
-
-        let mut accounts = accounts.clone();
-
+        // Split the proofs over batches of up to `batch_limit` proofs. The keys of accounts
+        // with many storage slots are split over multiple calls.
         let batch_limit = 1000;
-        while !accounts.is_empty() {
-            inplace_print(&format!(
-                "fetching storage proof {idx}/{num_storage_proofs}..."
-            ));
-
-            // Create a batch for all storage proofs
-            let mut batch = self.client.new_batch();
-
-            // Collect all requests
-            let mut requests = Vec::new();
-
-            let mut batch_size = 0;
-            while !accounts.is_empty() && batch_size < batch_limit {
-                let mut address_to_remove = None;
-                if let Some((address, keys)) = accounts.iter_mut().next() {
-                    // Calculate how many keys we can still process
-                    let num_keys_to_process = if batch_size + keys.len() < batch_limit {
-                        keys.len()
-                    } else {
-                        batch_limit - batch_size
-                    };
-
-                    // If we can process all keys, remove the address from the map after the loop
-                    if num_keys_to_process == keys.len() {
-                        address_to_remove = Some(address.clone());
-                    }
+        let mut batches = Vec::new();
+        let mut batch = Vec::new();
+        let mut batch_size = 0;
+        for (address, keys) in accounts {
+            let mut keys = keys.into_iter().map(StorageKey::from).collect::<Vec<_>>();
+            loop {
+                // Every call also includes the proof of the account itself
+                let num_keys_to_process = keys.len().min(batch_limit - batch_size - 1);
+                let remaining_keys = keys.split_off(num_keys_to_process);
+                batch_size += 1 + keys.len();
+                batch.push((address, keys));
+                if batch_size + 1 >= batch_limit {
+                    batches.push(std::mem::take(&mut batch));
+                    batch_size = 0;
+                }
+                if remaining_keys.is_empty() {
+                    break;
+                }
+                keys = remaining_keys;
+            }
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
 
-                    // Extract the keys to process
-                    let keys_to_process = keys
-                        .drain(0..num_keys_to_process)
-                        .map(|v| StorageKey::from(v))
-                        .collect::<Vec<_>>();
-
-                    // Add the request
-                    requests.push(Box::pin(
-                        batch
-                            .add_call::<_, EIP1186AccountProofResponse>(
-                                "eth_getProof",
-                                &(
-                                    address.clone(),
-                                    keys_to_process.clone(),
-                                    BlockId::from(block_number),
-                                ),
-                            )
-                            .unwrap(),
+        let block_hash = match &self.cache {
+            Some(_) => Some(self.get_block_hash(block_number).await?),
+            None => None,
+        };
+        let idx = AtomicUsize::new(offset);
+        let proofs = self
+            .dispatch_chunks(batches.into_iter(), |batch| {
+                let idx = &idx;
+                async move {
+                    let proofs = self
+                        .get_proof_batch(block_number, block_hash, batch)
+                        .await?;
+                    let num_proofs = proofs.iter().map(|p| p.storage_proof.len()).sum::<usize>();
+                    let idx = idx.fetch_add(num_proofs, Ordering::Relaxed) + num_proofs;
+                    inplace_print(&format!(
+                        "fetching storage proof {idx}/{num_storage_proofs}..."
                     ));
-
-                    // Keep track of how many keys were processed
-                    // Add an additional 1 for the account proof itself
-                    batch_size += 1 + keys_to_process.len();
+                    Ok(proofs)
                 }
+            })
+            .await?;
+        clear_line();
+
+        let mut storage_proofs: MerkleProof = HashMap::new();
+        for mut proof in proofs {
+            if let Some(map_proof) = storage_proofs.get_mut(&proof.address) {
+                map_proof.storage_proof.append(&mut proof.storage_proof);
+            } else {
+                storage_proofs.insert(proof.address, proof);
+            }
+        }
 
-                // Remove the address if all keys were processed for this account
-                if let Some(address) = address_to_remove {
-                    accounts.remove(&address);
+        Ok(storage_proofs)
+    }
+
+    async fn get_touched_state(
+        &self,
+        block_number: u64,
+    ) -> Result<Option<TouchedState>, anyhow::Error> {
+        if !self.prestate_tracer {
+            return Ok(None);
+        }
+
+        let calls = [(
+            "debug_traceBlockByNumber",
+            json!([
+                BlockNumberOrTag::from(block_number),
+                { "tracer": "prestateTracer" }
+            ]),
+        )];
+        let traces: Vec<PrestateTrace> = match self.call_batch(&calls).await {
+            Ok(mut traces) => traces.remove(0),
+            Err(e) => {
+                warn!("Could not trace block {block_number}, discovering state by execution instead: {e:#}");
+                return Ok(None);
+            }
+        };
+
+        // Every trace contains the state right before its transaction, so the first time an
+        // account or slot shows up is its state before the block.
+        let mut state = TouchedState::default();
+        for trace in traces {
+            for (address, account) in trace.result {
+                state.accounts.entry(address).or_insert_with(|| {
+                    let code = Bytecode::new_raw(account.code);
+                    AccountInfo::new(account.balance, account.nonce, code.hash_slow(), code)
+                });
+                let storage = state.storage.entry(address).or_default();
+                for (key, value) in account.storage {
+                    storage
+                        .entry(U256::from_be_bytes(key.0))
+                        .or_insert(U256::from_be_bytes(value.0));
                 }
             }
+        }
+        Ok(Some(state))
+    }
+
+    async fn get_logs(&self, filter: &Filter) -> Result<Vec<Log>, anyhow::Error> {
+        let mut logs = self.call_batch(&[("eth_getLogs", json!([filter]))]).await?;
+        Ok(logs.remove(0))
+    }
+
+    async fn get_transaction_by_hash(&self, hash: B256) -> Result<Transaction, anyhow::Error> {
+        let mut txs: Vec<Option<Transaction>> = self
+            .call_batch(&[("eth_getTransactionByHash", json!([hash]))])
+            .await?;
+        txs.remove(0)
+            .ok_or_else(|| anyhow!("transaction {hash} not found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
-            // Send the batch
-            batch.send().await?;
+    use axum::{
+        extract::State,
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        routing::post,
+        Json, Router,
+    };
+    use tokio::net::TcpListener;
 
-            // Collect the data from the batch
-            for request in requests.into_iter() {
-                let mut proof = request.await?;
-                idx += proof.storage_proof.len();
-                if let Some(map_proof) = storage_proofs.get_mut(&proof.address) {
-                    map_proof.storage_proof.append(&mut proof.storage_proof);
+    use super::*;
+
+    /// How a mock node answers a request.
+    #[derive(Clone, Copy, PartialEq)]
+    enum Reply {
+        Ok,
+        /// Reject the whole batch with a 429.
+        RateLimited,
+        /// Fail only the first call in the batch.
+        CallError,
+        /// Answer every call with a value too large for a nonce.
+        Malformed,
+    }
+
+    #[derive(Clone)]
+    struct MockNode {
+        batch_sizes: Arc<Mutex<Vec<usize>>>,
+        reply: Arc<dyn Fn(usize) -> Reply + Send + Sync>,
+        /// Larger batches are rejected with a 413.
+        batch_limit: usize,
+    }
+
+    impl MockNode {
+        fn batch_sizes(&self) -> Vec<usize> {
+            self.batch_sizes.lock().unwrap().clone()
+        }
+    }
+
+    async fn handle_batch(State(node): State<MockNode>, Json(batch): Json<Vec<Value>>) -> Response {
+        let request_index = {
+            let mut batch_sizes = node.batch_sizes.lock().unwrap();
+            batch_sizes.push(batch.len());
+            batch_sizes.len() - 1
+        };
+        if batch.len() > node.batch_limit {
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+        let reply = (node.reply)(request_index);
+        if reply == Reply::RateLimited {
+            return StatusCode::TOO_MANY_REQUESTS.into_response();
+        }
+        let responses = batch
+            .iter()
+            .enumerate()
+            .map(|(i, call)| {
+                if reply == Reply::CallError && i == 0 {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": call["id"],
+                        "error": { "code": -32005, "message": "limit exceeded" },
+                    })
+                } else if reply == Reply::Malformed {
+                    json!({ "jsonrpc": "2.0", "id": call["id"], "result": "0x1ffffffffffffffff" })
+                } else if call["method"] == "debug_traceBlockByNumber" {
+                    json!({ "jsonrpc": "2.0", "id": call["id"], "result": block_trace() })
                 } else {
-                    storage_proofs.insert(proof.address, proof);
+                    json!({ "jsonrpc": "2.0", "id": call["id"], "result": "0x2a" })
+                }
+            })
+            .collect::<Vec<_>>();
+        Json(responses).into_response()
+    }
+
+    const ACCOUNT_A: Address = Address::repeat_byte(0xaa);
+    const ACCOUNT_B: Address = Address::repeat_byte(0xbb);
+
+    /// A `prestateTracer` trace of a block with two transactions, where the second one
+    /// touches state modified by the first one.
+    fn block_trace() -> Value {
+        json!([
+            {
+                "txHash": B256::repeat_byte(1),
+                "result": {
+                    (ACCOUNT_A.to_string()): {
+                        "balance": "0x10",
+                        "nonce": 1,
+                        "storage": { (B256::with_last_byte(1).to_string()): B256::with_last_byte(7) }
+                    }
+                }
+            },
+            {
+                "txHash": B256::repeat_byte(2),
+                "result": {
+                    (ACCOUNT_A.to_string()): {
+                        "balance": "0x5",
+                        "nonce": 2,
+                        "storage": {
+                            (B256::with_last_byte(1).to_string()): B256::with_last_byte(9),
+                            (B256::with_last_byte(2).to_string()): B256::with_last_byte(8)
+                        }
+                    },
+                    (ACCOUNT_B.to_string()): { "balance": "0x0" }
                 }
             }
+        ])
+    }
+
+    /// Starts a JSON-RPC server answering every call with `0x2a`, or failing as decided by
+    /// `reply` for the n-th request it receives.
+    async fn spawn_node(
+        reply: impl Fn(usize) -> Reply + Send + Sync + 'static,
+    ) -> (String, MockNode) {
+        spawn_limited_node(reply, usize::MAX).await
+    }
+
+    async fn spawn_limited_node(
+        reply: impl Fn(usize) -> Reply + Send + Sync + 'static,
+        batch_limit: usize,
+    ) -> (String, MockNode) {
+        let node = MockNode {
+            batch_sizes: Arc::default(),
+            reply: Arc::new(reply),
+            batch_limit,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let router = Router::new()
+            .route("/", post(handle_batch))
+            .with_state(node.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        (url, node)
+    }
+
+    fn retry_config() -> RetryConfig {
+        RetryConfig {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            ..Default::default()
         }
-        clear_line();
+    }
 
-        Ok(storage_proofs)
+    fn slots() -> Vec<(Address, U256)> {
+        (0..3).map(|i| (Address::ZERO, U256::from(i))).collect()
+    }
+
+    #[tokio::test]
+    async fn test_retry_rate_limited_batch() {
+        let (url, node) = spawn_node(|n| {
+            if n == 0 {
+                Reply::RateLimited
+            } else {
+                Reply::Ok
+            }
+        })
+        .await;
+        let provider = RpcBlockDataProvider::with_retry_config(&url, 0, retry_config());
+
+        let values = provider.get_storage_values(&slots()).await.unwrap();
+        assert_eq!(values, vec![U256::from(42); 3]);
+        assert_eq!(node.batch_sizes(), vec![3, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_retry_only_failed_calls() {
+        let (url, node) = spawn_node(|n| if n == 0 { Reply::CallError } else { Reply::Ok }).await;
+        let provider = RpcBlockDataProvider::with_retry_config(&url, 0, retry_config());
+
+        let values = provider.get_storage_values(&slots()).await.unwrap();
+        assert_eq!(values, vec![U256::from(42); 3]);
+        assert_eq!(node.batch_sizes(), vec![3, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_failover_benches_endpoint() {
+        let (bad_url, bad_node) = spawn_node(|_| Reply::RateLimited).await;
+        let (good_url, good_node) = spawn_node(|_| Reply::Ok).await;
+        let provider = RpcBlockDataProvider::with_retry_config(
+            &format!("{bad_url},{good_url}"),
+            0,
+            retry_config(),
+        );
+
+        // The first endpoint is tried until it gets benched, then the fallback is used.
+        provider.get_storage_values(&slots()).await.unwrap();
+        assert_eq!(
+            bad_node.batch_sizes().len(),
+            retry_config().max_failures as usize
+        );
+        assert_eq!(good_node.batch_sizes().len(), 1);
+
+        // The benched endpoint is skipped for the following requests.
+        provider.get_storage_values(&slots()).await.unwrap();
+        assert_eq!(
+            bad_node.batch_sizes().len(),
+            retry_config().max_failures as usize
+        );
+        assert_eq!(good_node.batch_sizes().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failover_on_malformed_account() {
+        let (bad_url, bad_node) = spawn_node(|_| Reply::Malformed).await;
+        let (good_url, good_node) = spawn_node(|_| Reply::Ok).await;
+        let provider = RpcBlockDataProvider::with_retry_config(
+            &format!("{bad_url},{good_url}"),
+            0,
+            retry_config(),
+        );
+
+        let accounts = provider.get_accounts(&[ACCOUNT_A]).await.unwrap();
+        assert_eq!(accounts[0].nonce, 42);
+        assert_eq!(accounts[0].balance, U256::from(42));
+        assert_eq!(
+            bad_node.batch_sizes().len(),
+            retry_config().max_failures as usize
+        );
+        assert_eq!(good_node.batch_sizes().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_give_up_after_max_attempts() {
+        let (url, node) = spawn_node(|_| Reply::RateLimited).await;
+        let provider = RpcBlockDataProvider::with_retry_config(&url, 0, retry_config());
+
+        assert!(provider.get_storage_values(&slots()).await.is_err());
+        assert_eq!(node.batch_sizes().len(), retry_config().max_attempts);
+    }
+
+    #[tokio::test]
+    async fn test_shrink_oversized_batches() {
+        let (url, node) = spawn_limited_node(|_| Reply::Ok, 2).await;
+        let provider = RpcBlockDataProvider::with_retry_config(&url, 0, retry_config())
+            .with_dispatch_config(DispatchConfig {
+                max_in_flight: 1,
+                max_batch_size: 8,
+                ..Default::default()
+            });
+        let slots = (0..5)
+            .map(|i| (Address::ZERO, U256::from(i)))
+            .collect::<Vec<_>>();
+
+        let values = provider.get_storage_values(&slots).await.unwrap();
+        assert_eq!(values, vec![U256::from(42); 5]);
+        assert_eq!(node.batch_sizes()[0], 5);
+        // Halved twice to fit the limit, then grown by one after the success.
+        assert_eq!(provider.batch_size.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_chunks_concurrently() {
+        let (url, node) = spawn_node(|_| Reply::Ok).await;
+        let provider = RpcBlockDataProvider::with_retry_config(&url, 0, retry_config());
+        let slots = (0..2500)
+            .map(|i| (Address::ZERO, U256::from(i)))
+            .collect::<Vec<_>>();
+
+        let values = provider.get_storage_values(&slots).await.unwrap();
+        assert_eq!(values.len(), 2500);
+        let mut batch_sizes = node.batch_sizes();
+        batch_sizes.sort();
+        assert_eq!(batch_sizes, vec![500, 1000, 1000]);
+    }
+
+    #[test]
+    fn test_batch_limit_errors() {
+        assert!(is_batch_limit_error(
+            r#"{"code":-32600,"message":"batch too large"}"#
+        ));
+        assert!(is_batch_limit_error("Batch size limit exceeded"));
+        assert!(!is_batch_limit_error("execution reverted"));
+    }
+
+    #[tokio::test]
+    async fn test_touched_state_from_prestate_trace() {
+        let (url, node) = spawn_node(|_| Reply::Ok).await;
+
+        // Without the tracer enabled the state is discovered by execution.
+        let provider = RpcBlockDataProvider::with_retry_config(&url, 0, retry_config());
+        assert!(provider.get_touched_state(1).await.unwrap().is_none());
+        assert!(node.batch_sizes().is_empty());
+
+        let provider = provider.with_prestate_tracer(true);
+        let state = provider.get_touched_state(1).await.unwrap().unwrap();
+        assert_eq!(state.accounts.len(), 2);
+        assert_eq!(state.accounts[&ACCOUNT_A].balance, U256::from(0x10));
+        assert_eq!(state.accounts[&ACCOUNT_A].nonce, 1);
+        assert_eq!(state.accounts[&ACCOUNT_B].balance, U256::ZERO);
+        assert_eq!(
+            state.storage[&ACCOUNT_A],
+            HashMap::from([
+                (U256::from(1), U256::from(7)),
+                (U256::from(2), U256::from(8))
+            ])
+        );
+        assert!(state.storage[&ACCOUNT_B].is_empty());
+    }
+
+    #[test]
+    fn test_child_positions() {
+        let blocks_to_fetch = [(10, true), (9, false), (8, false), (6, false), (5, true)];
+        assert_eq!(
+            child_positions(&blocks_to_fetch),
+            vec![None, Some(0), Some(1), None, None]
+        );
+    }
+
+    #[test]
+    fn test_parse_endpoints() {
+        let endpoints = parse_endpoints("http://a:8545, https://b/key,").unwrap();
+        let labels = endpoints.iter().map(endpoint_label).collect::<Vec<_>>();
+        assert_eq!(labels, vec!["a:8545", "b"]);
+        assert!(parse_endpoints("").is_err());
+        assert!(parse_endpoints("not a url").is_err());
     }
 }
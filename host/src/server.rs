@@ -16,6 +16,8 @@ pub async fn serve(state: ProverState) -> anyhow::Result<()> {
 
     debug!("Listening on: {}", listener.local_addr()?);
 
+    state.spawn_config_watcher();
+
     let router = create_router(state.opts.concurrency_limit).with_state(state);
     axum::serve(listener, router)
         .await
@@ -25,6 +25,14 @@ use crate::{error::HostResult, ProverState};
 /// - guest_proof_time_histogram - time taken for proof generation by this guest
 /// - prepare_input_time_histogram - time taken for prepare input
 /// - total_time_histogram - time taken for the whole proof request
+/// - rpc_call_count - the number of JSON-RPC calls made, by method, endpoint and status
+/// - rpc_latency_histogram - time taken for JSON-RPC requests and batches, by method, endpoint
+///   and status
+/// - rpc_batch_size_histogram - the number of calls per method in a JSON-RPC batch
+/// - rpc_retry_count - the number of retried JSON-RPC batches, by the endpoint that failed
+/// - rpc_endpoint_benched_count - the number of times an endpoint was benched for failing
+/// - rpc_cache_hit_count - the number of lookups answered by the rpc cache, by kind of data
+/// - rpc_cache_miss_count - the number of lookups the rpc cache couldn't answer, by kind of data
 /// - process_cpu_seconds_total - total user and system CPU time spent in seconds
 /// - process_open_fds - number of open file descriptors
 /// - process_max_fds - maximum number of open file descriptors
@@ -10,9 +10,11 @@ use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
     cors::{self, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
     set_header::SetResponseHeaderLayer,
     trace::TraceLayer,
 };
+use tracing::{field::Empty, info_span};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
@@ -67,6 +69,8 @@ pub fn create_docs() -> utoipa::openapi::OpenApi {
     })
 }
 
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
 pub fn create_router(concurrency_limit: usize) -> Router<ProverState> {
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
@@ -86,7 +90,24 @@ pub fn create_router(concurrency_limit: usize) -> Router<ProverState> {
         ),
     );
 
-    let trace = TraceLayer::new_for_http();
+    // Every request gets its own span tagged with an `x-request-id` (generated when the client
+    // doesn't send one), so that all spans emitted while handling it can be correlated.
+    let trace = TraceLayer::new_for_http().make_span_with(|req: &Request| {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .unwrap_or_default();
+        info_span!(
+            "request",
+            method = %req.method(),
+            uri = %req.uri(),
+            request_id,
+            block_number = Empty,
+            network = Empty,
+            proof_type = Empty,
+        )
+    });
 
     Router::new()
         // Only add the concurrency limit to the proof route. We want to still be able to call
@@ -101,6 +122,8 @@ pub fn create_router(concurrency_limit: usize) -> Router<ProverState> {
         .layer(middleware)
         .layer(middleware::from_fn(check_max_body_size))
         .layer(trace)
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
         .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", create_docs()))
         .fallback(|uri: Uri| async move {
             (StatusCode::NOT_FOUND, format!("No handler found for {uri}"))
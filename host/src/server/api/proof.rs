@@ -4,23 +4,26 @@ use crate::metrics::observe_guest_time;
 use crate::metrics::observe_prepare_input_time;
 use axum::{debug_handler, extract::State, routing::post, Json, Router};
 use raiko_lib::{
-    consts::get_network_spec,
     input::{get_input_path, GuestInput},
     Measurement,
 };
 use serde_json::Value;
+use tracing::{field::display, info, Span};
 use utoipa::OpenApi;
 
 use crate::{
+    archive::get_archive_path,
     error::{HostError, HostResult},
     memory,
     metrics::{
-        dec_current_req, inc_current_req, inc_guest_error, inc_guest_success, inc_host_error,
-        inc_host_req_count, observe_total_time,
+        inc_guest_error, inc_guest_success, inc_host_error, inc_host_req_count, observe_total_time,
+        track_current_req,
     },
-    raiko::Raiko,
+    preflight::PreflightStats,
+    raiko::{resolve_block_number, BlockDataProvider, Raiko},
     request::ProofRequest,
     rpc_provider::RpcBlockDataProvider,
+    state_dump::StateDumpProvider,
     ProverState,
 };
 
@@ -50,13 +53,34 @@ fn set_cached_input(
         let path = get_input_path(dir, block_number, network);
         if !path.exists() {
             let file = File::create(&path).map_err(<std::io::Error as Into<HostError>>::into)?;
-            println!("caching input for {path:?}");
+            info!("caching input for {path:?}");
             bincode::serialize_into(file, &input).map_err(|e| HostError::Anyhow(e.into()))?;
         }
     }
     Ok(())
 }
 
+/// Generates the input with the given L2 provider, recording everything fetched into an
+/// archive when `record_path` is set.
+async fn generate_input<BDP: BlockDataProvider>(
+    raiko: &Raiko,
+    provider: BDP,
+    record_path: &Option<PathBuf>,
+    proof_request: &ProofRequest,
+) -> HostResult<(GuestInput, PreflightStats)> {
+    match record_path {
+        Some(dir) => {
+            let path = get_archive_path(
+                dir,
+                proof_request.block_number,
+                &proof_request.network.to_string(),
+            );
+            raiko.generate_input_recorded(provider, &path).await
+        }
+        None => raiko.generate_input(provider).await,
+    }
+}
+
 #[utoipa::path(post, path = "/proof",
     tag = "Proving",
     responses (
@@ -73,53 +97,93 @@ fn set_cached_input(
 /// - sp1 - uses the sp1 prover
 /// - risc0 - uses the risc0 prover
 async fn proof_handler(
-    State(ProverState { opts }): State<ProverState>,
+    State(prover_state): State<ProverState>,
     Json(req): Json<Value>,
 ) -> HostResult<Json<Value>> {
-    inc_current_req();
-    // Override the existing proof request config from the config file and command line
-    // options with the request from the client.
-    let mut config = opts.proof_request_opt.clone();
-    config.merge(&req)?;
+    let _current_req = track_current_req();
+    // Config reloads only apply to requests that start after the reload.
+    let opts = prover_state.current_opts();
+    // Override the existing proof request config from the config file, command line
+    // options and network profile with the request from the client.
+    let mut config = opts.request_config(&req)?;
+    // Look up the block number when the block is only named by a hash.
+    resolve_block_number(&mut config, &opts.chain_specs).await?;
 
     // Construct the actual proof request from the available configs.
-    let proof_request = ProofRequest::try_from(config).map_err(|e| {
-        dec_current_req();
-        e
-    })?;
+    let proof_request = ProofRequest::from_config(config, &opts.chain_specs)?;
     inc_host_req_count(proof_request.block_number);
 
-    println!(
+    Span::current()
+        .record("block_number", proof_request.block_number)
+        .record("network", display(&proof_request.network))
+        .record("proof_type", display(&proof_request.proof_type));
+    info!(
         "# Generating proof for block {} on {}",
         proof_request.block_number, proof_request.network
     );
 
-    // Check for a cached input for the given request config.
+    // Check for a cached input for the given request config. The input doesn't record the
+    // proposal transaction, so only a requested block hash can be checked against the cache.
     let cached_input = get_cached_input(
         &opts.cache_path,
         proof_request.block_number,
         &proof_request.network.to_string(),
-    );
-
-    let chain_spec = get_network_spec(proof_request.network);
+    )
+    .filter(|input| {
+        proof_request.proposal_tx_hash.is_none()
+            && proof_request
+                .block_hash
+                .map_or(true, |hash| hash == input.block_hash_reference)
+    });
+
+    let chain_spec = opts
+        .chain_specs
+        .get_chain_spec(&proof_request.network.to_string())
+        .ok_or_else(|| {
+            HostError::InvalidRequestConfig(format!(
+                "Unsupported network: {}",
+                proof_request.network
+            ))
+        })?;
 
     // Execute the proof generation.
     let total_time = Measurement::start("", false);
 
-    let raiko = Raiko::new(chain_spec, proof_request.clone());
-    let input = if let Some(cached_input) = cached_input {
-        println!("Using cached input");
-        cached_input
+    let raiko =
+        Raiko::new(chain_spec, proof_request.clone()).with_chain_specs(opts.chain_specs.clone());
+    let (input, preflight_stats) = if let Some(cached_input) = cached_input {
+        info!("Using cached input");
+        (cached_input, None)
     } else {
+        // Catch a wrong network or endpoint before doing any heavy work. A devnet served
+        // from state dumps has no L2 node to check the request against.
+        if opts.state_dump.is_none() {
+            raiko.validate_request().await?;
+        }
         memory::reset_stats();
         let measurement = Measurement::start("Generating input...", false);
-        let provider =
-            RpcBlockDataProvider::new(&proof_request.rpc.clone(), proof_request.block_number - 1);
-        let input = raiko.generate_input(provider).await?;
+        // Block 0 has no parent and is rejected by the preflight.
+        let parent_number = proof_request.block_number.saturating_sub(1);
+        let (input, stats) = match &opts.state_dump {
+            Some(dir) => {
+                let provider = StateDumpProvider::load_dir(dir, parent_number)?;
+                generate_input(&raiko, provider, &opts.record_path, &proof_request).await?
+            }
+            None => {
+                let mut provider = RpcBlockDataProvider::new(&proof_request.rpc, parent_number)
+                    .with_prestate_tracer(opts.prestate_tracer)
+                    .with_dispatch_config(opts.dispatch_config());
+                if let Some(rpc_cache) = &prover_state.rpc_cache {
+                    provider =
+                        provider.with_cache(rpc_cache.clone(), &proof_request.network.to_string());
+                }
+                generate_input(&raiko, provider, &opts.record_path, &proof_request).await?
+            }
+        };
         let input_time = measurement.stop_with("=> Input generated");
         observe_prepare_input_time(proof_request.block_number, input_time.as_millis(), true);
         memory::print_stats("Input generation peak memory used: ");
-        input
+        (input, Some(stats))
     };
     memory::reset_stats();
     let output = raiko.get_output(&input)?;
@@ -127,8 +191,7 @@ async fn proof_handler(
 
     memory::reset_stats();
     let measurement = Measurement::start("Generating proof...", false);
-    let proof = raiko.prove(input.clone(), &output).await.map_err(|e| {
-        dec_current_req();
+    let mut proof = raiko.prove(input.clone(), &output).await.map_err(|e| {
         let total_time = total_time.stop_with("====> Proof generation failed");
         observe_total_time(proof_request.block_number, total_time.as_millis(), false);
         match e {
@@ -161,14 +224,11 @@ async fn proof_handler(
         proof_request.block_number,
         &proof_request.network.to_string(),
         input,
-    )
-    .map_err(|e| {
-        dec_current_req();
-        e
-    })?;
-
-    dec_current_req();
+    )?;
 
+    if let (Some(stats), Value::Object(proof)) = (preflight_stats, &mut proof) {
+        proof.insert("preflight_stats".to_string(), serde_json::to_value(stats)?);
+    }
     Ok(Json(proof))
 }
 
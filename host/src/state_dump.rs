@@ -0,0 +1,627 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+    path::Path,
+    str::FromStr,
+};
+
+use alloy_primitives::{Address, Bytes, B256, U256, U64};
+use alloy_rpc_types::{
+    Block, BlockTransactions, EIP1186AccountProofResponse, EIP1186StorageProof, Filter, Log,
+    Transaction,
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use raiko_primitives::{
+    keccak::keccak,
+    mpt::{MptNode, StateAccount},
+};
+use revm::primitives::{AccountInfo, Bytecode};
+use serde::{Deserialize, Deserializer};
+
+use crate::{raiko::BlockDataProvider, MerkleProof};
+
+/// The accounts of a chain at a single block, as written by `anvil_dumpState` or found in the
+/// `alloc` of a geth genesis file.
+#[derive(Clone, Debug, Default)]
+pub struct StateDump {
+    pub accounts: BTreeMap<Address, DumpAccount>,
+}
+
+/// An account of a [`StateDump`].
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DumpAccount {
+    #[serde(default, deserialize_with = "deserialize_quantity")]
+    pub nonce: U256,
+    #[serde(default, deserialize_with = "deserialize_quantity")]
+    pub balance: U256,
+    #[serde(default)]
+    pub code: Bytes,
+    #[serde(default)]
+    pub storage: BTreeMap<U256, U256>,
+}
+
+/// The layouts of the state dump files, which only differ in where the accounts are.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StateDumpFile {
+    Genesis {
+        alloc: BTreeMap<Address, DumpAccount>,
+    },
+    Dump {
+        accounts: BTreeMap<Address, DumpAccount>,
+    },
+    Alloc(BTreeMap<Address, DumpAccount>),
+}
+
+impl<'de> Deserialize<'de> for StateDump {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let accounts = match StateDumpFile::deserialize(deserializer)? {
+            StateDumpFile::Genesis { alloc } => alloc,
+            StateDumpFile::Dump { accounts } => accounts,
+            StateDumpFile::Alloc(accounts) => accounts,
+        };
+        Ok(Self { accounts })
+    }
+}
+
+/// Quantities are plain numbers in some dumps and decimal or hex strings in others.
+fn deserialize_quantity<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Quantity {
+        Number(u64),
+        String(String),
+    }
+
+    match Quantity::deserialize(deserializer)? {
+        Quantity::Number(number) => Ok(U256::from(number)),
+        Quantity::String(string) => U256::from_str(&string).map_err(serde::de::Error::custom),
+    }
+}
+
+impl StateDump {
+    /// Reads a state dump from a JSON file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("could not open {path:?}"))?;
+        serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("could not read the state dump {path:?}"))
+    }
+
+    /// Builds the state trie and the storage tries of all accounts.
+    fn tries(&self) -> Result<(MptNode, HashMap<Address, MptNode>)> {
+        let mut state_trie = MptNode::default();
+        let mut storage_tries = HashMap::with_capacity(self.accounts.len());
+        for (address, account) in &self.accounts {
+            let mut storage_trie = MptNode::default();
+            for (slot, value) in &account.storage {
+                if !value.is_zero() {
+                    storage_trie.insert_rlp(&keccak(slot.to_be_bytes::<32>()), *value)?;
+                }
+            }
+            let state_account = StateAccount {
+                nonce: account.nonce()?,
+                balance: account.balance,
+                storage_root: storage_trie.hash(),
+                code_hash: keccak(&account.code).into(),
+            };
+            state_trie.insert_rlp(&keccak(address), state_account)?;
+            storage_tries.insert(*address, storage_trie);
+        }
+        Ok((state_trie, storage_tries))
+    }
+}
+
+impl DumpAccount {
+    fn nonce(&self) -> Result<u64> {
+        self.nonce
+            .try_into()
+            .map_err(|_| anyhow!("nonce {} is too large", self.nonce))
+    }
+}
+
+/// A state dump with its tries, which are built once when the dump is added.
+struct DumpedState {
+    dump: StateDump,
+    state_trie: MptNode,
+    storage_tries: HashMap<Address, MptNode>,
+}
+
+/// Serves blocks and state from files instead of a node, so that blocks of a local devnet can
+/// be proven without an archive node for `eth_getProof`. The proofs are generated from the
+/// tries built from the state dumps.
+///
+/// Proving a block needs the dumps of the state after its parent and after the block itself.
+pub struct StateDumpProvider {
+    blocks: HashMap<u64, Block>,
+    states: BTreeMap<u64, DumpedState>,
+    block_number: u64,
+}
+
+impl StateDumpProvider {
+    /// Creates a provider for the given blocks, reading accounts and storage from the state
+    /// after block `block_number`.
+    pub fn new(blocks: Vec<Block>, block_number: u64) -> Result<Self> {
+        let blocks = blocks
+            .into_iter()
+            .map(|block| {
+                let number = block
+                    .header
+                    .number
+                    .and_then(|number| number.try_into().ok())
+                    .context("block without a number")?;
+                Ok((number, block))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            blocks,
+            states: BTreeMap::new(),
+            block_number,
+        })
+    }
+
+    /// Reads a directory with the blocks in `blocks.json`, a JSON array of blocks with their
+    /// transactions, and the state after a block in `<block number>.json`. The states after
+    /// block `block_number` and after the block following it, the one being proven, are
+    /// read.
+    pub fn load_dir(dir: &Path, block_number: u64) -> Result<Self> {
+        let blocks_path = dir.join("blocks.json");
+        let file =
+            File::open(&blocks_path).with_context(|| format!("could not open {blocks_path:?}"))?;
+        let blocks: Vec<Block> = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("could not read the blocks {blocks_path:?}"))?;
+        let mut provider = Self::new(blocks, block_number)?;
+        for number in [block_number, block_number + 1] {
+            let state = StateDump::load(&dir.join(format!("{number}.json")))?;
+            provider = provider.with_state(number, state)?;
+        }
+        Ok(provider)
+    }
+
+    /// Adds the state after block `block_number`, which has to match the state root of the
+    /// block when the block is known.
+    pub fn with_state(mut self, block_number: u64, state: StateDump) -> Result<Self> {
+        let (state_trie, storage_tries) = state.tries()?;
+        // A dump that doesn't match the chain would only fail much later in the guest
+        if let Some(block) = self.blocks.get(&block_number) {
+            ensure!(
+                block.header.state_root == state_trie.hash(),
+                "the state dump doesn't match the state root of block {block_number}"
+            );
+        }
+        self.states.insert(
+            block_number,
+            DumpedState {
+                dump: state,
+                state_trie,
+                storage_tries,
+            },
+        );
+        Ok(self)
+    }
+
+    /// Returns the dump of the state after block `block_number`.
+    fn state_at(&self, block_number: u64) -> Result<&DumpedState> {
+        self.states
+            .get(&block_number)
+            .with_context(|| format!("no state dump of block {block_number}"))
+    }
+
+    /// Returns the state root of the state after block `block_number`.
+    pub fn state_root(&self, block_number: u64) -> Result<B256> {
+        Ok(self.state_at(block_number)?.state_trie.hash())
+    }
+}
+
+impl BlockDataProvider for StateDumpProvider {
+    async fn get_blocks(&self, blocks_to_fetch: &[(u64, bool)]) -> Result<Vec<Block>> {
+        blocks_to_fetch
+            .iter()
+            .map(|(block_number, _)| {
+                self.blocks
+                    .get(block_number)
+                    .cloned()
+                    .with_context(|| format!("block {block_number} is not in the block list"))
+            })
+            .collect()
+    }
+
+    async fn get_accounts(&self, accounts: &[Address]) -> Result<Vec<AccountInfo>> {
+        let state = &self.state_at(self.block_number)?.dump;
+        accounts
+            .iter()
+            .map(|address| {
+                let Some(account) = state.accounts.get(address) else {
+                    return Ok(AccountInfo::default());
+                };
+                let code = Bytecode::new_raw(account.code.clone());
+                Ok(AccountInfo::new(
+                    account.balance,
+                    account.nonce()?,
+                    code.hash_slow(),
+                    code,
+                ))
+            })
+            .collect()
+    }
+
+    async fn get_storage_values(&self, accounts: &[(Address, U256)]) -> Result<Vec<U256>> {
+        let state = &self.state_at(self.block_number)?.dump;
+        Ok(accounts
+            .iter()
+            .map(|(address, slot)| {
+                state
+                    .accounts
+                    .get(address)
+                    .and_then(|account| account.storage.get(slot))
+                    .copied()
+                    .unwrap_or_default()
+            })
+            .collect())
+    }
+
+    async fn get_merkle_proofs(
+        &self,
+        block_number: u64,
+        accounts: HashMap<Address, Vec<U256>>,
+        _offset: usize,
+        _num_storage_proofs: usize,
+    ) -> Result<MerkleProof> {
+        let DumpedState {
+            dump: state,
+            state_trie,
+            storage_tries,
+        } = self.state_at(block_number)?;
+
+        let empty_trie = MptNode::default();
+        accounts
+            .into_iter()
+            .map(|(address, slots)| {
+                let account = state.accounts.get(&address).cloned().unwrap_or_default();
+                let storage_trie = storage_tries.get(&address).unwrap_or(&empty_trie);
+                let storage_proof = slots
+                    .into_iter()
+                    .map(|slot| {
+                        let key = B256::from(slot);
+                        Ok(EIP1186StorageProof {
+                            key: key.into(),
+                            value: account.storage.get(&slot).copied().unwrap_or_default(),
+                            proof: storage_trie
+                                .proof(&keccak(key))?
+                                .into_iter()
+                                .map(Bytes::from)
+                                .collect(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let proof = EIP1186AccountProofResponse {
+                    address,
+                    balance: account.balance,
+                    code_hash: keccak(&account.code).into(),
+                    nonce: U64::from(account.nonce()?),
+                    storage_hash: storage_trie.hash(),
+                    account_proof: state_trie
+                        .proof(&keccak(address))?
+                        .into_iter()
+                        .map(Bytes::from)
+                        .collect(),
+                    storage_proof,
+                };
+                Ok((address, proof))
+            })
+            .collect()
+    }
+
+    async fn get_logs(&self, _filter: &Filter) -> Result<Vec<Log>> {
+        bail!("logs are not part of a state dump")
+    }
+
+    async fn get_transaction_by_hash(&self, hash: B256) -> Result<Transaction> {
+        self.blocks
+            .values()
+            .find_map(|block| match &block.transactions {
+                BlockTransactions::Full(txs) => txs.iter().find(|tx| tx.hash == hash).cloned(),
+                _ => None,
+            })
+            .with_context(|| format!("transaction {hash} is not in the block list"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_consensus::Header as AlloyConsensusHeader;
+    use alloy_rpc_types::Withdrawal;
+    use raiko_lib::{
+        builder::{prepare::derive_base_fee, BlockBuilderStrategy, TaikoStrategy},
+        consts::{ChainSpec, Network, ETH_HOLESKY_CHAIN_SPEC},
+        utils::HeaderHasher,
+    };
+    use raiko_primitives::mpt::proofs_to_tries;
+    use revm::primitives::SpecId;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        raiko::Raiko,
+        request::{ProofRequest, ProofType},
+    };
+
+    const ACCOUNT_A: Address = Address::repeat_byte(0xaa);
+    const ACCOUNT_B: Address = Address::repeat_byte(0xbb);
+
+    fn state_dump() -> StateDump {
+        serde_json::from_value(json!({
+            "config": {},
+            "alloc": {
+                ACCOUNT_A.to_string(): {
+                    "balance": "1000000000000000000",
+                    "nonce": "0x1",
+                },
+                ACCOUNT_B.to_string(): {
+                    "balance": "0x10",
+                    "code": "0x6000",
+                    "storage": {
+                        "0x01": "0x2a",
+                        "0x02": "0x00",
+                    },
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_state_dump_layouts() {
+        let genesis = state_dump();
+        assert_eq!(genesis.accounts.len(), 2);
+        assert_eq!(genesis.accounts[&ACCOUNT_A].nonce, U256::from(1));
+        assert_eq!(
+            genesis.accounts[&ACCOUNT_A].balance,
+            U256::from(1_000_000_000_000_000_000u64)
+        );
+
+        let anvil: StateDump = serde_json::from_value(json!({
+            "block": {},
+            "accounts": {
+                ACCOUNT_A.to_string(): { "nonce": 1, "balance": "0x0", "code": "0x" },
+            },
+        }))
+        .unwrap();
+        assert_eq!(anvil.accounts[&ACCOUNT_A].nonce, U256::from(1));
+    }
+
+    #[tokio::test]
+    async fn test_merkle_proofs_match_state_root() {
+        let provider = StateDumpProvider::new(Vec::new(), 0)
+            .unwrap()
+            .with_state(0, state_dump())
+            .unwrap();
+        let state_root = provider.state_root(0).unwrap();
+
+        let missing = Address::repeat_byte(0xcc);
+        let accounts = HashMap::from([
+            (ACCOUNT_A, vec![]),
+            (ACCOUNT_B, vec![U256::from(1), U256::from(3)]),
+            (missing, vec![]),
+        ]);
+        let proofs = provider
+            .get_merkle_proofs(0, accounts.clone(), 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(proofs[&ACCOUNT_B].storage_proof[0].value, U256::from(42));
+
+        let (state_trie, storage) = proofs_to_tries(
+            state_root,
+            proofs.clone().into_iter().collect(),
+            proofs.into_iter().collect(),
+        )
+        .unwrap();
+        assert_eq!(state_trie.hash(), state_root);
+        let (storage_trie, _) = &storage[&ACCOUNT_B];
+        assert_eq!(
+            storage_trie
+                .get_rlp::<U256>(&keccak(U256::from(1).to_be_bytes::<32>()))
+                .unwrap(),
+            Some(U256::from(42))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proofs_need_the_exact_state() {
+        let provider = StateDumpProvider::new(Vec::new(), 0)
+            .unwrap()
+            .with_state(0, state_dump())
+            .unwrap();
+        let accounts = HashMap::from([(ACCOUNT_A, vec![])]);
+        assert!(provider
+            .get_merkle_proofs(0, accounts.clone(), 0, 0)
+            .await
+            .is_ok());
+        assert!(provider.get_merkle_proofs(1, accounts, 0, 0).await.is_err());
+        assert!(provider.state_root(1).is_err());
+    }
+
+    /// Converts a header to the block a node would return for it.
+    fn block(header: &AlloyConsensusHeader, withdrawals: Vec<Withdrawal>) -> Block {
+        let mut block = Block::default();
+        block.header.hash = Some(header.hash());
+        block.header.parent_hash = header.parent_hash;
+        block.header.uncles_hash = header.ommers_hash;
+        block.header.miner = header.beneficiary;
+        block.header.state_root = header.state_root;
+        block.header.transactions_root = header.transactions_root;
+        block.header.receipts_root = header.receipts_root;
+        block.header.withdrawals_root = header.withdrawals_root;
+        block.header.logs_bloom = header.logs_bloom;
+        block.header.difficulty = header.difficulty;
+        block.header.number = Some(header.number.try_into().unwrap());
+        block.header.gas_limit = header.gas_limit.try_into().unwrap();
+        block.header.gas_used = header.gas_used.try_into().unwrap();
+        block.header.timestamp = header.timestamp.try_into().unwrap();
+        block.header.extra_data = header.extra_data.clone();
+        block.header.mix_hash = Some(header.mix_hash);
+        block.header.nonce = Some(header.nonce);
+        block.header.base_fee_per_gas = header
+            .base_fee_per_gas
+            .map(|base_fee| base_fee.try_into().unwrap());
+        block.transactions = BlockTransactions::Full(Vec::new());
+        block.withdrawals = Some(withdrawals);
+        block
+    }
+
+    fn devnet_raiko(block_number: u64) -> Raiko {
+        let chain_spec = ChainSpec::new_single(
+            "devnet".to_string(),
+            1337,
+            SpecId::SHANGHAI,
+            ETH_HOLESKY_CHAIN_SPEC.eip_1559_constants,
+            false,
+        );
+        let network = Network::Custom(chain_spec.name.clone());
+        Raiko::new(
+            chain_spec,
+            ProofRequest {
+                block_number,
+                block_hash: None,
+                proposal_tx_hash: None,
+                rpc: String::new(),
+                l1_rpc: String::new(),
+                beacon_rpc: String::new(),
+                blob_archive_url: None,
+                blob_dir: None,
+                proposal_search_range: None,
+                network,
+                l1_network: Network::Ethereum.to_string(),
+                graffiti: B256::ZERO,
+                prover: Address::ZERO,
+                proof_type: ProofType::Native,
+                prover_args: HashMap::new(),
+            },
+        )
+    }
+
+    fn dump_provider(
+        blocks: &[Block],
+        states: &[(u64, StateDump)],
+        block_number: u64,
+    ) -> StateDumpProvider {
+        states
+            .iter()
+            .cloned()
+            .try_fold(
+                StateDumpProvider::new(blocks.to_vec(), block_number).unwrap(),
+                |provider, (number, state)| provider.with_state(number, state),
+            )
+            .unwrap()
+    }
+
+    fn withdrawal(index: u64, address: Address) -> Withdrawal {
+        Withdrawal {
+            index,
+            validator_index: 0,
+            address,
+            // 0.001 ether
+            amount: 1_000_000,
+        }
+    }
+
+    /// Builds a devnet chain on top of [`state_dump`] in which each block only pays out the
+    /// given withdrawals. Returns the blocks with the state dumps after each of them.
+    async fn devnet_chain(
+        withdrawals: Vec<Vec<Withdrawal>>,
+    ) -> (Vec<Block>, Vec<(u64, StateDump)>) {
+        let genesis = AlloyConsensusHeader {
+            state_root: state_dump().tries().unwrap().0.hash(),
+            gas_limit: 30_000_000,
+            base_fee_per_gas: Some(1_000_000_000),
+            ..Default::default()
+        };
+        let mut blocks = vec![block(&genesis, Vec::new())];
+        let mut states = vec![(0, state_dump())];
+        let mut parent = genesis;
+        for (number, withdrawals) in (1..).zip(withdrawals) {
+            let mut state = states.last().unwrap().1.clone();
+            for withdrawal in &withdrawals {
+                state
+                    .accounts
+                    .entry(withdrawal.address)
+                    .or_default()
+                    .balance += U256::from(withdrawal.amount) * U256::from(1_000_000_000u64);
+            }
+            // The roots and the hash of a block only follow from building it, so the block is
+            // built from a preliminary header first.
+            let header = AlloyConsensusHeader {
+                parent_hash: parent.hash(),
+                number,
+                state_root: state.tries().unwrap().0.hash(),
+                gas_limit: 30_000_000,
+                timestamp: 12 * number,
+                base_fee_per_gas: Some(
+                    derive_base_fee(&parent, &ETH_HOLESKY_CHAIN_SPEC.eip_1559_constants)
+                        .unwrap()
+                        .into(),
+                ),
+                ..Default::default()
+            };
+            states.push((number, state));
+            blocks.push(block(&header, withdrawals.clone()));
+            let (input, _) = devnet_raiko(number)
+                .generate_input(dump_provider(&blocks, &states, number - 1))
+                .await
+                .unwrap();
+            let (header, _) = TaikoStrategy::build_from(&input).unwrap();
+            *blocks.last_mut().unwrap() = block(&header, withdrawals);
+            parent = header;
+        }
+        (blocks, states)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_prove_block_from_state_dumps() {
+        let (blocks, states) = devnet_chain(vec![vec![withdrawal(0, ACCOUNT_A)]]).await;
+        assert_eq!(
+            states[1].1.accounts[&ACCOUNT_A].balance,
+            U256::from(1_001_000_000_000_000_000u128)
+        );
+
+        let raiko = devnet_raiko(1);
+        let (input, stats) = raiko
+            .generate_input(dump_provider(&blocks, &states, 0))
+            .await
+            .unwrap();
+        assert_eq!(Some(input.block_hash_reference), blocks[1].header.hash);
+        assert_eq!(stats.rpc_calls, 0);
+        let output = raiko.get_output(&input).unwrap();
+        raiko.prove(input, &output).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_carried_state_matches_fresh_preflight() {
+        // Block 2 pays out to the account block 1 touched and to an account it didn't touch
+        let (blocks, states) = devnet_chain(vec![
+            vec![withdrawal(0, ACCOUNT_A)],
+            vec![withdrawal(1, ACCOUNT_A), withdrawal(2, ACCOUNT_B)],
+        ])
+        .await;
+
+        let mut inputs = devnet_raiko(1)
+            .generate_inputs(2, |block_number| {
+                dump_provider(&blocks, &states, block_number)
+            })
+            .await
+            .unwrap();
+        assert_eq!(inputs.len(), 2);
+        let (mut carried, _) = inputs.pop().unwrap();
+        let (mut fresh, _) = devnet_raiko(2)
+            .generate_input(dump_provider(&blocks, &states, 1))
+            .await
+            .unwrap();
+        // The contracts are collected in no particular order
+        carried.contracts.sort_by_key(|code| code.to_vec());
+        fresh.contracts.sort_by_key(|code| code.to_vec());
+        assert_eq!(
+            serde_json::to_value(&carried).unwrap(),
+            serde_json::to_value(&fresh).unwrap()
+        );
+    }
+}
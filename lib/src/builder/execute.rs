@@ -49,6 +49,10 @@ const MIN_SPEC_ID: SpecId = SpecId::SHANGHAI;
 pub struct TkoTxExecStrategy {}
 
 impl TxExecStrategy for TkoTxExecStrategy {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(block_number = block_builder.input.block_number))
+    )]
     fn execute_transactions<D>(mut block_builder: BlockBuilder<D>) -> Result<BlockBuilder<D>>
     where
         D: Database + DatabaseCommit + OptimisticDatabase,
@@ -87,6 +91,7 @@ impl TxExecStrategy for TkoTxExecStrategy {
         let mut transactions = generate_transactions(
             block_builder.input.taiko.block_proposed.meta.blobUsed,
             &block_builder.input.taiko.tx_data,
+            &block_builder.input.taiko.blob_tx_list,
             anchor_tx,
         );
 
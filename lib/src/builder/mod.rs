@@ -37,8 +37,8 @@ pub mod prepare;
 /// Optimistic database
 #[allow(async_fn_in_trait)]
 pub trait OptimisticDatabase {
-    /// Handle post execution work
-    async fn fetch_data(&mut self) -> bool;
+    /// Handle post execution work, returning whether the run was valid
+    async fn fetch_data(&mut self) -> Result<bool>;
 
     /// If the current database is optimistic
     fn is_optimistic(&self) -> bool;
@@ -103,6 +103,11 @@ where
     pub fn mut_db(&mut self) -> Option<&mut D> {
         self.db.as_mut()
     }
+
+    /// Takes the database out of the builder.
+    pub fn take_db(&mut self) -> Option<D> {
+        self.db.take()
+    }
 }
 
 /// A bundle of strategies for building a block using [BlockBuilder].
@@ -12,13 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::fmt::Debug;
+use core::{cmp::Ordering, fmt::Debug};
 
 use alloy_consensus::Header as AlloyConsensusHeader;
-use anyhow::{bail, Context, Result};
-use revm::{Database, DatabaseCommit};
+use alloy_primitives::{B256, U256};
+use alloy_rpc_types::Transaction as AlloyTransaction;
+use anyhow::{bail, ensure, Context, Result};
+use revm::{
+    primitives::{calc_excess_blob_gas, SpecId},
+    Database, DatabaseCommit,
+};
 
-use crate::{builder::BlockBuilder, consts::MAX_EXTRA_DATA_BYTES, utils::HeaderHasher};
+use crate::{
+    builder::BlockBuilder,
+    consts::{Eip1559Constants, GAS_LIMIT_BOUND_DIVISOR, MAX_EXTRA_DATA_BYTES, MIN_GAS_LIMIT, ONE},
+    utils::{HeaderHasher, ANCHOR_GAS_LIMIT},
+};
 
 pub trait HeaderPrepStrategy {
     fn prepare_header<D>(block_builder: BlockBuilder<D>) -> Result<BlockBuilder<D>>
@@ -27,6 +36,61 @@ pub trait HeaderPrepStrategy {
         <D as Database>::Error: core::fmt::Debug;
 }
 
+/// Prepares the header of an Ethereum block, validating every field that can be derived from
+/// the parent against the consensus rules.
+pub struct EthHeaderPrepStrategy {}
+
+impl HeaderPrepStrategy for EthHeaderPrepStrategy {
+    fn prepare_header<D>(mut block_builder: BlockBuilder<D>) -> Result<BlockBuilder<D>>
+    where
+        D: Database + DatabaseCommit,
+        <D as Database>::Error: Debug,
+    {
+        let input = &block_builder.input;
+        let parent = &input.parent_header;
+        // Validate gas limit
+        let parent_gas_limit: u64 = parent
+            .gas_limit
+            .try_into()
+            .context("Invalid parent gas limit: too large")?;
+        let limit = parent_gas_limit / GAS_LIMIT_BOUND_DIVISOR;
+        if parent_gas_limit.abs_diff(input.gas_limit) >= limit {
+            bail!(
+                "Invalid gas limit: expected {parent_gas_limit} +- {limit}, got {}",
+                input.gas_limit,
+            );
+        }
+        if input.gas_limit < MIN_GAS_LIMIT {
+            bail!(
+                "Invalid gas limit: expected >= {MIN_GAS_LIMIT}, got {}",
+                input.gas_limit,
+            );
+        }
+        // Validate timestamp
+        if input.timestamp <= parent.timestamp {
+            bail!(
+                "Invalid timestamp: expected > {}, got {}",
+                parent.timestamp,
+                input.timestamp,
+            );
+        }
+        validate_extra_data(&block_builder)?;
+        // Validate base fee
+        let base_fee = derive_base_fee(parent, block_builder.chain_spec.gas_constants())?;
+        if input.base_fee_per_gas != base_fee {
+            bail!(
+                "Invalid base fee: expected {base_fee}, got {}",
+                input.base_fee_per_gas,
+            );
+        }
+
+        block_builder.header = Some(derive_header(&block_builder, base_fee)?);
+        Ok(block_builder)
+    }
+}
+
+/// Prepares the header of a Taiko block. The gas limit and base fee follow the Taiko protocol
+/// rules instead of the Ethereum ones, which are still used for non-Taiko chains.
 pub struct TaikoHeaderPrepStrategy {}
 
 impl HeaderPrepStrategy for TaikoHeaderPrepStrategy {
@@ -35,41 +99,371 @@ impl HeaderPrepStrategy for TaikoHeaderPrepStrategy {
         D: Database + DatabaseCommit,
         <D as Database>::Error: Debug,
     {
+        if !block_builder.chain_spec.is_taiko() {
+            return EthHeaderPrepStrategy::prepare_header(block_builder);
+        }
+
+        let input = &block_builder.input;
         // Validate timestamp
-        let timestamp: u64 = block_builder.input.timestamp;
-        if timestamp < block_builder.input.parent_header.timestamp {
+        let timestamp: u64 = input.timestamp;
+        if timestamp < input.parent_header.timestamp {
             bail!(
                 "Invalid timestamp: expected >= {}, got {}",
-                block_builder.input.parent_header.timestamp,
-                block_builder.input.timestamp,
+                input.parent_header.timestamp,
+                input.timestamp,
             );
         }
-        // Validate extra data
-        let extra_data_bytes = block_builder.input.extra_data.len();
-        if extra_data_bytes > MAX_EXTRA_DATA_BYTES {
-            bail!("Invalid extra data: expected <= {MAX_EXTRA_DATA_BYTES}, got {extra_data_bytes}")
+        validate_extra_data(&block_builder)?;
+        // Validate gas limit: the proposed gas limit plus the gas reserved for the anchor tx
+        let gas_limit = u64::from(input.taiko.block_proposed.meta.gasLimit) + ANCHOR_GAS_LIMIT;
+        if input.gas_limit != gas_limit {
+            bail!(
+                "Invalid gas limit: expected {gas_limit}, got {}",
+                input.gas_limit
+            );
         }
-        // Derive header
-        let number: u64 = block_builder.input.parent_header.number;
-        block_builder.header = Some(AlloyConsensusHeader {
-            // Initialize fields that we can compute from the parent
-            parent_hash: block_builder.input.parent_header.hash(),
-            number: number
-                .checked_add(1)
-                .with_context(|| "Invalid block number: too large")?,
-            base_fee_per_gas: Some(block_builder.input.base_fee_per_gas.into()),
-            // Initialize metadata from input
-            beneficiary: block_builder.input.beneficiary,
-            gas_limit: block_builder.input.gas_limit.into(),
-            timestamp: block_builder.input.timestamp,
-            mix_hash: block_builder.input.mix_hash,
-            extra_data: block_builder.input.extra_data.clone(),
-            blob_gas_used: block_builder.input.blob_gas_used.map(|b| b.into()),
-            excess_blob_gas: block_builder.input.excess_blob_gas.map(|b| b.into()),
-            parent_beacon_block_root: block_builder.input.parent_beacon_block_root,
-            // do not fill the remaining fields
-            ..Default::default()
-        });
+        // Validate base fee: the anchor tx pays the base fee, and the L2 contract checks it
+        // against its own EIP-1559 calculation when the anchor tx is executed.
+        let anchor_tx: AlloyTransaction =
+            serde_json::from_str(&input.taiko.anchor_tx).context("Invalid anchor tx")?;
+        let base_fee: u64 = anchor_tx
+            .max_fee_per_gas
+            .context("Invalid anchor tx: expected an EIP-1559 tx")?
+            .try_into()
+            .context("Invalid base fee: too large")?;
+        if input.base_fee_per_gas != base_fee {
+            bail!(
+                "Invalid base fee: expected {base_fee}, got {}",
+                input.base_fee_per_gas,
+            );
+        }
+
+        block_builder.header = Some(derive_header(&block_builder, base_fee)?);
+        Ok(block_builder)
+    }
+}
+
+/// Prepares the header from the input without validating it against the consensus rules.
+/// Only for executing a block whose input isn't complete yet, e.g. to discover the state it
+/// touches before its proposal is known, never for a block that is proven.
+pub struct UncheckedHeaderPrepStrategy {}
+
+impl HeaderPrepStrategy for UncheckedHeaderPrepStrategy {
+    fn prepare_header<D>(mut block_builder: BlockBuilder<D>) -> Result<BlockBuilder<D>>
+    where
+        D: Database + DatabaseCommit,
+        <D as Database>::Error: Debug,
+    {
+        let base_fee = block_builder.input.base_fee_per_gas;
+        block_builder.header = Some(derive_header(&block_builder, base_fee)?);
         Ok(block_builder)
     }
 }
+
+/// Computes the base fee of a block from its parent as specified by
+/// [EIP-1559](https://eips.ethereum.org/EIPS/eip-1559).
+pub fn derive_base_fee(parent: &AlloyConsensusHeader, constants: &Eip1559Constants) -> Result<u64> {
+    let parent_base_fee = U256::from(
+        parent
+            .base_fee_per_gas
+            .context("Invalid parent: no base fee")?,
+    );
+    let parent_gas_used = U256::from(parent.gas_used);
+    let parent_gas_target = U256::from(parent.gas_limit) / constants.elasticity_multiplier;
+    ensure!(
+        parent_gas_target > U256::ZERO,
+        "Invalid parent: no gas target"
+    );
+
+    let base_fee = match parent_gas_used.cmp(&parent_gas_target) {
+        Ordering::Equal => parent_base_fee,
+        Ordering::Greater => {
+            let base_fee_delta = parent_base_fee * (parent_gas_used - parent_gas_target)
+                / parent_gas_target
+                / constants.base_fee_max_increase_denominator;
+            parent_base_fee + base_fee_delta.max(ONE)
+        }
+        Ordering::Less => {
+            let base_fee_delta = parent_base_fee * (parent_gas_target - parent_gas_used)
+                / parent_gas_target
+                / constants.base_fee_max_decrease_denominator;
+            parent_base_fee.saturating_sub(base_fee_delta)
+        }
+    };
+    base_fee.try_into().context("Invalid base fee: too large")
+}
+
+fn validate_extra_data<D>(block_builder: &BlockBuilder<D>) -> Result<()> {
+    let extra_data_bytes = block_builder.input.extra_data.len();
+    if extra_data_bytes > MAX_EXTRA_DATA_BYTES {
+        bail!("Invalid extra data: expected <= {MAX_EXTRA_DATA_BYTES}, got {extra_data_bytes}")
+    }
+    Ok(())
+}
+
+/// Validates the blob gas and beacon root fields, which are only present from Cancun on.
+fn derive_cancun_fields<D>(
+    block_builder: &BlockBuilder<D>,
+    number: u64,
+) -> Result<(Option<u64>, Option<B256>)> {
+    let input = &block_builder.input;
+    let spec_id = block_builder
+        .chain_spec
+        .active_fork(number, input.timestamp)?;
+    if spec_id < SpecId::CANCUN {
+        ensure!(
+            input.excess_blob_gas.is_none(),
+            "Invalid excess blob gas: expected none before Cancun"
+        );
+        ensure!(
+            input.parent_beacon_block_root.is_none(),
+            "Invalid parent beacon block root: expected none before Cancun"
+        );
+        return Ok((None, None));
+    }
+
+    // The parent of the first Cancun block has no blob gas fields, which counts as zero
+    let parent = &input.parent_header;
+    let parent_excess_blob_gas: u64 = parent
+        .excess_blob_gas
+        .unwrap_or_default()
+        .try_into()
+        .context("Invalid parent excess blob gas: too large")?;
+    let parent_blob_gas_used: u64 = parent
+        .blob_gas_used
+        .unwrap_or_default()
+        .try_into()
+        .context("Invalid parent blob gas used: too large")?;
+    let excess_blob_gas = calc_excess_blob_gas(parent_excess_blob_gas, parent_blob_gas_used);
+    if input.excess_blob_gas != Some(excess_blob_gas) {
+        bail!(
+            "Invalid excess blob gas: expected {excess_blob_gas}, got {:?}",
+            input.excess_blob_gas,
+        );
+    }
+    let parent_beacon_block_root = input
+        .parent_beacon_block_root
+        .context("Invalid parent beacon block root: expected one from Cancun on")?;
+    Ok((Some(excess_blob_gas), Some(parent_beacon_block_root)))
+}
+
+/// Derives the header fields known before execution. The blob gas used is filled in when the
+/// transactions are executed.
+fn derive_header<D>(
+    block_builder: &BlockBuilder<D>,
+    base_fee: u64,
+) -> Result<AlloyConsensusHeader> {
+    let input = &block_builder.input;
+    let number: u64 = input
+        .parent_header
+        .number
+        .checked_add(1)
+        .with_context(|| "Invalid block number: too large")?;
+    let (excess_blob_gas, parent_beacon_block_root) = derive_cancun_fields(block_builder, number)?;
+    Ok(AlloyConsensusHeader {
+        // Initialize fields that we can compute from the parent
+        parent_hash: input.parent_header.hash(),
+        number,
+        base_fee_per_gas: Some(base_fee.into()),
+        excess_blob_gas: excess_blob_gas.map(|b| b.into()),
+        parent_beacon_block_root,
+        // Initialize metadata from input
+        beneficiary: input.beneficiary,
+        gas_limit: input.gas_limit.into(),
+        timestamp: input.timestamp,
+        mix_hash: input.mix_hash,
+        extra_data: input.extra_data.clone(),
+        // do not fill the remaining fields
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consts::{ETH_MAINNET_CHAIN_SPEC, TAIKO_A7_CHAIN_SPEC},
+        input::GuestInput,
+        mem_db::MemDb,
+    };
+
+    const GWEI: u64 = 1_000_000_000;
+
+    fn parent_header(gas_used: u64) -> AlloyConsensusHeader {
+        AlloyConsensusHeader {
+            number: 19_000_000,
+            timestamp: 1_705_000_000,
+            gas_limit: 30_000_000,
+            gas_used: gas_used.into(),
+            base_fee_per_gas: Some(GWEI.into()),
+            ..Default::default()
+        }
+    }
+
+    fn eth_input(parent_header: AlloyConsensusHeader) -> GuestInput {
+        GuestInput {
+            chain_spec: ETH_MAINNET_CHAIN_SPEC.clone(),
+            block_number: parent_header.number + 1,
+            gas_limit: 30_000_000,
+            timestamp: parent_header.timestamp + 12,
+            base_fee_per_gas: derive_base_fee(
+                &parent_header,
+                ETH_MAINNET_CHAIN_SPEC.gas_constants(),
+            )
+            .unwrap(),
+            parent_header,
+            ..Default::default()
+        }
+    }
+
+    fn prepare<T: HeaderPrepStrategy>(input: &GuestInput) -> Result<AlloyConsensusHeader> {
+        BlockBuilder::<MemDb>::new(input)
+            .prepare_header::<T>()
+            .map(|builder| builder.header.unwrap())
+    }
+
+    #[test]
+    fn test_derive_base_fee() {
+        let constants = Eip1559Constants::default();
+        // At the gas target the base fee stays the same
+        assert_eq!(
+            derive_base_fee(&parent_header(15_000_000), &constants).unwrap(),
+            GWEI
+        );
+        // A full block increases it by 1/8, an empty block decreases it by 1/8
+        assert_eq!(
+            derive_base_fee(&parent_header(30_000_000), &constants).unwrap(),
+            1_125_000_000
+        );
+        assert_eq!(
+            derive_base_fee(&parent_header(0), &constants).unwrap(),
+            875_000_000
+        );
+        // Above the target the base fee always increases
+        let parent = AlloyConsensusHeader {
+            base_fee_per_gas: Some(7),
+            ..parent_header(15_000_001)
+        };
+        assert_eq!(derive_base_fee(&parent, &constants).unwrap(), 8);
+    }
+
+    #[test]
+    fn test_eth_header() {
+        let input = eth_input(parent_header(30_000_000));
+        let header = prepare::<EthHeaderPrepStrategy>(&input).unwrap();
+        assert_eq!(header.number, input.block_number);
+        assert_eq!(header.parent_hash, input.parent_header.hash());
+        assert_eq!(header.base_fee_per_gas, Some(1_125_000_000));
+        assert_eq!(header.excess_blob_gas, None);
+
+        // The base fee has to follow from the parent
+        let mut invalid = input.clone();
+        invalid.base_fee_per_gas = GWEI;
+        assert!(prepare::<EthHeaderPrepStrategy>(&invalid).is_err());
+
+        // The gas limit can change by less than 1/1024 of the parent gas limit
+        let mut valid = input.clone();
+        valid.gas_limit = 30_000_000 + 30_000_000 / 1024 - 1;
+        assert!(prepare::<EthHeaderPrepStrategy>(&valid).is_ok());
+        let mut invalid = input.clone();
+        invalid.gas_limit = 30_000_000 + 30_000_000 / 1024;
+        assert!(prepare::<EthHeaderPrepStrategy>(&invalid).is_err());
+
+        // The timestamp has to increase
+        let mut invalid = input.clone();
+        invalid.timestamp = input.parent_header.timestamp;
+        assert!(prepare::<EthHeaderPrepStrategy>(&invalid).is_err());
+
+        // Cancun fields are rejected before Cancun
+        let mut invalid = input;
+        invalid.excess_blob_gas = Some(0);
+        assert!(prepare::<EthHeaderPrepStrategy>(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_eth_header_cancun() {
+        let parent = AlloyConsensusHeader {
+            timestamp: 1_711_000_000,
+            excess_blob_gas: Some(0),
+            blob_gas_used: Some(786_432),
+            ..parent_header(15_000_000)
+        };
+        let mut input = eth_input(parent);
+        input.parent_beacon_block_root = Some(B256::repeat_byte(1));
+
+        // A parent with the maximum blob gas raises the excess by the target
+        input.excess_blob_gas = Some(393_216);
+        let header = prepare::<EthHeaderPrepStrategy>(&input).unwrap();
+        assert_eq!(header.excess_blob_gas, Some(393_216));
+        assert_eq!(header.parent_beacon_block_root, Some(B256::repeat_byte(1)));
+
+        let mut invalid = input.clone();
+        invalid.excess_blob_gas = Some(0);
+        assert!(prepare::<EthHeaderPrepStrategy>(&invalid).is_err());
+
+        let mut invalid = input;
+        invalid.parent_beacon_block_root = None;
+        assert!(prepare::<EthHeaderPrepStrategy>(&invalid).is_err());
+    }
+
+    fn taiko_input() -> GuestInput {
+        let mut input = GuestInput {
+            chain_spec: TAIKO_A7_CHAIN_SPEC.clone(),
+            parent_header: parent_header(0),
+            timestamp: 1_705_000_012,
+            gas_limit: 15_000_000 + ANCHOR_GAS_LIMIT,
+            base_fee_per_gas: 10,
+            ..Default::default()
+        };
+        input.taiko.block_proposed.meta.gasLimit = 15_000_000;
+        input.taiko.anchor_tx = serde_json::to_string(&AlloyTransaction {
+            max_fee_per_gas: Some(10),
+            ..Default::default()
+        })
+        .unwrap();
+        input
+    }
+
+    #[test]
+    fn test_taiko_header() {
+        // The Ethereum gas limit and base fee rules don't apply
+        let input = taiko_input();
+        let header = prepare::<TaikoHeaderPrepStrategy>(&input).unwrap();
+        assert_eq!(header.number, input.parent_header.number + 1);
+        assert_eq!(header.gas_limit, u128::from(15_000_000 + ANCHOR_GAS_LIMIT));
+        assert_eq!(header.base_fee_per_gas, Some(10));
+
+        // The timestamp can stay the same
+        let mut valid = input.clone();
+        valid.timestamp = input.parent_header.timestamp;
+        assert!(prepare::<TaikoHeaderPrepStrategy>(&valid).is_ok());
+
+        // The base fee is the one the anchor tx pays
+        let mut invalid = input.clone();
+        invalid.base_fee_per_gas = 11;
+        let err = prepare::<TaikoHeaderPrepStrategy>(&invalid).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid base fee"));
+
+        let mut invalid = input;
+        invalid.taiko.anchor_tx = String::new();
+        let err = prepare::<TaikoHeaderPrepStrategy>(&invalid).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid anchor tx"));
+    }
+
+    #[test]
+    fn test_taiko_header_gas_limit() {
+        // The anchor gas is added on top of the proposed gas limit
+        let mut input = taiko_input();
+        input.gas_limit = 15_000_000;
+        let err = prepare::<TaikoHeaderPrepStrategy>(&input).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid gas limit"));
+
+        // Without the proposal the gas limit can't be checked, which is only fine for
+        // discovering the state the block touches
+        let mut input = taiko_input();
+        input.taiko.block_proposed = Default::default();
+        assert!(prepare::<TaikoHeaderPrepStrategy>(&input).is_err());
+        let header = prepare::<UncheckedHeaderPrepStrategy>(&input).unwrap();
+        assert_eq!(header.gas_limit, u128::from(input.gas_limit));
+    }
+}
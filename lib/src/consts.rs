@@ -27,6 +27,7 @@ use serde::{Deserialize, Serialize};
 
 #[cfg(not(feature = "std"))]
 use crate::no_std::*;
+use crate::protocol::ProtocolVersion;
 
 /// U256 representation of 0.
 pub const ZERO: U256 = U256::ZERO;
@@ -36,6 +37,12 @@ pub const ONE: U256 = uint!(1_U256);
 /// Maximum size of extra data.
 pub const MAX_EXTRA_DATA_BYTES: usize = 32;
 
+/// Minimum gas limit of a block.
+pub const MIN_GAS_LIMIT: u64 = 5_000;
+
+/// The gas limit of a block can change by less than 1/1024 of the parent gas limit.
+pub const GAS_LIMIT_BOUND_DIVISOR: u64 = 1024;
+
 /// Maximum allowed block number difference for the `block_hash` call.
 pub const MAX_BLOCK_HASH_AGE: u64 = 256;
 
@@ -68,6 +75,7 @@ lazy_static! {
             genesis_time: 0u64,
             seconds_per_slot: 1u64,
             is_taiko: false,
+            protocol_versions: BTreeMap::new(),
         };
 
     /// The Ethereum testnet "holesky" specification.
@@ -94,6 +102,7 @@ lazy_static! {
             genesis_time: 0u64,
             seconds_per_slot: 1u64,
             is_taiko: false,
+            protocol_versions: BTreeMap::new(),
         };
 
     /// The Taiko A6 specification.
@@ -119,6 +128,7 @@ lazy_static! {
         genesis_time: 0u64,
         seconds_per_slot: 1u64,
         is_taiko: true,
+        protocol_versions: BTreeMap::from([(0, ProtocolVersion::A6)]),
     };
 
     /// The Taiko A7 specification.
@@ -144,20 +154,54 @@ lazy_static! {
         genesis_time: 1695902400u64,
         seconds_per_slot: 12u64,
         is_taiko: true,
+        protocol_versions: BTreeMap::from([(0, ProtocolVersion::A7)]),
     };
 }
 
-pub fn get_network_spec(network: Network) -> ChainSpec {
-    match network {
-        Network::Ethereum => ETH_MAINNET_CHAIN_SPEC.clone(),
-        Network::Holesky => ETH_HOLESKY_CHAIN_SPEC.clone(),
-        Network::TaikoA6 => TAIKO_A6_CHAIN_SPEC.clone(),
-        Network::TaikoA7 => TAIKO_A7_CHAIN_SPEC.clone(),
+/// The chain specifications known to the host, keyed by the lowercased network name.
+///
+/// The default registry holds the built-in networks. Specifications loaded from a config
+/// file are merged on top, replacing a built-in with the same name or adding a new network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SupportedChainSpecs(BTreeMap<String, ChainSpec>);
+
+impl Default for SupportedChainSpecs {
+    fn default() -> Self {
+        let mut chain_specs = Self(BTreeMap::new());
+        chain_specs.extend([
+            ETH_MAINNET_CHAIN_SPEC.clone(),
+            ETH_HOLESKY_CHAIN_SPEC.clone(),
+            TAIKO_A6_CHAIN_SPEC.clone(),
+            TAIKO_A7_CHAIN_SPEC.clone(),
+        ]);
+        chain_specs
+    }
+}
+
+impl SupportedChainSpecs {
+    /// Adds the given specifications, replacing any existing one with the same name.
+    pub fn extend(&mut self, chain_specs: impl IntoIterator<Item = ChainSpec>) {
+        for chain_spec in chain_specs {
+            self.0.insert(chain_spec.name.to_lowercase(), chain_spec);
+        }
+    }
+
+    /// Returns the specification of the network with the given name.
+    pub fn get_chain_spec(&self, network: &str) -> Option<ChainSpec> {
+        self.0.get(&network.to_lowercase()).cloned()
+    }
+
+    /// Returns the names of all supported networks.
+    pub fn supported_networks(&self) -> Vec<String> {
+        self.0
+            .values()
+            .map(|chain_spec| chain_spec.name.clone())
+            .collect()
     }
 }
 
 /// The condition at which a fork is activated.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ForkCondition {
     /// The fork is activated with a certain block.
     Block(BlockNumber),
@@ -200,19 +244,27 @@ impl Default for Eip1559Constants {
 }
 
 /// Specification of a specific chain.
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct ChainSpec {
     pub name: String,
     pub chain_id: ChainId,
     pub max_spec_id: SpecId,
     pub hard_forks: BTreeMap<SpecId, ForkCondition>,
+    #[serde(default)]
     pub eip_1559_constants: Eip1559Constants,
+    #[serde(default)]
     pub l1_contract: Option<Address>,
+    #[serde(default)]
     pub l2_contract: Option<Address>,
+    #[serde(default)]
     pub sgx_verifier_address: Option<Address>,
     pub genesis_time: u64,
     pub seconds_per_slot: u64,
+    #[serde(default)]
     pub is_taiko: bool,
+    /// The Taiko protocol revisions, activated at the L2 block they are keyed by.
+    #[serde(default)]
+    pub protocol_versions: BTreeMap<BlockNumber, ProtocolVersion>,
 }
 
 impl ChainSpec {
@@ -236,6 +288,7 @@ impl ChainSpec {
             genesis_time: 0u64,
             seconds_per_slot: 1u64,
             is_taiko,
+            protocol_versions: BTreeMap::new(),
         }
     }
     /// Returns the network chain ID.
@@ -274,12 +327,20 @@ impl ChainSpec {
         self.is_taiko
     }
 
-    pub fn network(&self) -> Option<Network> {
-        Network::from_str(&self.name).ok()
+    /// Returns the Taiko protocol revision active at a given L2 block, or `None` if the chain
+    /// isn't a Taiko chain.
+    pub fn protocol_version(&self, block_no: BlockNumber) -> Option<ProtocolVersion> {
+        self.protocol_versions
+            .range(..=block_no)
+            .next_back()
+            .map(|(_, version)| *version)
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+/// A network the host can generate proofs for. The chain spec of a network is looked up by
+/// its name in [SupportedChainSpecs], so custom networks only need a chain spec file. Any
+/// name parses as a network, whether it has a chain spec is checked when it's used.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub enum Network {
     /// The Ethereum Mainnet
     #[default]
@@ -290,19 +351,24 @@ pub enum Network {
     TaikoA6,
     /// Taiko A7 tesnet
     TaikoA7,
+    /// A network that isn't built in, e.g. a local Taiko devnet, by its lowercased name
+    Custom(String),
 }
 
 impl FromStr for Network {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
+        let name = s.to_lowercase();
+        match name.as_str() {
             "ethereum" => Ok(Network::Ethereum),
             "holesky" => Ok(Network::Holesky),
-            "taiko_a6" => Ok(Network::TaikoA6),
-            "taiko_a7" => Ok(Network::TaikoA7),
-            #[allow(clippy::needless_return)]
-            _ => bail!("Unknown network"),
+            // The variant names were used when serializing the network before custom networks
+            // were supported.
+            "taiko_a6" | "taikoa6" => Ok(Network::TaikoA6),
+            "taiko_a7" | "taikoa7" => Ok(Network::TaikoA7),
+            "" => bail!("Unknown network"),
+            _ => Ok(Network::Custom(name)),
         }
     }
 }
@@ -314,18 +380,21 @@ impl Display for Network {
             Network::Holesky => "holesky",
             Network::TaikoA6 => "taiko_a6",
             Network::TaikoA7 => "taiko_a7",
+            Network::Custom(name) => name,
         })
     }
 }
 
-impl Network {
-    pub fn is_taiko(&self) -> bool {
-        match self {
-            Network::Ethereum => false,
-            Network::Holesky => false,
-            Network::TaikoA6 => true,
-            Network::TaikoA7 => true,
-        }
+impl Serialize for Network {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Network::from_str(&name).map_err(serde::de::Error::custom)
     }
 }
 
@@ -349,4 +418,44 @@ mod tests {
             Some(SpecId::SHANGHAI)
         );
     }
+
+    #[test]
+    fn supported_chain_specs() {
+        let mut chain_specs = SupportedChainSpecs::default();
+        assert_eq!(
+            chain_specs.get_chain_spec("Taiko_A7"),
+            Some(TAIKO_A7_CHAIN_SPEC.clone())
+        );
+        assert_eq!(chain_specs.get_chain_spec("taiko_devnet"), None);
+
+        // A loaded specification replaces the built-in with the same name and adds new ones.
+        let mut taiko_a7 = TAIKO_A7_CHAIN_SPEC.clone();
+        taiko_a7.genesis_time = 0;
+        let devnet = ChainSpec {
+            name: "taiko_devnet".to_string(),
+            ..TAIKO_A7_CHAIN_SPEC.clone()
+        };
+        chain_specs.extend([taiko_a7.clone(), devnet.clone()]);
+        assert_eq!(chain_specs.get_chain_spec("taiko_a7"), Some(taiko_a7));
+        assert_eq!(chain_specs.get_chain_spec("taiko_devnet"), Some(devnet));
+        assert_eq!(chain_specs.supported_networks().len(), 5);
+    }
+
+    #[test]
+    fn custom_network() {
+        for network in [
+            Network::TaikoA7,
+            Network::Custom("taiko_devnet".to_string()),
+        ] {
+            assert_eq!(Network::from_str(&network.to_string()).unwrap(), network);
+            let encoded = bincode::serialize(&network).unwrap();
+            assert_eq!(bincode::deserialize::<Network>(&encoded).unwrap(), network);
+        }
+        assert_eq!(Network::from_str("TaikoA7").unwrap(), Network::TaikoA7);
+        assert_eq!(
+            Network::from_str("Taiko_Devnet").unwrap(),
+            Network::Custom("taiko_devnet".to_string())
+        );
+        assert!(Network::from_str("").is_err());
+    }
 }
@@ -89,14 +89,31 @@ pub struct TaikoGuestInput {
     /// header
     #[serde_as(as = "RlpBytes")]
     pub l1_header: AlloyConsensusHeader,
+    /// The tx list from calldata, or the blob holding the tx list
     pub tx_data: Vec<u8>,
     pub anchor_tx: String,
     pub block_proposed: BlockProposed,
     pub prover_data: TaikoProverData,
     pub tx_blob_hash: Option<B256>,
+    /// KZG commitment of the blob holding the tx list, as published in the beacon sidecar
+    pub blob_commitment: Option<FixedBytes<48>>,
+    /// KZG proof that the blob matches `blob_commitment`
+    pub blob_proof: Option<FixedBytes<48>>,
+    /// Where the tx list is in the data decoded from the blob
+    pub blob_tx_list: BlobTxListLocation,
     pub skip_verify_blob: bool,
 }
 
+/// Where the tx list of a block proposed with a blob is stored in the blob. Defaults to a tx
+/// list filling the blob.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobTxListLocation {
+    /// Offset of the tx list in the data decoded from the blob
+    pub byte_offset: usize,
+    /// Size of the tx list, or `None` if it takes up the rest of the data
+    pub byte_size: Option<usize>,
+}
+
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct TaikoProverData {
     pub prover: Address,
@@ -321,6 +338,8 @@ impl From<taiko_a6::BlockProposed> for BlockProposed {
                 minTier: item.meta.minTier,
                 blobUsed: item.meta.blobUsed,
                 parentMetaHash: item.meta.parentMetaHash,
+                // The tx list location is returned next to the event by
+                // `ProtocolVersion::decode_block_proposed` and hashed with the metadata
                 ..Default::default()
             },
             depositsProcessed: item
@@ -32,6 +32,7 @@ pub mod builder;
 pub mod consts;
 pub mod input;
 pub mod mem_db;
+pub mod protocol;
 pub mod protocol_instance;
 pub mod prover;
 pub mod utils;
@@ -115,6 +116,14 @@ impl Measurement {
 
     pub fn stop_with(&self, title: &str) -> time::Duration {
         let time_elapsed = self.start.elapsed();
+        // Record the timing on the current span so it shows up in exported traces.
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            measurement = %self.title.trim_end_matches("..."),
+            elapsed_ms = time_elapsed.as_millis() as u64,
+            "{}",
+            title.trim_start_matches("=> ")
+        );
         print_duration(
             &format!("{}{title} in ", if self.inplace { "\r" } else { "" }),
             time_elapsed,
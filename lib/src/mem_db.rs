@@ -283,8 +283,8 @@ impl DatabaseCommit for MemDb {
 }
 
 impl OptimisticDatabase for MemDb {
-    async fn fetch_data(&mut self) -> bool {
-        true
+    async fn fetch_data(&mut self) -> anyhow::Result<bool> {
+        Ok(true)
     }
 
     fn is_optimistic(&self) -> bool {
@@ -0,0 +1,278 @@
+//! The revisions of the Taiko protocol contracts.
+use alloy_consensus::Header as AlloyConsensusHeader;
+use alloy_primitives::{Log, B256};
+use alloy_sol_types::{SolCall, SolEvent, SolValue};
+use anyhow::{anyhow, ensure, Result};
+use raiko_primitives::keccak::keccak;
+use serde::{Deserialize, Serialize};
+
+#[cfg(not(feature = "std"))]
+use crate::no_std::*;
+use crate::{
+    input::{
+        anchorCall, decode_anchor, proposeBlockCall, taiko_a6, BlobTxListLocation, BlockMetadata,
+        BlockProposed,
+    },
+    utils::HeaderHasher,
+};
+
+/// The largest tx list offset and size the A6 metadata can hold, both are `uint24`.
+const MAX_A6_TX_LIST_BYTES: usize = (1 << 24) - 1;
+
+/// A revision of the Taiko protocol contracts.
+///
+/// Everything that depends on the contract ABIs or on the protocol rules is decided here, so
+/// supporting a new revision means adding a variant and activating it in the chain specs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtocolVersion {
+    /// The protocol of the A6 testnet.
+    A6,
+    /// The protocol of the A7 testnet, which also anchors the L1 state root.
+    A7,
+}
+
+impl ProtocolVersion {
+    /// The signature of the `BlockProposed` event.
+    pub fn block_proposed_signature(&self) -> B256 {
+        match self {
+            ProtocolVersion::A6 => taiko_a6::BlockProposed::SIGNATURE_HASH,
+            ProtocolVersion::A7 => BlockProposed::SIGNATURE_HASH,
+        }
+    }
+
+    /// Decodes a `BlockProposed` event into the current metadata layout, together with where
+    /// the tx list is if the block was proposed with blobs.
+    pub fn decode_block_proposed(&self, log: &Log) -> Result<(BlockProposed, BlobTxListLocation)> {
+        match self {
+            ProtocolVersion::A6 => {
+                let event = taiko_a6::BlockProposed::decode_log(log, false)
+                    .map_err(|e| anyhow!(e))?
+                    .data;
+                // Blobs can be shared by several blocks, each using a part of it
+                let location = BlobTxListLocation {
+                    byte_offset: event.meta.txListByteOffset as usize,
+                    byte_size: Some(event.meta.txListByteSize as usize),
+                };
+                Ok((event.into(), location))
+            }
+            ProtocolVersion::A7 => {
+                let event = BlockProposed::decode_log(log, false)
+                    .map_err(|e| anyhow!(e))?
+                    .data;
+                // The metadata has no tx list location, the tx list fills the blob
+                Ok((event, BlobTxListLocation::default()))
+            }
+        }
+    }
+
+    /// Checks that the metadata of the block commits to where the tx list is in the blob.
+    /// Only the A6 metadata holds the part of the blob with the tx list.
+    pub fn check_blob_tx_list(&self, location: &BlobTxListLocation) -> Result<()> {
+        match self {
+            ProtocolVersion::A6 => {
+                let byte_size = location
+                    .byte_size
+                    .ok_or_else(|| anyhow!("the A6 metadata needs the size of the tx list"))?;
+                ensure!(
+                    location.byte_offset <= MAX_A6_TX_LIST_BYTES
+                        && byte_size <= MAX_A6_TX_LIST_BYTES,
+                    "the tx list location doesn't fit in the A6 metadata"
+                );
+            }
+            ProtocolVersion::A7 => ensure!(
+                *location == BlobTxListLocation::default(),
+                "the A7 metadata has no tx list location"
+            ),
+        }
+        Ok(())
+    }
+
+    /// Hashes the metadata of a block like the protocol contracts do, as
+    /// `keccak256(abi.encode(meta))`. The A6 `TaikoL1` hashes its own `BlockMetadata`, which
+    /// has the `uint24` tx list offset and size after `l1Height` and no `sender`, so the A6
+    /// hash has to be taken over that layout to match the meta hash stored on chain.
+    pub fn meta_hash(&self, meta: &BlockMetadata, blob_tx_list: &BlobTxListLocation) -> B256 {
+        match self {
+            ProtocolVersion::A6 => keccak(
+                taiko_a6::BlockMetadata {
+                    l1Hash: meta.l1Hash,
+                    difficulty: meta.difficulty,
+                    blobHash: meta.blobHash,
+                    extraData: meta.extraData,
+                    depositsHash: meta.depositsHash,
+                    coinbase: meta.coinbase,
+                    id: meta.id,
+                    gasLimit: meta.gasLimit,
+                    timestamp: meta.timestamp,
+                    l1Height: meta.l1Height,
+                    txListByteOffset: blob_tx_list.byte_offset as u32,
+                    txListByteSize: blob_tx_list.byte_size.unwrap_or_default() as u32,
+                    minTier: meta.minTier,
+                    blobUsed: meta.blobUsed,
+                    parentMetaHash: meta.parentMetaHash,
+                }
+                .abi_encode(),
+            )
+            .into(),
+            ProtocolVersion::A7 => keccak(meta.abi_encode()).into(),
+        }
+    }
+
+    /// Returns the tx list of a block that was proposed with calldata.
+    pub fn decode_calldata_tx_list(&self, calldata: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            ProtocolVersion::A6 => taiko_a6::proposeBlockCall::abi_decode(calldata, false)
+                .map(|call| call.txList.to_vec())
+                .map_err(|e| anyhow!(e)),
+            ProtocolVersion::A7 => proposeBlockCall::abi_decode(calldata, false)
+                .map(|call| call.txList.to_vec())
+                .map_err(|e| anyhow!(e)),
+        }
+    }
+
+    /// Decodes the input of the anchor transaction.
+    pub fn decode_anchor(&self, input: &[u8]) -> Result<anchorCall> {
+        // The anchor ABI is the same in all revisions so far
+        match self {
+            ProtocolVersion::A6 | ProtocolVersion::A7 => decode_anchor(input),
+        }
+    }
+
+    /// Checks the anchor call against the L1 block the L2 block is anchored to and the
+    /// parent of the L2 block.
+    pub fn check_anchor(
+        &self,
+        anchor_call: &anchorCall,
+        l1_header: &AlloyConsensusHeader,
+        parent_header: &AlloyConsensusHeader,
+    ) -> Result<()> {
+        // The L1 blockhash needs to match the expected value
+        ensure!(anchor_call.l1Hash == l1_header.hash(), "L1 hash mismatch");
+        match self {
+            ProtocolVersion::A6 => {}
+            ProtocolVersion::A7 => ensure!(
+                anchor_call.l1StateRoot == l1_header.state_root,
+                "L1 state root mismatch"
+            ),
+        }
+        ensure!(
+            anchor_call.l1BlockId == l1_header.number,
+            "L1 block number mismatch"
+        );
+        // The parent gas used input needs to match the gas used value of the parent block
+        ensure!(
+            anchor_call.parentGasUsed == parent_header.gas_used as u32,
+            "parentGasUsed mismatch"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::{b256, Address};
+
+    use super::*;
+    use crate::consts::{ETH_MAINNET_CHAIN_SPEC, TAIKO_A6_CHAIN_SPEC, TAIKO_A7_CHAIN_SPEC};
+
+    #[test]
+    fn protocol_version_by_block() {
+        let chain_spec = TAIKO_A7_CHAIN_SPEC.clone();
+        assert_eq!(chain_spec.protocol_version(0), Some(ProtocolVersion::A7));
+        assert_eq!(
+            TAIKO_A6_CHAIN_SPEC.protocol_version(1000),
+            Some(ProtocolVersion::A6)
+        );
+        assert_eq!(ETH_MAINNET_CHAIN_SPEC.protocol_version(1000), None);
+
+        let mut chain_spec = chain_spec;
+        chain_spec
+            .protocol_versions
+            .insert(100, ProtocolVersion::A6);
+        assert_eq!(chain_spec.protocol_version(99), Some(ProtocolVersion::A7));
+        assert_eq!(chain_spec.protocol_version(100), Some(ProtocolVersion::A6));
+    }
+
+    #[test]
+    fn check_anchor_state_root() {
+        let l1_header = AlloyConsensusHeader {
+            number: 10,
+            state_root: B256::with_last_byte(1),
+            ..Default::default()
+        };
+        let parent_header = AlloyConsensusHeader::default();
+        let anchor_call = anchorCall {
+            l1Hash: l1_header.hash(),
+            l1StateRoot: B256::ZERO,
+            l1BlockId: 10,
+            parentGasUsed: 0,
+        };
+        // Only A7 commits to the L1 state root
+        assert!(ProtocolVersion::A6
+            .check_anchor(&anchor_call, &l1_header, &parent_header)
+            .is_ok());
+        assert!(ProtocolVersion::A7
+            .check_anchor(&anchor_call, &l1_header, &parent_header)
+            .is_err());
+    }
+
+    #[test]
+    fn a6_meta_hash() {
+        let meta = BlockMetadata {
+            l1Hash: B256::repeat_byte(0x11),
+            difficulty: B256::repeat_byte(0x22),
+            blobHash: B256::repeat_byte(0x33),
+            extraData: B256::repeat_byte(0x44),
+            depositsHash: B256::repeat_byte(0x55),
+            coinbase: Address::repeat_byte(0x66),
+            id: 7,
+            gasLimit: 15_000_000,
+            timestamp: 1_700_000_000,
+            l1Height: 1_234_567,
+            minTier: 200,
+            blobUsed: true,
+            parentMetaHash: B256::repeat_byte(0x77),
+            sender: Address::repeat_byte(0x88),
+        };
+        let location = BlobTxListLocation {
+            byte_offset: 100,
+            byte_size: Some(200),
+        };
+        // keccak256(abi.encode(meta)) of the A6 `TaikoData.BlockMetadata` with the same fields,
+        // the tx list at offset 100 with size 200 and without the sender
+        assert_eq!(
+            ProtocolVersion::A6.meta_hash(&meta, &location),
+            b256!("b5de4ab5379ed5c04283210dc3dba8e6958ca71c6b4a3751593ced39ae77c9a4")
+        );
+        // The A7 metadata is hashed as is
+        assert_eq!(
+            ProtocolVersion::A7.meta_hash(&meta, &BlobTxListLocation::default()),
+            B256::from(keccak(meta.abi_encode()))
+        );
+    }
+
+    #[test]
+    fn blob_tx_list_committed_by_metadata() {
+        let meta = BlockMetadata::default();
+        let location = BlobTxListLocation {
+            byte_offset: 100,
+            byte_size: Some(200),
+        };
+        let moved = BlobTxListLocation {
+            byte_offset: 0,
+            ..location.clone()
+        };
+
+        // Only the A6 metadata holds the location, so it changes the A6 meta hash
+        assert!(ProtocolVersion::A6.check_blob_tx_list(&location).is_ok());
+        assert_ne!(
+            ProtocolVersion::A6.meta_hash(&meta, &location),
+            ProtocolVersion::A6.meta_hash(&meta, &moved)
+        );
+        assert!(ProtocolVersion::A7.check_blob_tx_list(&location).is_err());
+        assert!(ProtocolVersion::A7
+            .check_blob_tx_list(&BlobTxListLocation::default())
+            .is_ok());
+    }
+}
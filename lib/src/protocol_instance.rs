@@ -1,8 +1,8 @@
 use alloy_consensus::Header as AlloyConsensusHeader;
 use alloy_primitives::{Address, TxHash, B256};
 use alloy_sol_types::SolValue;
-use anyhow::{ensure, Result};
-use c_kzg_taiko::{Blob, KzgCommitment, KzgSettings};
+use anyhow::{anyhow, ensure, Result};
+use c_kzg_taiko::{Blob, Bytes48, KzgCommitment, KzgProof, KzgSettings};
 use raiko_primitives::keccak::keccak;
 use sha2::{Digest as _, Sha256};
 
@@ -10,7 +10,8 @@ use super::utils::ANCHOR_GAS_LIMIT;
 #[cfg(not(feature = "std"))]
 use crate::no_std::*;
 use crate::{
-    input::{BlockMetadata, EthDeposit, GuestInput, Transition},
+    input::{BlobTxListLocation, BlockMetadata, EthDeposit, GuestInput, Transition},
+    protocol::ProtocolVersion,
     utils::HeaderHasher,
 };
 
@@ -20,6 +21,10 @@ const KZG_TRUST_SETUP_DATA: &[u8] = include_bytes!("../../kzg_settings_raw.bin")
 pub struct ProtocolInstance {
     pub transition: Transition,
     pub block_metadata: BlockMetadata,
+    /// Where the tx list is in the blob, part of the metadata in some protocol versions
+    pub blob_tx_list: BlobTxListLocation,
+    /// The protocol the metadata is hashed for, `None` for chains other than Taiko
+    pub protocol: Option<ProtocolVersion>,
     pub prover: Address,
     pub chain_id: u64,
     pub sgx_verifier_address: Address,
@@ -27,7 +32,10 @@ pub struct ProtocolInstance {
 
 impl ProtocolInstance {
     pub fn meta_hash(&self) -> B256 {
-        keccak(self.block_metadata.abi_encode()).into()
+        match self.protocol {
+            Some(protocol) => protocol.meta_hash(&self.block_metadata, &self.blob_tx_list),
+            None => keccak(self.block_metadata.abi_encode()).into(),
+        }
     }
 
     // keccak256(abi.encode(tran, newInstance, prover, metaHash))
@@ -92,24 +100,62 @@ pub fn assemble_protocol_instance(
     input: &GuestInput,
     header: &AlloyConsensusHeader,
 ) -> Result<ProtocolInstance> {
+    let protocol = if input.chain_spec.is_taiko() {
+        Some(
+            input
+                .chain_spec
+                .protocol_version(input.block_number)
+                .ok_or_else(|| anyhow!("no protocol version for block {}", input.block_number))?,
+        )
+    } else {
+        None
+    };
     let blob_used = input.taiko.block_proposed.meta.blobUsed;
     let tx_list_hash = if blob_used {
+        // The tx list location is only used when the metadata commits to it
+        if let Some(protocol) = protocol {
+            protocol.check_blob_tx_list(&input.taiko.blob_tx_list)?;
+        }
+        let blob_hash = input
+            .taiko
+            .tx_blob_hash
+            .ok_or_else(|| anyhow!("missing the hash of the blob holding the tx list"))?;
         if !input.taiko.skip_verify_blob {
             println!("kzg check enabled!");
             let mut data = Vec::from(KZG_TRUST_SETUP_DATA);
             let kzg_settings = KzgSettings::from_u8_slice(&mut data);
-            let kzg_commit = KzgCommitment::blob_to_kzg_commitment(
-                &Blob::from_bytes(&input.taiko.tx_data.as_slice()).unwrap(),
-                &kzg_settings,
-            )
-            .unwrap();
-            let versioned_hash = kzg_to_versioned_hash(kzg_commit);
-            assert_eq!(versioned_hash, input.taiko.tx_blob_hash.unwrap());
-            versioned_hash
+            let blob = Blob::from_bytes(input.taiko.tx_data.as_slice())
+                .map_err(|e| anyhow!("invalid blob: {e:?}"))?;
+            let kzg_commit = match (&input.taiko.blob_commitment, &input.taiko.blob_proof) {
+                // Checking the proof from the sidecar is cheaper than recomputing the
+                // commitment from the blob
+                (Some(commitment), Some(proof)) => {
+                    let commitment_bytes = Bytes48::from_bytes(commitment.as_slice())
+                        .map_err(|e| anyhow!("invalid kzg commitment: {e:?}"))?;
+                    let proof_bytes = Bytes48::from_bytes(proof.as_slice())
+                        .map_err(|e| anyhow!("invalid kzg proof: {e:?}"))?;
+                    let valid = KzgProof::verify_blob_kzg_proof(
+                        &blob,
+                        &commitment_bytes,
+                        &proof_bytes,
+                        &kzg_settings,
+                    )
+                    .map_err(|e| anyhow!("could not verify the kzg proof: {e:?}"))?;
+                    ensure!(valid, "blob does not match the kzg commitment");
+                    KzgCommitment::from_bytes(commitment.as_slice())
+                        .map_err(|e| anyhow!("invalid kzg commitment: {e:?}"))?
+                }
+                _ => KzgCommitment::blob_to_kzg_commitment(&blob, &kzg_settings)
+                    .map_err(|e| anyhow!("could not compute the kzg commitment: {e:?}"))?,
+            };
+            ensure!(
+                kzg_to_versioned_hash(kzg_commit) == blob_hash,
+                "blob does not match the blob hash {blob_hash}"
+            );
         } else {
             println!("kzg check disabled!");
-            input.taiko.tx_blob_hash.unwrap()
         }
+        blob_hash
     } else {
         TxHash::from(keccak(input.taiko.tx_data.as_slice()))
     };
@@ -153,6 +199,8 @@ pub fn assemble_protocol_instance(
             parentMetaHash: input.taiko.block_proposed.meta.parentMetaHash,
             sender: input.taiko.block_proposed.meta.sender,
         },
+        blob_tx_list: input.taiko.blob_tx_list.clone(),
+        protocol,
         prover: input.taiko.prover_data.prover,
         chain_id: input.chain_spec.chain_id,
         sgx_verifier_address: input.chain_spec.sgx_verifier_address.unwrap_or_default(),
@@ -181,12 +229,16 @@ fn bytes_to_bytes32(input: &[u8]) -> [u8; 32] {
 
 #[cfg(test)]
 mod tests {
-    use alloy_primitives::{address, b256};
+    use alloy_primitives::{address, b256, FixedBytes};
     use alloy_sol_types::SolCall;
+    use c_kzg_taiko::BYTES_PER_BLOB;
     use raiko_primitives::keccak;
 
     use super::*;
-    use crate::input::{proveBlockCall, TierProof};
+    use crate::{
+        consts::TAIKO_A7_CHAIN_SPEC,
+        input::{proveBlockCall, TierProof},
+    };
 
     #[test]
     fn bytes_to_bytes32_test() {
@@ -201,6 +253,63 @@ mod tests {
         );
     }
 
+    /// Returns the commitment and proof of a blob, computed with the guest's trusted setup.
+    fn blob_commitment_and_proof(blob: &[u8]) -> (FixedBytes<48>, FixedBytes<48>) {
+        let mut data = Vec::from(KZG_TRUST_SETUP_DATA);
+        let kzg_settings = KzgSettings::from_u8_slice(&mut data);
+        let blob = Blob::from_bytes(blob).unwrap();
+        let commitment = KzgCommitment::blob_to_kzg_commitment(&blob, &kzg_settings).unwrap();
+        let commitment_bytes = Bytes48::from_bytes(commitment.as_slice()).unwrap();
+        let proof =
+            KzgProof::compute_blob_kzg_proof(&blob, &commitment_bytes, &kzg_settings).unwrap();
+        (
+            FixedBytes::from_slice(commitment.as_slice()),
+            FixedBytes::from_slice(proof.as_slice()),
+        )
+    }
+
+    #[test]
+    fn test_blob_kzg_proof_checked() {
+        let blob = vec![0u8; BYTES_PER_BLOB];
+        let (commitment, proof) = blob_commitment_and_proof(&blob);
+        let blob_hash = kzg_to_versioned_hash(KzgCommitment::from_bytes(&commitment.0).unwrap());
+
+        let mut input = GuestInput {
+            chain_spec: TAIKO_A7_CHAIN_SPEC.clone(),
+            block_number: 1,
+            ..Default::default()
+        };
+        input.taiko.tx_data = blob.clone();
+        input.taiko.tx_blob_hash = Some(blob_hash);
+        input.taiko.blob_commitment = Some(commitment);
+        input.taiko.blob_proof = Some(proof);
+        input.taiko.block_proposed.meta = BlockMetadata {
+            l1Hash: input.taiko.l1_header.hash(),
+            blobHash: blob_hash,
+            depositsHash: keccak::keccak(Vec::<EthDeposit>::new().abi_encode()).into(),
+            id: 1,
+            gasLimit: 15_000_000,
+            blobUsed: true,
+            ..Default::default()
+        };
+        let header = AlloyConsensusHeader {
+            number: 1,
+            gas_limit: (15_000_000 + ANCHOR_GAS_LIMIT).into(),
+            ..Default::default()
+        };
+        let pi = assemble_protocol_instance(&input, &header).unwrap();
+        assert_eq!(pi.block_metadata.blobHash, blob_hash);
+
+        // A proof for another blob doesn't verify against the commitment of this one
+        let mut other_blob = blob;
+        other_blob[1] = 1;
+        input.taiko.blob_proof = Some(blob_commitment_and_proof(&other_blob).1);
+        let err = assemble_protocol_instance(&input, &header).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("does not match the kzg commitment"));
+    }
+
     #[test]
     fn test_calc_eip712_pi_hash() {
         let trans = Transition {
@@ -13,12 +13,9 @@ use libflate::zlib::Decoder as zlibDecoder;
 use libflate::zlib::Encoder as zlibEncoder;
 use raiko_primitives::{keccak256, B256};
 
+use crate::input::{BlobTxListLocation, GuestInput};
 #[cfg(not(feature = "std"))]
 use crate::no_std::*;
-use crate::{
-    consts::Network,
-    input::{decode_anchor, GuestInput},
-};
 
 pub const ANCHOR_GAS_LIMIT: u64 = 250_000;
 
@@ -56,11 +53,12 @@ fn validate_calldata_tx_list(tx_list: &[u8]) -> bool {
 pub fn generate_transactions(
     is_blob_data: bool,
     tx_list: &[u8],
+    blob_tx_list: &BlobTxListLocation,
     anchor_tx: Option<AlloyTransaction>,
 ) -> Vec<TxEnvelope> {
     // Decode the tx list from the raw data posted onchain
     let tx_list = &if is_blob_data {
-        let compressed_tx_list = decode_blob_data(tx_list);
+        let compressed_tx_list = decode_blob_tx_list(tx_list, blob_tx_list);
         zlib_decompress_data(&compressed_tx_list).unwrap_or_default()
     } else if validate_calldata_tx_list(tx_list) {
         zlib_decompress_data(tx_list).unwrap_or_default()
@@ -112,6 +110,17 @@ const BLOB_VERSION_OFFSET: usize = 1;
 const BLOB_ENCODING_VERSION: u8 = 0;
 const MAX_BLOB_DATA_SIZE: usize = (4 * 31 + 3) * 1024 - 4;
 
+/// Decodes the blob and returns the part of the data holding the tx list.
+fn decode_blob_tx_list(blob: &[u8], location: &BlobTxListLocation) -> Vec<u8> {
+    let data = decode_blob_data(blob);
+    let end = location
+        .byte_size
+        .map_or(data.len(), |size| location.byte_offset.saturating_add(size));
+    data.get(location.byte_offset..end)
+        .map(|tx_list| tx_list.to_vec())
+        .unwrap_or_default()
+}
+
 // decoding https://github.com/ethereum-optimism/optimism/blob/develop/op-service/eth/blob.go
 fn decode_blob_data(blob_buf: &[u8]) -> Vec<u8> {
     // check the version
@@ -289,27 +298,12 @@ pub fn check_anchor_tx(input: &GuestInput, anchor: &TxEnvelope, from: &Address)
             );
 
             // Okay now let's decode the anchor tx to verify the inputs
-            let anchor_call = decode_anchor(&tx.input)?;
-            // The L1 blockhash needs to match the expected value
-            ensure!(
-                anchor_call.l1Hash == input.taiko.l1_header.hash(),
-                "L1 hash mismatch"
-            );
-            if input.chain_spec.network().unwrap() == Network::TaikoA7 {
-                ensure!(
-                    anchor_call.l1StateRoot == input.taiko.l1_header.state_root,
-                    "L1 state root mismatch"
-                );
-            }
-            ensure!(
-                anchor_call.l1BlockId == input.taiko.l1_header.number,
-                "L1 block number mismatch"
-            );
-            // The parent gas used input needs to match the gas used value of the parent block
-            ensure!(
-                anchor_call.parentGasUsed == input.parent_header.gas_used as u32,
-                "parentGasUsed mismatch"
-            );
+            let protocol = input
+                .chain_spec
+                .protocol_version(input.block_number)
+                .with_context(|| format!("no protocol version for block {}", input.block_number))?;
+            let anchor_call = protocol.decode_anchor(&tx.input)?;
+            protocol.check_anchor(&anchor_call, &input.taiko.l1_header, &input.parent_header)?;
         }
         _ => {
             panic!("invalid anchor tx type");
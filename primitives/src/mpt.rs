@@ -509,6 +509,51 @@ impl MptNode {
         }
     }
 
+    /// Returns the EIP-1186 proof for a given key.
+    ///
+    /// The proof contains the RLP encodings of the root and of all the nodes on the path to
+    /// the key that are referenced by their hash, in that order. Nodes with a shorter
+    /// encoding are embedded in their parent and not part of the proof. The proof shows
+    /// that the key is not in the trie if it doesn't end with a leaf for the key.
+    pub fn proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let mut proof = Vec::new();
+        self.proof_internal(&to_nibs(key), true, &mut proof)?;
+        Ok(proof)
+    }
+
+    fn proof_internal(
+        &self,
+        key_nibs: &[u8],
+        is_root: bool,
+        proof: &mut Vec<Vec<u8>>,
+    ) -> Result<(), Error> {
+        match &self.data {
+            MptNodeData::Null => return Ok(()),
+            MptNodeData::Digest(digest) => return Err(Error::NodeNotResolved(*digest)),
+            _ => {}
+        }
+        if is_root || matches!(self.reference(), MptNodeReference::Digest(_)) {
+            proof.push(alloy_rlp::encode(self));
+        }
+
+        match &self.data {
+            MptNodeData::Branch(nodes) => match key_nibs.split_first() {
+                Some((i, tail)) => match nodes[*i as usize] {
+                    Some(ref node) => node.proof_internal(tail, false, proof),
+                    None => Ok(()),
+                },
+                None => Ok(()),
+            },
+            MptNodeData::Extension(prefix, node) => {
+                match key_nibs.strip_prefix(prefix_nibs(prefix).as_slice()) {
+                    Some(tail) => node.proof_internal(tail, false, proof),
+                    None => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// Removes a key from the trie.
     ///
     /// This method attempts to remove a key-value pair from the trie. If the key is
@@ -1363,6 +1408,35 @@ mod tests {
         assert!(trie.is_empty());
     }
 
+    #[test]
+    pub fn test_proof() {
+        const N: usize = 512;
+
+        let mut trie = MptNode::default();
+        assert!(trie
+            .proof(&keccak(0usize.to_be_bytes()))
+            .unwrap()
+            .is_empty());
+        for i in 0..N {
+            trie.insert_rlp(&keccak(i.to_be_bytes()), i).unwrap();
+        }
+
+        for i in 0..N {
+            // inclusion proofs resolve the value against the root
+            let key = keccak(i.to_be_bytes());
+            let proof_nodes = parse_proof(&trie.proof(&key).unwrap()).unwrap();
+            let proof_trie = mpt_from_proof(&proof_nodes).unwrap();
+            assert_eq!(proof_trie.hash(), trie.hash());
+            assert_eq!(proof_trie.get_rlp(&key).unwrap(), Some(i));
+
+            // exclusion proofs
+            let key = keccak((i + N).to_be_bytes());
+            let proof_nodes = parse_proof(&trie.proof(&key).unwrap()).unwrap();
+            assert_eq!(proof_nodes[0].hash(), trie.hash());
+            assert!(is_not_included(&key, &proof_nodes).unwrap());
+        }
+    }
+
     #[test]
     pub fn test_index_trie() {
         const N: usize = 512;